@@ -1,4 +1,5 @@
 use crate::config::Config;
+use crate::events::{read_recording, EventRecorder};
 use crate::log::ScopedLogger;
 use crate::memory::MemReader;
 use crate::memory::MemoryReadErrorType;
@@ -7,14 +8,21 @@ use crate::outputmodules::ModuleDefinition;
 use crate::outputmodules::OutputModule;
 use crate::utils::PhraseParser;
 use crate::RekordboxOffsets;
-use crate::memory::PointerChainValue;
+use crate::udpsource::UdpSource;
+use crate::memory::PointerChainString;
 use binrw::BinRead;
+use serde::{Deserialize, Serialize};
 use notify::Watcher;
 use rekordcrate::anlz::{self, BeatGrid};
+use std::collections::HashMap;
 use std::io::Cursor;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 
 use crate::memory::Value;
 
@@ -25,49 +33,297 @@ use crate::memory::Value;
 
 
 
+/// Runtime controls set by modules that accept input, eg. OSC
+pub struct KeeperControl {
+    forced_master: AtomicUsize, // usize::MAX when not forced
+    stop: AtomicBool,
+    muted: AtomicBool,
+    beat_phase_offset: AtomicU32, // f32 bits
+}
+
+pub static KEEPER_CONTROL: KeeperControl = KeeperControl::new();
+
+impl KeeperControl {
+    const fn new() -> Self {
+        Self {
+            forced_master: AtomicUsize::new(usize::MAX),
+            stop: AtomicBool::new(false),
+            muted: AtomicBool::new(false),
+            beat_phase_offset: AtomicU32::new(0), // 0.0
+        }
+    }
+
+    /// Shift all reported beats by `beats`, wrapping within the bar
+    pub fn set_beat_phase_offset(&self, beats: f32) {
+        self.beat_phase_offset.store(beats.to_bits(), Ordering::Relaxed);
+    }
+
+    fn beat_phase_offset(&self) -> f32 {
+        f32::from_bits(self.beat_phase_offset.load(Ordering::Relaxed))
+    }
+
+    /// Make `BeatKeeper::start` return after the current update, eg. on Ctrl+C
+    pub fn request_stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    /// Keep tracking Rekordbox, but stop sending anything to the modules until unmuted
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    fn muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    /// Treat `deck` as the master deck regardless of Rekordbox, or follow Rekordbox again with None
+    pub fn force_master(&self, deck: Option<usize>) {
+        self.forced_master.store(deck.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    fn forced_master(&self) -> Option<usize> {
+        let deck = self.forced_master.load(Ordering::Relaxed);
+        if deck == usize::MAX { None } else { Some(deck) }
+    }
+}
+
+/// Errors while reading Rekordbox, as reported to a host through `KeeperEvent`s
+#[derive(Debug, PartialEq, Clone)]
+pub enum KeeperError {
+    ProcessNotFound,
+    SnapshotFailed { detail: Option<String> },
+    ReadMemoryFailed { detail: Option<String>, label: Option<String>, address: usize },
+    WriteMemoryFailed { detail: Option<String> },
+    ModuleNotFound { detail: Option<String> },
+    WatcherFailed { detail: String },
+}
+
+impl From<&MemoryReadError> for KeeperError {
+    fn from(e: &MemoryReadError) -> Self {
+        let detail = e.detail.clone();
+        match e.error_type {
+            MemoryReadErrorType::ProcessNotFound => KeeperError::ProcessNotFound,
+            MemoryReadErrorType::SnapshotFailed => KeeperError::SnapshotFailed { detail },
+            MemoryReadErrorType::ReadMemoryFailed => KeeperError::ReadMemoryFailed { detail, label: e.label.clone(), address: e.address },
+            MemoryReadErrorType::WriteMemoryFailed => KeeperError::WriteMemoryFailed { detail },
+            MemoryReadErrorType::ModuleNotFound => KeeperError::ModuleNotFound { detail },
+        }
+    }
+}
+
+impl std::fmt::Display for KeeperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (name, detail) = match self {
+            KeeperError::ProcessNotFound => ("Rekordbox process not found", &None),
+            KeeperError::SnapshotFailed { detail } => ("Snapshot failed", detail),
+            KeeperError::ReadMemoryFailed { detail, label, address } => {
+                write!(f, "Read memory failed")?;
+                if let Some(label) = label {
+                    write!(f, " reading {label}")?;
+                }
+                return match detail {
+                    Some(d) => write!(f, " at {address:X}: {d}"),
+                    None => write!(f, " at {address:X}"),
+                };
+            }
+            KeeperError::WriteMemoryFailed { detail } => ("Write memory failed", detail),
+            KeeperError::ModuleNotFound { detail } => ("Module not found", detail),
+            KeeperError::WatcherFailed { detail } => return write!(f, "Failed to create file watcher: {detail}"),
+        };
+        match detail {
+            Some(d) => write!(f, "{name}: {d}"),
+            None => write!(f, "{name}"),
+        }
+    }
+}
+
+/// Connection state changes and errors, for hosts embedding the keeper (eg. a GUI)
+#[derive(Debug, PartialEq, Clone)]
+#[allow(dead_code)] // Only read by hosts, eg. the status window of the gui feature
+pub enum KeeperEvent {
+    Connected,
+    Reconnected, // Follows Connected when the connection had been lost before
+    Disconnected,
+    Error(KeeperError),
+    Master { deck: usize, bpm: f32, track: TrackInfo }, // Sent on slow updates when changed
+    Modules(Vec<(String, bool)>), // Name of each module and whether it's running, false while the watchdog restarts it
+}
+
+/// Where the keeper reads Rekordbox's state from, chosen with keeper.source_backend. Values a
+/// backend doesn't provide are reported as None or empty, like values missing from the offsets
+pub trait DeckSource {
+    /// Receive new data, if the backend isn't read on demand. Called before every update
+    fn poll(&mut self) -> Result<(), MemoryReadError> {
+        Ok(())
+    }
+    fn deckcount(&self) -> usize;
+    fn read_timing_data(&self, deck: usize) -> Result<TimingDataRaw, MemoryReadError>;
+    /// Rekordbox's index of the master deck, which may be a deck that isn't tracked
+    fn read_masterdeck_index(&self) -> Result<usize, MemoryReadError>;
+    fn read_deck_layout(&self) -> Result<Option<usize>, MemoryReadError> {
+        Ok(None)
+    }
+    fn read_crossfader(&self) -> Result<Option<f32>, MemoryReadError> {
+        Ok(None)
+    }
+    fn read_channel_faders(&self) -> Result<Option<Vec<f32>>, MemoryReadError> {
+        Ok(None)
+    }
+    fn read_cue_monitors(&self) -> Result<Option<Vec<bool>>, MemoryReadError> {
+        Ok(None)
+    }
+    fn read_perf_states(&self) -> Result<Option<Vec<PerfState>>, MemoryReadError> {
+        Ok(None)
+    }
+    fn get_track_infos(&self) -> Result<Vec<TrackInfo>, MemoryReadError> {
+        Ok(vec![])
+    }
+    fn read_preview_track(&self) -> Result<Option<TrackInfo>, MemoryReadError> {
+        Ok(None)
+    }
+    fn get_anlz_paths(&self) -> Result<Vec<String>, MemoryReadError> {
+        Ok(vec![])
+    }
+    fn get_artwork_paths(&self) -> Result<Vec<String>, MemoryReadError> {
+        Ok(vec![])
+    }
+}
+
+/// keeper.source_backend
+enum SourceBackend {
+    Memory,
+    Udp(String), // Address to listen on, keeper.source_address
+}
+
+impl SourceBackend {
+    fn from_config(keeper_config: &Config, logger: &ScopedLogger) -> Self {
+        match keeper_config.get_or_default("source_backend", "memory".to_string()).as_str() {
+            "memory" => Self::Memory,
+            "udp" => Self::Udp(keeper_config.get_or_default("source_address", "127.0.0.1:7001".to_string())),
+            other => {
+                logger.err(&format!("Unknown keeper.source_backend '{other}', expected memory or udp. Reading memory"));
+                Self::Memory
+            }
+        }
+    }
+}
+
+/// Reads Rekordbox's process memory, the default backend
 pub struct Rekordbox {
     masterdeck_index: Value<u8>,
+    deck_layout: Option<Value<u8>>,
+    crossfader: Option<Value<f32>>,
+    preview_track: Option<PointerChainString>,
     current_bpms: Vec<Value<f32>>,
     sample_positions: Vec<Value<i64>>,
-    track_infos: Vec<PointerChainValue<[u8; 200]>>,
-    anlz_paths: Vec<PointerChainValue<[u8; 500]>>,
+    track_infos: Vec<PointerChainString>,
+    anlz_paths: Vec<PointerChainString>,
+    artwork_paths: Vec<PointerChainString>,
+    channel_faders: Vec<Value<f32>>,
+    cue_monitors: Vec<Value<u8>>,
+    slip_actives: Vec<Value<u8>>,
+    loop_rolls: Vec<Value<u8>>,
     deckcount: usize,
-    phraseparser: PhraseParser,
-    mem: MemReader
+    mem: MemReader,
+    logger: ScopedLogger,
+}
+
+/// Longest text read from Rekordbox's memory, in bytes
+#[derive(Clone, Copy)]
+struct StringBufferSizes {
+    track_info: usize,
+    path: usize, // Analysis file and artwork paths
 }
 
 impl Rekordbox {
-    fn new(offsets: RekordboxOffsets, decks: usize) -> Result<Self, MemoryReadError> {
-        let mem = crate::memory::MemReader::new()?;
+    /// Tracked deck `i` is read from Rekordbox's deck `deck_map[i]`
+    fn new(offsets: RekordboxOffsets, deck_map: &[usize], buffer_sizes: StringBufferSizes, process_pid: Option<u32>, logger: &ScopedLogger) -> Result<Self, MemoryReadError> {
+        let pid = crate::memory::resolve_pid(process_pid, logger)?;
+        let mem = crate::memory::MemReader::new(pid)?;
 
-        let current_bpms = mem.new_values(&offsets.current_bpm[0..decks])?;
-        let sample_positions = mem.new_values(&offsets.sample_position[0..decks])?;
-        let track_infos = mem.new_pointerchain_values(&offsets.track_info[0..decks]);
-        let anlz_paths = mem.new_pointerchain_values(&offsets.anlz_path[0..decks]);
+        let mapped = |pointers: &[crate::memory::Pointer]| -> Vec<crate::memory::Pointer> {
+            deck_map.iter().map(|&deck| pointers[deck].clone()).collect()
+        };
+        let current_bpms = mem.new_values(&mapped(&offsets.current_bpm), "current_bpm")?;
+        let sample_positions = mem.new_values(&mapped(&offsets.sample_position), "sample_position")?;
+        let track_infos = mem.new_pointerchain_strings(&mapped(&offsets.track_info), "track_info", buffer_sizes.track_info, "keeper.track_info_buffer_size");
+        let anlz_paths = mem.new_pointerchain_strings(&mapped(&offsets.anlz_path), "anlz_path", buffer_sizes.path, "keeper.path_buffer_size");
+        let artwork_paths = if deck_map.iter().all(|&deck| deck < offsets.artwork_path.len()) {
+            mem.new_pointerchain_strings(&mapped(&offsets.artwork_path), "artwork_path", buffer_sizes.path, "keeper.path_buffer_size")
+        } else {
+            vec![]
+        };
+        let channel_faders = if deck_map.iter().all(|&deck| deck < offsets.channel_fader.len()) {
+            mem.new_values(&mapped(&offsets.channel_fader), "channel_fader").unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let cue_monitors = if deck_map.iter().all(|&deck| deck < offsets.cue_monitor.len()) {
+            mem.new_values(&mapped(&offsets.cue_monitor), "cue_monitor").unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let slip_actives = if deck_map.iter().all(|&deck| deck < offsets.slip_active.len()) {
+            mem.new_values(&mapped(&offsets.slip_active), "slip_active").unwrap_or_default()
+        } else {
+            vec![]
+        };
+        let loop_rolls = if deck_map.iter().all(|&deck| deck < offsets.loop_roll.len()) {
+            mem.new_values(&mapped(&offsets.loop_roll), "loop_roll").unwrap_or_default()
+        } else {
+            vec![]
+        };
 
         let deckcount = current_bpms.len();
 
-        let masterdeck_index_val: Value<u8> = mem.new_value(&offsets.masterdeck_index)?;
+        let masterdeck_index_val: Value<u8> = mem.new_value(&offsets.masterdeck_index, "masterdeck_index")?;
+        // Not available for all versions, and not needed for tracking
+        let deck_layout = offsets.deck_layout.as_ref().and_then(|p| mem.new_value(p, "deck_layout").ok());
+        let crossfader = offsets.crossfader.as_ref().and_then(|p| mem.new_value(p, "crossfader").ok());
+        let preview_track = offsets
+            .preview_track
+            .clone()
+            .map(|p| mem.new_pointerchain_string(p, "preview_track", buffer_sizes.track_info, "keeper.track_info_buffer_size"));
 
         Ok(Self {
             current_bpms,
             sample_positions,
             masterdeck_index: masterdeck_index_val,
+            deck_layout,
+            crossfader,
+            preview_track,
             deckcount,
             track_infos,
             anlz_paths,
-            phraseparser: PhraseParser::new(),
-            mem
+            artwork_paths,
+            channel_faders,
+            cue_monitors,
+            slip_actives,
+            loop_rolls,
+            mem,
+            logger: logger.clone(),
         })
     }
+}
+
+impl DeckSource for Rekordbox {
+    fn deckcount(&self) -> usize {
+        self.deckcount
+    }
 
     fn read_timing_data(&self, deck: usize) -> Result<TimingDataRaw, MemoryReadError> {
         let sample_position = self.sample_positions[deck].read(&self.mem)?;
+        let read_at = SystemTime::now();
         let current_bpm = self.current_bpms[deck].read(&self.mem)?;
 
         Ok(TimingDataRaw {
             current_bpm,
             sample_position,
+            read_at,
         })
     }
 
@@ -75,54 +331,139 @@ impl Rekordbox {
         Ok(self.masterdeck_index.read(&self.mem)? as usize)
     }
 
+    /// Number of decks shown in Rekordbox (2 or 4), if the offsets provide it
+    fn read_deck_layout(&self) -> Result<Option<usize>, MemoryReadError> {
+        let Some(deck_layout) = &self.deck_layout else {
+            return Ok(None);
+        };
+        let decks = deck_layout.read(&self.mem)? as usize;
+        Ok(if (1..=4).contains(&decks) { Some(decks) } else { None })
+    }
+
+    /// Crossfader position from -1 (left) to 1 (right), if the offsets provide it
+    fn read_crossfader(&self) -> Result<Option<f32>, MemoryReadError> {
+        let Some(crossfader) = &self.crossfader else {
+            return Ok(None);
+        };
+        let position = crossfader.read(&self.mem)?;
+        Ok(position.is_finite().then(|| position.clamp(-1., 1.)))
+    }
+
+    /// Channel fader of every tracked deck from 0 (closed) to 1, if the offsets provide them
+    fn read_channel_faders(&self) -> Result<Option<Vec<f32>>, MemoryReadError> {
+        if self.channel_faders.is_empty() {
+            return Ok(None);
+        }
+        self.channel_faders
+            .iter()
+            .map(|fader| Ok(fader.read(&self.mem)?.clamp(0., 1.)))
+            .collect::<Result<Vec<f32>, MemoryReadError>>()
+            .map(Some)
+    }
+
+    /// Whether the headphone cue (PFL) of every tracked deck is on, if the offsets provide it
+    fn read_cue_monitors(&self) -> Result<Option<Vec<bool>>, MemoryReadError> {
+        if self.cue_monitors.is_empty() {
+            return Ok(None);
+        }
+        self.cue_monitors
+            .iter()
+            .map(|cue| Ok(cue.read(&self.mem)? != 0))
+            .collect::<Result<Vec<bool>, MemoryReadError>>()
+            .map(Some)
+    }
+
+    /// Slip mode and loop roll of every tracked deck, if the offsets provide either
+    fn read_perf_states(&self) -> Result<Option<Vec<PerfState>>, MemoryReadError> {
+        if self.slip_actives.is_empty() && self.loop_rolls.is_empty() {
+            return Ok(None);
+        }
+        let flag = |values: &[Value<u8>], i: usize| -> Result<bool, MemoryReadError> {
+            Ok(values.get(i).map(|value| value.read(&self.mem)).transpose()?.is_some_and(|value| value != 0))
+        };
+        (0..self.deckcount)
+            .map(|i| Ok(PerfState::from_flags(flag(&self.slip_actives, i)?, flag(&self.loop_rolls, i)?)))
+            .collect::<Result<Vec<PerfState>, MemoryReadError>>()
+            .map(Some)
+    }
+
     fn get_track_infos(&self) -> Result<Vec<TrackInfo>, MemoryReadError> {
         (0..self.deckcount)
             .map(|i| {
-                let raw = self.track_infos[i]
-                    .read(&self.mem)?
-                    .into_iter()
-                    .take_while(|x| *x != 0x00)
-                    .collect::<Vec<u8>>();
-                let text = String::from_utf8(raw).unwrap_or_else(|_| "ERR".to_string());
-                let mut lines = text
-                    .lines()
-                    .map(|x| x.split_once(": ").unwrap_or(("", "")).1)
-                    .map(|x| x.to_string());
-
-                Ok(TrackInfo {
-                    title: lines.next().unwrap_or("".to_string()),
-                    artist: lines.next().unwrap_or("".to_string()),
-                    album: lines.next().unwrap_or("".to_string()),
-                })
+                let mut track = parse_track_info(&self.track_infos[i].read(&self.mem, &self.logger)?);
+
+                // The ID is only extra information, so a failed read doesn't fail the track info
+                let id = self.anlz_paths[i]
+                    .read(&self.mem, &self.logger)
+                    .ok()
+                    .and_then(|raw| track_id_from_anlz_path(&String::from_utf8(raw).ok()?));
+
+                track.id = id;
+                Ok(track)
             })
             .collect()
     }
 
+    /// Track selected in the browser, if the offsets provide it. None if nothing is selected
+    fn read_preview_track(&self) -> Result<Option<TrackInfo>, MemoryReadError> {
+        let Some(preview_track) = &self.preview_track else {
+            return Ok(None);
+        };
+        let track = parse_track_info(&preview_track.read(&self.mem, &self.logger)?);
+        Ok(if track.title.is_empty() { None } else { Some(track) })
+    }
+
     fn get_anlz_paths(&self) -> Result<Vec<String>, MemoryReadError> {
         (0..self.deckcount)
             .map(|i| {
-                let raw = self.anlz_paths[i]
-                    .read(&self.mem)?
-                    .into_iter()
-                    .take_while(|x| *x != 0x00)
-                    .collect::<Vec<u8>>();
+                let raw = self.anlz_paths[i].read(&self.mem, &self.logger)?;
+                Ok(String::from_utf8(raw).unwrap_or_else(|_| "ERR".to_string()))
+            })
+            .collect()
+    }
+
+    /// Paths to the artwork in Rekordbox's cache. Empty if the offsets don't provide them
+    fn get_artwork_paths(&self) -> Result<Vec<String>, MemoryReadError> {
+        self.artwork_paths
+            .iter()
+            .map(|artwork_path| {
+                let raw = artwork_path.read(&self.mem, &self.logger)?;
                 Ok(String::from_utf8(raw).unwrap_or_else(|_| "ERR".to_string()))
             })
             .collect()
     }
 }
 
+/// Rekordbox stores analysis files as `USBANLZ/<3 chars>/<rest of track UUID>/ANLZ0000.DAT`,
+/// so the track's UUID is the two folder names joined together
+fn track_id_from_anlz_path(path: &str) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let mut parts = path.rsplit('/').skip(1);
+    let rest = parts.next()?;
+    let prefix = parts.next()?;
+    if parts.next()? != "USBANLZ" || prefix.is_empty() || rest.is_empty() {
+        return None;
+    }
+    Some(format!("{prefix}{rest}"))
+}
+
 #[derive(Debug)]
-struct TimingDataRaw {
-    current_bpm: f32,
-    sample_position: i64,
+pub struct TimingDataRaw {
+    pub current_bpm: f32,
+    pub sample_position: i64, // At 44.1 kHz
+    pub read_at: SystemTime, // When the sample position was read
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct TrackInfo {
     pub title: String,
     pub artist: String,
     pub album: String,
+    /// Empty if Rekordbox doesn't show it for the track
+    pub genre: String,
+    pub comment: String,
+    /// Rekordbox track UUID, if it could be determined from the analysis file path
+    pub id: Option<String>,
 }
 impl Default for TrackInfo {
     fn default() -> Self {
@@ -130,10 +471,59 @@ impl Default for TrackInfo {
             title: "".to_string(),
             artist: "".to_string(),
             album: "".to_string(),
+            genre: "".to_string(),
+            comment: "".to_string(),
+            id: None,
+        }
+    }
+}
+
+/// Whether a deck's beatgrid and phrases can be trusted
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum AnalysisStatus {
+    /// A new track was loaded and its analysis files haven't been parsed yet
+    Loading = 0,
+    /// Both the DAT and EXT files were parsed
+    Ready = 1,
+    /// The analysis files couldn't be read, eg. for streaming tracks that haven't been analysed
+    Failed = 2,
+}
+
+/// Performance gestures on a deck that play something other than the track's normal flow
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum PerfState {
+    /// Plain playback
+    Normal = 0,
+    /// Slip mode is on, so the track continues silently underneath loops, scratches and reverse
+    Slip = 1,
+    /// A loop roll is held, which returns to where the track would be when released
+    LoopRoll = 2,
+}
+
+impl PerfState {
+    /// A loop roll wins over slip mode, as it's the gesture being performed
+    fn from_flags(slip: bool, loop_roll: bool) -> Self {
+        if loop_roll {
+            PerfState::LoopRoll
+        } else if slip {
+            PerfState::Slip
+        } else {
+            PerfState::Normal
         }
     }
 }
 
+/// Why the master track was sent again
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum MasterChangeReason {
+    /// Same track, resent by the track info heartbeat
+    Refresh = 0,
+    /// A new track was loaded on the master deck
+    NewTrack = 1,
+    /// Another deck became master
+    DeckSwitched = 2,
+}
+
 #[derive(Clone)]
 struct ChangeTrackedValue<T> {
     value: T,
@@ -150,6 +540,10 @@ impl<T: std::cmp::PartialEq> ChangeTrackedValue<T> {
             false
         }
     }
+    /// Stores the value, returning whether it should be sent: when it changed or the heartbeat is due
+    fn set_or_heartbeat(&mut self, value: T, heartbeat: bool) -> bool {
+        self.set(value) || heartbeat
+    }
 }
 
 struct HeartbeatConfig {
@@ -164,21 +558,221 @@ struct HeartbeatConfig {
 
 }
 
+/// Boundary that master phrase changes are held until, so scene changes land on the music
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Quantize {
+    Off,
+    Beat,
+    Bar,
+}
+
+impl Quantize {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "off" => Some(Quantize::Off),
+            "beat" => Some(Quantize::Beat),
+            "bar" => Some(Quantize::Bar),
+            _ => None,
+        }
+    }
+
+    /// Whether a boundary was passed between two consecutive master beats
+    fn crossed(&self, last_beat: f32, beat: f32) -> bool {
+        match self {
+            Quantize::Off => true,
+            Quantize::Beat => beat.floor() != last_beat.floor(),
+            Quantize::Bar => beat < last_beat,
+        }
+    }
+}
+
+/// How long before the deadline `sleep_until` stops sleeping and spins, to cover the scheduler's
+/// wake-up jitter
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+/// With `precise`, sleeps until just before the deadline and busy-waits the rest, at the cost of
+/// some CPU
+fn sleep_until(deadline: Instant, precise: bool) {
+    let now = Instant::now();
+    if deadline <= now {
+        return;
+    }
+    if !precise {
+        thread::sleep(deadline - now);
+        return;
+    }
+    if deadline - now > SPIN_MARGIN {
+        thread::sleep(deadline - now - SPIN_MARGIN);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+/// Raises the Windows timer resolution to 1 ms while alive, so sleeps wake up on time instead of
+/// on the default ~15 ms ticks. Does nothing on other platforms
+struct TimerResolution;
+
+impl TimerResolution {
+    fn new() -> Self {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            winapi::um::timeapi::timeBeginPeriod(1);
+        }
+        TimerResolution
+    }
+}
+
+impl Drop for TimerResolution {
+    fn drop(&mut self) {
+        #[cfg(target_os = "windows")]
+        unsafe {
+            winapi::um::timeapi::timeEndPeriod(1);
+        }
+    }
+}
+
+/// Seconds the remaining time has to rise above the threshold before the end warning is cleared
+const END_WARNING_HYSTERESIS: f32 = 2.;
+
+/// Whether the master track is about to end, debounced so it doesn't flap around the threshold
+struct EndWarning {
+    seconds: Option<f32>, // None disables
+    active: bool,
+}
+
+impl EndWarning {
+    /// Returns the new state when it changed. Cleared when the remaining time jumps back up,
+    /// eg. for a new track, or when it is unknown
+    fn update(&mut self, remaining: Option<f32>) -> Option<bool> {
+        let seconds = self.seconds?;
+        let active = match remaining {
+            Some(remaining) if self.active => remaining <= seconds + END_WARNING_HYSTERESIS,
+            Some(remaining) => remaining < seconds,
+            None => false,
+        };
+        if active == self.active {
+            return None;
+        }
+        self.active = active;
+        Some(active)
+    }
+}
+
+/// Side of the crossfader a deck's channel is assigned to, as set on the mixer
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CrossfaderAssign {
+    A, // Left
+    B, // Right
+    Thru,
+}
+
+/// Parse keeper.crossfader_assign, eg. "a,b,thru,thru", for the tracked decks in order. Decks
+/// not listed are Thru
+fn parse_crossfader_assign(value: &str, logger: &ScopedLogger) -> Vec<CrossfaderAssign> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.to_lowercase().as_str() {
+            "a" => CrossfaderAssign::A,
+            "b" => CrossfaderAssign::B,
+            "thru" => CrossfaderAssign::Thru,
+            _ => {
+                logger.err(&format!("Invalid crossfader assignment '{x}', expected a, b or thru. Using thru"));
+                CrossfaderAssign::Thru
+            }
+        })
+        .collect()
+}
+
+/// Decides whether a playing deck can be heard, from its channel fader and the crossfader
+struct OnAirConfig {
+    volume_threshold: f32,     // Channel fader, 0 to 1
+    crossfader_threshold: f32, // Level through the crossfader, 0 to 1
+    assign: Vec<CrossfaderAssign>, // Indexed by tracked deck
+}
+
+impl OnAirConfig {
+    /// Faders the offsets don't provide count as open
+    fn audible(&self, deck: usize, fader: Option<f32>, crossfader: Option<f32>) -> bool {
+        let fader_open = !fader.is_some_and(|level| level <= self.volume_threshold);
+        // Linear, 1 at the deck's own side and 0 at the opposite end
+        let level = match (self.assign.get(deck).copied().unwrap_or(CrossfaderAssign::Thru), crossfader) {
+            (CrossfaderAssign::Thru, _) | (_, None) => 1.,
+            (CrossfaderAssign::A, Some(position)) => (1. - position) / 2.,
+            (CrossfaderAssign::B, Some(position)) => (1. + position) / 2.,
+        };
+        fader_open && level > self.crossfader_threshold
+    }
+}
+
+/// Weights for the derived energy value, from the energy config section
+struct EnergyConfig {
+    phrase_weights: [f32; 6], // Indexed by PhraseParser::phrase_name_to_index
+    bpm_weight: f32,
+    bpm_min: f32,
+    bpm_max: f32,
+}
+
+impl EnergyConfig {
+    fn new(conf: &Config) -> Self {
+        Self {
+            phrase_weights: [
+                conf.get_or_default("none", 0.5),
+                conf.get_or_default("intro", 0.3),
+                conf.get_or_default("verse", 0.6),
+                conf.get_or_default("chorus", 1.0),
+                conf.get_or_default("bridge", 0.4),
+                conf.get_or_default("outro", 0.3),
+            ],
+            bpm_weight: conf.get_or_default::<f32>("bpm_weight", 0.3).clamp(0., 1.),
+            bpm_min: conf.get_or_default("bpm_min", 80.),
+            bpm_max: conf.get_or_default("bpm_max", 160.),
+        }
+    }
+
+    /// Energy from 0 to 1
+    fn energy(&self, phrase: &str, bpm: f32) -> f32 {
+        let phrase_energy = self.phrase_weights[PhraseParser::phrase_name_to_index(phrase) as usize];
+        let bpm_energy = (bpm - self.bpm_min) / (self.bpm_max - self.bpm_min).max(1.);
+        ((1. - self.bpm_weight) * phrase_energy + self.bpm_weight * bpm_energy.clamp(0., 1.)).clamp(0., 1.)
+    }
+}
+
 pub struct BeatKeeper {
+    rekordbox: Option<Box<dyn DeckSource>>,
+    source_backend: SourceBackend,
+    phraseparser: PhraseParser,
+    has_connected: bool, // Connected to Rekordbox at least once, to tell reconnects apart
+    offsets: RekordboxOffsets,
+    fallback_offsets: Option<RekordboxOffsets>, // Swapped with offsets after repeated read failures
+    read_failures: u32,
+    rekordbox_decks: usize,
+    deck_map: Option<Vec<usize>>, // keeper.deck_map, replaces keeper.decks
+    process_pid: Option<u32>,
+    string_buffer_sizes: StringBufferSizes,
+    update_count: i32,
+    slow_update_denominator: i32,
+    very_slow_update_denominator: i32,
     masterdeck_index: ChangeTrackedValue<usize>,
     offset_samples: i64,
-    running_modules: Vec<Box<dyn OutputModule>>,
+    running_modules: Vec<RunningModule>,
 
     track_infos: Vec<ChangeTrackedValue<TrackInfo>>,
     track_trackers: Vec<TrackTracker>,
 
     anlz_paths: Vec<ChangeTrackedValue<String>>,
+    analysis_status: Vec<ChangeTrackedValue<AnalysisStatus>>,
+    artwork_paths: Vec<ChangeTrackedValue<String>>,
     watcher: notify::RecommendedWatcher,
     watcher_rx: mpsc::Receiver<notify::Result<notify::Event>>,
 
     logger: ScopedLogger,
     last_error: Option<MemoryReadError>,
+    events: Option<mpsc::Sender<KeeperEvent>>,
     keep_warm: bool,
+    master_only: bool,
     decks: usize,
 
 
@@ -187,6 +781,300 @@ pub struct BeatKeeper {
 
     hearbeat_config: HeartbeatConfig,
     very_slow_update_flag: bool,
+    muted: bool,
+    resend_pending: bool, // Everything is sent again on the next update, eg. after unmuting
+    resend_tracks: bool,  // Latched until the next slow update, like very_slow_update_flag
+    master_out_of_range_reported: bool,
+    suppress_when_idle: bool,
+    master_idle: ChangeTrackedValue<bool>,
+    energy_config: EnergyConfig,
+    energy: ChangeTrackedValue<f32>,
+    master_switch_debounce: Duration,
+    load_freeze: Duration, // keeper.freeze_on_load_ms
+    frozen_until: [Option<Instant>; 4], // Beat and time are held after a track change, until the analysis is loaded or this passes
+    pending_masterdeck_index: Option<(usize, Instant)>,
+    quantize: Quantize,
+    pending_phrase: Option<String>,
+    last_master_beat: f32,
+    end_warning: EndWarning,
+    on_air_config: OnAirConfig,
+    crossfader: ChangeTrackedValue<f32>,
+    preview_track: ChangeTrackedValue<Option<TrackInfo>>,
+    start_time: Instant,
+    startup_grace: Duration,
+    waiting_reported: bool,
+    optional_read_failed: [bool; 9], // Indexed by OptionalValue
+    idle_heartbeat_interval: Option<Duration>,
+    last_idle_heartbeat: Instant,
+    watchdog: bool,
+    watchdog_backoff: Duration,
+    restarting_modules: Vec<RestartingModule>,
+    stats_line: bool,
+    step_division: u32, // keeper.step_division, for sixteenth_changed
+    unknown_title: String,
+    unknown_artist: String,
+    master_status: ChangeTrackedValue<(usize, f32, TrackInfo)>, // Last sent KeeperEvent::Master
+}
+
+/// A started module, with an optional limit on how often the high-frequency callbacks
+/// (beat, time and phase) are called
+struct RunningModule {
+    module: Box<dyn OutputModule>,
+    spec: ModuleSpec,
+    min_interval: Option<Duration>,
+    last_fast_update: Option<Instant>,
+    fast_due: bool,
+}
+
+/// Everything needed to create a module again
+#[derive(Clone)]
+struct ModuleSpec {
+    definition: ModuleDefinition,
+    conf: Config,
+    logger: ScopedLogger,
+    max_rate: f32, // Highest rate in Hz for the beat, time and phase callbacks, 0 for every update
+}
+
+impl ModuleSpec {
+    fn create(&self) -> Result<RunningModule, ()> {
+        let module = (self.definition.create)(self.conf.clone(), self.logger.clone())?;
+        Ok(RunningModule::new(module, self.clone()))
+    }
+}
+
+/// Create the enabled modules. With `record` and debug.record_events, the event recorder is
+/// started last, so it records what every other module was sent
+fn start_modules(
+    modules: Vec<ModuleDefinition>,
+    config: &Config,
+    logger: &ScopedLogger,
+    record: bool,
+) -> Vec<RunningModule> {
+    let recorder = (record && config.get_or_default("debug.record_events", false))
+        .then(|| ModuleDefinition::new("debug", "Event recorder", EventRecorder::create));
+    let enabled = modules
+        .into_iter()
+        .filter(|module| config.get_or_default(&format!("{}.enabled", module.config_name), false));
+
+    let mut running_modules = vec![];
+
+    logger.info("Active modules:");
+    for module in enabled.chain(recorder) {
+        logger.info(&format!(" - {}", module.pretty_name));
+
+        let conf = config.reduce_to_namespace(&module.config_name);
+        let spec = ModuleSpec {
+            max_rate: conf.get_or_default("max_rate", 0.),
+            logger: ScopedLogger::new(&logger.logger, &module.pretty_name),
+            conf,
+            definition: module,
+        };
+        match spec.create() {
+            Ok(module) => {
+                running_modules.push(module);
+            }
+            Err(()) => {
+                logger.err(&format!("Failed to start module {}", spec.definition.pretty_name));
+            }
+        }
+    }
+    running_modules
+}
+
+/// Send an event log recorded with debug.record_events to the enabled modules at its original
+/// timing, without reading Rekordbox. Runs until the log ends or a stop is requested
+pub fn replay(path: &str, modules: Vec<ModuleDefinition>, config: Config, logger: ScopedLogger) {
+    let events = match read_recording(path, &logger) {
+        Ok(events) => events,
+        Err(e) => {
+            logger.err(&format!("Failed to read event log {path}: {e}"));
+            return;
+        }
+    };
+    let precise_timing = config.get_or_default("keeper.precise_timing", false);
+    let _timer_resolution = TimerResolution::new();
+
+    // Not recorded again, replaying a log into itself would truncate it
+    let mut running_modules = start_modules(modules, &config, &logger, false);
+
+    logger.info(&format!("Replaying {} events from {path}", events.len()));
+    let started = Instant::now();
+    for recorded in &events {
+        if KEEPER_CONTROL.stop_requested() {
+            break;
+        }
+        sleep_until(started + Duration::from_secs_f64(recorded.t.max(0.)), precise_timing);
+        for module in &mut running_modules {
+            recorded.event.dispatch(module.module.as_mut());
+        }
+    }
+
+    for module in &mut running_modules {
+        module.shutdown();
+    }
+    logger.info("Replay finished");
+}
+
+/// Phrases the metronome cycles through, in order
+const METRONOME_PHRASES: [&str; 5] = ["Intro", "Verse 1", "Chorus", "Bridge", "Outro"];
+
+/// Feed the enabled modules a steady 4/4 beat at `bpm` on deck 0, without reading Rekordbox, eg.
+/// to check a venue's lighting before the show. With `phrase_bars`, the phrase changes every that
+/// many bars. Runs until a stop is requested
+pub fn metronome(bpm: f32, phrase_bars: Option<u32>, modules: Vec<ModuleDefinition>, config: Config, logger: ScopedLogger) {
+    let update_rate = config.get_or_default("keeper.update_rate", 50);
+    let period = Duration::from_micros(1000000 / update_rate);
+    let precise_timing = config.get_or_default("keeper.precise_timing", false);
+    let slow_update_denominator = config.get_every_nth("keeper.slow_update_every_nth", 50);
+    let very_slow_update_denominator = config.get_every_nth("keeper.very_slow_update_every_nth", 1200);
+    let energy_config = EnergyConfig::new(&config.reduce_to_namespace("energy"));
+    let _timer_resolution = TimerResolution::new();
+
+    let mut running_modules = start_modules(modules, &config, &logger, true);
+
+    let track = TrackInfo {
+        title: format!("Metronome {bpm} BPM"),
+        artist: "rkbx_link".to_string(),
+        ..TrackInfo::default()
+    };
+    for module in &mut running_modules {
+        module.connection_changed(true);
+        module.masterdeck_index_changed(0);
+        module.track_change_reason_master(MasterChangeReason::NewTrack);
+        module.track_changed(&track, 0);
+        module.track_changed_master(&track);
+        module.master_changed(0, &track);
+        module.analysis_status_changed(AnalysisStatus::Ready, 0);
+        module.bar_length_changed(4, 0);
+        module.bar_length_changed_master(4);
+        module.beat_valid_changed(true, 0);
+        module.beat_valid_changed_master(true);
+        module.bpm_changed(bpm, 0);
+        module.bpm_changed_master(bpm);
+        module.original_bpm_changed(bpm, 0);
+        module.original_bpm_changed_master(bpm);
+        module.play_state_changed(true, 0);
+    }
+
+    logger.info(&format!("Sending a metronome at {bpm} BPM, press Ctrl+C to stop"));
+    let started = Instant::now();
+    let mut phrase = ChangeTrackedValue::new(usize::MAX);
+    let mut next_phrase_in = ChangeTrackedValue::new(-1);
+    let mut countdown = ChangeTrackedValue::new(0);
+    let step_division = step_division(&config.reduce_to_namespace("keeper"), &logger);
+    let mut step = ChangeTrackedValue::new(-1);
+    let mut update_count: u64 = 0;
+    while !KEEPER_CONTROL.stop_requested() {
+        let update_start_time = Instant::now();
+        let time = started.elapsed().as_secs_f64();
+        let beats = time * bpm as f64 / 60.;
+        let beat = (beats % 4.) as f32;
+        let countdown_changed = countdown.set(bar_countdown(beat, 4));
+        let step_changed = step.set(step_in_bar(beat, step_division));
+
+        // Whole beats into the current phrase, and the phrase's index
+        let phrase_beats = phrase_bars.map(|bars| bars as u64 * 4);
+        let phrase_changed = phrase_beats.is_some_and(|n| phrase.set((beats as u64 / n) as usize % METRONOME_PHRASES.len()));
+        let next_phrase_in_changed = phrase_beats.is_some_and(|n| next_phrase_in.set((n - beats as u64 % n) as i32));
+        let current_phrase = PhraseParser::alias(METRONOME_PHRASES.get(phrase.value).copied().unwrap_or_default());
+        let next_phrase = PhraseParser::alias(METRONOME_PHRASES[(phrase.value.wrapping_add(1)) % METRONOME_PHRASES.len()]);
+
+        for module in &mut running_modules {
+            module.limit_rate();
+            module.pre_update();
+            if module.fast_due {
+                module.beat_update(beat, 0);
+                module.time_update(time as f32, 0);
+                module.beat_timestamp_master(SystemTime::now());
+                module.beat_update_master(beat);
+                module.absolute_beat_update_master(beats as f32);
+                module.time_update_master(time as f32);
+            }
+            if countdown_changed {
+                module.bar_countdown_changed(countdown.value, 0);
+                module.bar_countdown_changed_master(countdown.value);
+            }
+            if step_changed {
+                module.sixteenth_changed(step.value, 0);
+                module.sixteenth_changed_master(step.value);
+            }
+            if phrase_changed {
+                module.phrase_changed(&current_phrase, 0);
+                module.phrase_changed_master(&current_phrase);
+                module.next_phrase_changed(&next_phrase, 0);
+                module.next_phrase_changed_master(&next_phrase);
+                module.energy_changed(energy_config.energy(&current_phrase, bpm));
+            }
+            if next_phrase_in_changed {
+                module.next_phrase_in(next_phrase_in.value, 0);
+                module.next_phrase_in_master(next_phrase_in.value);
+            }
+            if update_count.is_multiple_of(slow_update_denominator as u64) {
+                module.slow_update();
+                if update_count.is_multiple_of(very_slow_update_denominator as u64) {
+                    module.very_slow_update();
+                }
+            }
+        }
+        update_count += 1;
+        sleep_until(update_start_time + period, precise_timing);
+    }
+
+    for module in &mut running_modules {
+        module.shutdown();
+    }
+    logger.info("Metronome stopped");
+}
+
+/// A module torn down by the watchdog, waiting to be created again
+struct RestartingModule {
+    spec: ModuleSpec,
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+/// Longest wait between attempts to recreate a failed module
+const MAX_WATCHDOG_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Failed connection attempts or updates in a row before trying the other offset set
+const OFFSET_SWITCH_FAILURES: u32 = 3;
+
+impl RunningModule {
+    fn new(module: Box<dyn OutputModule>, spec: ModuleSpec) -> Self {
+        Self {
+            module,
+            // 0 or less means every update
+            min_interval: (spec.max_rate > 0.).then(|| Duration::from_secs_f32(1. / spec.max_rate)),
+            spec,
+            last_fast_update: None,
+            fast_due: true,
+        }
+    }
+
+    /// Decide whether the module gets this update's high-frequency callbacks
+    fn limit_rate(&mut self) {
+        self.fast_due = match (self.min_interval, self.last_fast_update) {
+            (Some(interval), Some(last)) => last.elapsed() >= interval,
+            _ => true,
+        };
+        if self.fast_due {
+            self.last_fast_update = Some(Instant::now());
+        }
+    }
+}
+
+impl Deref for RunningModule {
+    type Target = dyn OutputModule;
+
+    fn deref(&self) -> &Self::Target {
+        self.module.as_ref()
+    }
+}
+
+impl DerefMut for RunningModule {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.module.as_mut()
+    }
 }
 
 struct TrackingDataTracker {
@@ -197,6 +1085,18 @@ struct TrackingDataTracker {
     phrase: ChangeTrackedValue<String>,
     next_phrase: ChangeTrackedValue<String>,
     next_phrase_in: ChangeTrackedValue<i32>,
+    bpm_offset: ChangeTrackedValue<f32>,
+    phase_offset: ChangeTrackedValue<f32>,
+    playing: ChangeTrackedValue<bool>,
+    last_moved: Option<Instant>,
+    beat_valid: ChangeTrackedValue<bool>,
+    bar_length: ChangeTrackedValue<u32>,
+    bar_countdown: ChangeTrackedValue<i32>,
+    sixteenth: ChangeTrackedValue<i32>,
+    mood: ChangeTrackedValue<u8>,
+    on_air: ChangeTrackedValue<bool>,
+    cue_monitor: ChangeTrackedValue<bool>,
+    perf_state: ChangeTrackedValue<PerfState>,
 }
 
 impl TrackingDataTracker {
@@ -209,69 +1109,147 @@ impl TrackingDataTracker {
             phrase: ChangeTrackedValue::new("".to_string()),
             next_phrase: ChangeTrackedValue::new("".to_string()),
             next_phrase_in: ChangeTrackedValue::new(0),
+            bpm_offset: ChangeTrackedValue::new(0.),
+            phase_offset: ChangeTrackedValue::new(0.),
+            playing: ChangeTrackedValue::new(false),
+            last_moved: None,
+            beat_valid: ChangeTrackedValue::new(false),
+            bar_length: ChangeTrackedValue::new(4),
+            bar_countdown: ChangeTrackedValue::new(0),
+            sixteenth: ChangeTrackedValue::new(-1),
+            mood: ChangeTrackedValue::new(0),
+            on_air: ChangeTrackedValue::new(false),
+            cue_monitor: ChangeTrackedValue::new(false),
+            perf_state: ChangeTrackedValue::new(PerfState::Normal),
+        }
+    }
+}
+
+impl Drop for BeatKeeper {
+    fn drop(&mut self) {
+        for module in &mut self.running_modules {
+            module.shutdown();
         }
     }
 }
 
 impl BeatKeeper {
+    /// Run the keeper on the current thread until a stop is requested, reconnecting to Rekordbox when needed
     pub fn start(
         offsets: RekordboxOffsets,
+        fallback_offsets: Option<RekordboxOffsets>,
         modules: Vec<ModuleDefinition>,
         config: Config,
         logger: ScopedLogger,
+        events: Option<mpsc::Sender<KeeperEvent>>,
     ) {
-        let keeper_config = config.reduce_to_namespace("keeper");
-        let update_rate = keeper_config.get_or_default("update_rate", 50);
-        let slow_update_denominator = keeper_config.get_or_default("slow_update_every_nth", 50);
-        let very_slow_update_denominator = keeper_config.get_or_default("very_slow_update_every_nth", 1200);
-
-        let mut running_modules = vec![];
+        let update_rate = config.get_or_default("keeper.update_rate", 50);
+        let period = Duration::from_micros(1000000 / update_rate); // 50Hz
+        // Slower updates while no deck is playing, None to always run at update_rate
+        let idle_period = config
+            .get_or_default("keeper.adaptive_rate", false)
+            .then(|| Duration::from_micros(1000000 / config.get_or_default("keeper.idle_update_rate", 5u64).max(1)));
+        let precise_timing = config.get_or_default("keeper.precise_timing", false);
+        let _timer_resolution = TimerResolution::new();
+
+        let Ok(mut keeper) = BeatKeeper::new(offsets, fallback_offsets, modules, config, logger.clone(), events) else {
+            return;
+        };
 
-        logger.info("Active modules:");
-        for module in modules {
-            if !config.get_or_default(&format!("{}.enabled", module.config_name), false) {
-                continue;
-            }
-            logger.info(&format!(" - {}", module.pretty_name));
-
-            let conf = config.reduce_to_namespace(&module.config_name);
-            match (module.create)(conf, ScopedLogger::new(&logger.logger, &module.pretty_name)) {
-                Ok(module) => {
-                    running_modules.push(module);
+        logger.info("Looking for Rekordbox...");
+        logger.blank_line();
+
+        while !KEEPER_CONTROL.stop_requested() {
+            let was_connected = keeper.is_connected();
+            let update_start_time = std::time::Instant::now();
+            match keeper.tick() {
+                Ok(()) => {
+                    if was_connected {
+                        // Playback is still picked up at the idle rate, switching back on the next update
+                        let period = match idle_period {
+                            Some(idle_period) if !keeper.any_deck_playing() => idle_period,
+                            _ => period,
+                        };
+                        sleep_until(update_start_time + period, precise_timing);
+                    }
                 }
-                Err(()) => {
-                    logger.err(&format!("Failed to start module {}", module.pretty_name));
+                Err(_) => {
+                    if was_connected {
+                        logger.info("Reconnecting in 3s...");
+                    } else {
+                        logger.info("...");
+                    }
+                    thread::sleep(Duration::from_secs(3));
                 }
             }
         }
+        logger.info("Stopping");
+    }
+
+    /// Start the enabled modules. Rekordbox is connected to by `tick`. With `fallback_offsets`, eg.
+    /// for another Rekordbox layout, the two sets are tried in turn while reads keep failing
+    pub fn new(
+        offsets: RekordboxOffsets,
+        fallback_offsets: Option<RekordboxOffsets>,
+        modules: Vec<ModuleDefinition>,
+        config: Config,
+        logger: ScopedLogger,
+        events: Option<mpsc::Sender<KeeperEvent>>,
+    ) -> Result<Self, KeeperError> {
+        let keeper_config = config.reduce_to_namespace("keeper");
+        KEEPER_CONTROL.set_beat_phase_offset(keeper_config.get_or_default("beat_phase_offset", 0.));
+        let deck_map = parse_deck_map(&keeper_config.get_or_default("deck_map", String::new()), &logger);
+
+        let running_modules = start_modules(modules, &config, &logger, true);
 
         let (watcher_tx, watcher_rx) = mpsc::channel();
         let watcher = match notify::recommended_watcher(watcher_tx){
             Ok(w) => w,
             Err(e) => {
                 logger.err(&format!("Failed to create watcher: {e}"));
-                return;
+                return Err(KeeperError::WatcherFailed { detail: e.to_string() });
             }
         };
 
         // Read heartbeat config once at startup
 
-        let mut keeper = BeatKeeper {
+        let keeper = BeatKeeper {
+            rekordbox: None,
+            source_backend: SourceBackend::from_config(&keeper_config, &logger),
+            phraseparser: PhraseParser::new(),
+            has_connected: false,
+            offsets,
+            fallback_offsets,
+            read_failures: 0,
+            rekordbox_decks: deck_map.as_ref().map_or(keeper_config.get_or_default("decks", 2), Vec::len),
+            deck_map: deck_map.clone(),
+            // Empty to attach to the first Rekordbox found
+            process_pid: keeper_config.get_or_default("process_pid", String::new()).parse().ok(),
+            string_buffer_sizes: StringBufferSizes {
+                track_info: keeper_config.get_or_default("track_info_buffer_size", 200),
+                path: keeper_config.get_or_default("path_buffer_size", 500),
+            },
+            update_count: 0,
+            slow_update_denominator: keeper_config.get_every_nth("slow_update_every_nth", 50),
+            very_slow_update_denominator: keeper_config.get_every_nth("very_slow_update_every_nth", 1200),
             masterdeck_index: ChangeTrackedValue::new(0),
-            offset_samples: (keeper_config.get_or_default("delay_compensation", 0.) * 44100. / 1000.) as i64,
+            offset_samples: delay_compensation_samples(keeper_config.get_or_default("delay_compensation", 0.)),
             track_infos: vec![ChangeTrackedValue::new(Default::default()); 4],
             running_modules,
             logger: logger.clone(),
             last_error: None,
-            track_trackers: (0..4).map(|_| TrackTracker::new()).collect(),
+            events,
+            track_trackers: (0..4).map(|_| TrackTracker::new(keeper_config.get_or_default("fallback_beats", false))).collect(),
             keep_warm: keeper_config.get_or_default("keep_warm", true),
-            decks: keeper_config.get_or_default("decks", 4),
+            master_only: keeper_config.get_or_default("master_only", false),
+            decks: deck_map.as_ref().map_or(keeper_config.get_or_default("decks", 4), Vec::len),
             td_trackers: (0..4).map(|_| TrackingDataTracker::new()).collect(),
             master_td_tracker: TrackingDataTracker::new(),
             anlz_paths: vec![ChangeTrackedValue::new("".to_string()); 4],
+            analysis_status: vec![ChangeTrackedValue::new(AnalysisStatus::Loading); 4],
+            artwork_paths: vec![ChangeTrackedValue::new("".to_string()); 4],
             watcher,
             watcher_rx,
-<<<<<<< master
             hearbeat_config: HeartbeatConfig {
                 beat: keeper_config.get_or_default("heartbeat.beat", false),
                 pos: keeper_config.get_or_default("heartbeat.time", false),
@@ -283,158 +1261,476 @@ impl BeatKeeper {
                 phrase: keeper_config.get_or_default("heartbeat.phrase", false),
             },
             very_slow_update_flag: false,
-=======
->>>>>>> master
+            muted: false,
+            resend_pending: false,
+            resend_tracks: false,
+            master_out_of_range_reported: false,
+            suppress_when_idle: keeper_config.get_or_default("suppress_when_idle", false),
+            master_idle: ChangeTrackedValue::new(false),
+            energy_config: EnergyConfig::new(&config.reduce_to_namespace("energy")),
+            on_air_config: OnAirConfig {
+                volume_threshold: keeper_config.get_or_default("onair_volume_threshold", 0.05),
+                crossfader_threshold: keeper_config.get_or_default("onair_crossfader_threshold", 0.05),
+                assign: parse_crossfader_assign(&keeper_config.get_or_default("crossfader_assign", String::new()), &logger),
+            },
+            energy: ChangeTrackedValue::new(0.),
+            master_switch_debounce: Duration::from_millis(keeper_config.get_or_default("master_switch_debounce_ms", 0)),
+            load_freeze: Duration::from_millis(keeper_config.get_or_default("freeze_on_load_ms", 0)),
+            frozen_until: [None; 4],
+            pending_masterdeck_index: None,
+            quantize: {
+                let mode = keeper_config.get_or_default("quantize", "off".to_string());
+                Quantize::from_str(&mode).unwrap_or_else(|| {
+                    logger.err(&format!("Unknown quantize mode: {mode}"));
+                    Quantize::Off
+                })
+            },
+            pending_phrase: None,
+            last_master_beat: 0.,
+            end_warning: EndWarning {
+                seconds: keeper_config.get_or_default("end_warning_seconds", String::new()).parse().ok(),
+                active: false,
+            },
+            crossfader: ChangeTrackedValue::new(0.),
+            preview_track: ChangeTrackedValue::new(None),
+            start_time: Instant::now(),
+            startup_grace: Duration::from_millis(keeper_config.get_or_default("startup_grace_ms", 0)),
+            waiting_reported: false,
+            optional_read_failed: [false; 9],
+            watchdog: keeper_config.get_or_default("watchdog", false),
+            watchdog_backoff: Duration::from_millis(keeper_config.get_or_default("watchdog_backoff_ms", 5000)),
+            restarting_modules: vec![],
+            // 0 to disable
+            idle_heartbeat_interval: Some(keeper_config.get_or_default("idle_heartbeat_ms", 0))
+                .filter(|ms| *ms > 0)
+                .map(Duration::from_millis),
+            last_idle_heartbeat: Instant::now(),
+            stats_line: keeper_config.get_or_default("stats_line", false),
+            step_division: step_division(&keeper_config, &logger),
+            unknown_title: keeper_config.get_or_default("unknown_title", String::new()),
+            unknown_artist: keeper_config.get_or_default("unknown_artist", String::new()),
+            master_status: ChangeTrackedValue::new((usize::MAX, 0., TrackInfo::default())),
         };
+        keeper.send_module_states();
+        Ok(keeper)
+    }
 
-        let mut rekordbox = None;
+    /// Run a single update, or try to connect if Rekordbox isn't connected. Doesn't sleep, call
+    /// it at the desired update rate, and back off a few seconds after an error
+    pub fn tick(&mut self) -> Result<(), KeeperError> {
+        self.idle_heartbeat();
+
+        let Some(mut rb) = self.rekordbox.take() else {
+            return match self.connect() {
+                Ok(rb) => {
+                    self.rekordbox = Some(rb);
+                    self.logger.blank_line();
+                    self.logger.good("Connected to Rekordbox!");
+                    self.last_error = None;
+                    self.send_event(KeeperEvent::Connected);
+                    let reconnected = std::mem::replace(&mut self.has_connected, true);
+                    if reconnected {
+                        self.send_event(KeeperEvent::Reconnected);
+                    }
+                    for module in &mut self.running_modules {
+                        module.connection_changed(true);
+                        if reconnected {
+                            module.reconnected();
+                        }
+                    }
+                    Ok(())
+                }
+                Err(e) => {
+                    let error = KeeperError::from(&e);
+                    let error_type = e.error_type.clone();
+                    self.report_error(e);
+                    self.count_read_failure(error_type);
+                    Err(error)
+                }
+            };
+        };
 
-        let period = Duration::from_micros(1000000 / update_rate); // 50Hz
-        let mut n = 0;
+        self.apply_mute();
+        // While muted, values are still read and tracked so nothing jumps on unmute. Only
+        // pre_update is called, so modules can still receive input, eg. the OSC unmute
+        let muted_modules = if self.muted {
+            for module in &mut self.running_modules {
+                module.pre_update();
+            }
+            std::mem::take(&mut self.running_modules)
+        } else {
+            vec![]
+        };
 
-        logger.info("Looking for Rekordbox...");
-        println!();
+        let n = self.update_count;
+        let result = rb
+            .poll()
+            .and_then(|()| self.update(rb.as_ref(), n % self.slow_update_denominator == 0, n % self.very_slow_update_denominator == 0));
+        if self.muted {
+            // Keep any modules the watchdog restarted meanwhile
+            let restarted = std::mem::replace(&mut self.running_modules, muted_modules);
+            self.running_modules.extend(restarted);
+        }
+        if let Err(e) = result {
+            let error = KeeperError::from(&e);
+            let error_type = e.error_type.clone();
+            self.report_error(e);
+            self.count_read_failure(error_type);
+
+            self.send_event(KeeperEvent::Disconnected);
+            self.logger.err("Connection to Rekordbox lost");
+            for module in &mut self.running_modules {
+                module.connection_changed(false);
+            }
+            return Err(error);
+        }
+        self.update_count = self.update_count.wrapping_add(1);
+        self.read_failures = 0;
+        self.rekordbox = Some(rb);
+        Ok(())
+    }
 
-        loop {
-            if let Some(rb) = &rekordbox {
-                let update_start_time = std::time::Instant::now();
-                if let Err(e) = keeper.update(rb, n % slow_update_denominator == 0, n % very_slow_update_denominator == 0) {
-                    keeper.report_error(e);
+    fn connect(&self) -> Result<Box<dyn DeckSource>, MemoryReadError> {
+        let deck_map = self.deck_map.clone().unwrap_or_else(|| (0..self.rekordbox_decks).collect());
+        Ok(match &self.source_backend {
+            SourceBackend::Memory => Box::new(Rekordbox::new(self.offsets.clone(), &deck_map, self.string_buffer_sizes, self.process_pid, &self.logger)?),
+            SourceBackend::Udp(address) => Box::new(UdpSource::new(address, &deck_map)?),
+        })
+    }
 
-                    rekordbox = None;
-                    logger.err("Connection to Rekordbox lost");
-                    logger.info("Reconnecting in 3s...");
-                    thread::sleep(Duration::from_secs(3));
-                } else {
-                    n += 1;
-                    let elapsed = update_start_time.elapsed();
-                    if period > elapsed {
-                        thread::sleep(period - elapsed);
-                    }
+    /// Switch to the fallback offsets after repeated read failures, eg. when Rekordbox's layout
+    /// was changed. The sets are swapped, so the original one is tried again if that fails too
+    fn count_read_failure(&mut self, error_type: MemoryReadErrorType) {
+        if error_type != MemoryReadErrorType::ReadMemoryFailed {
+            return;
+        }
+        let Some(fallback) = &mut self.fallback_offsets else {
+            return;
+        };
+        self.read_failures += 1;
+        if self.read_failures < OFFSET_SWITCH_FAILURES {
+            return;
+        }
+        self.read_failures = 0;
+        std::mem::swap(&mut self.offsets, fallback);
+        // The errors of the new set should be reported, even if they are the same
+        self.last_error = None;
+        self.logger.warn(&format!("Reads keep failing, switching to the offsets for Rekordbox version {}", self.offsets.rbversion));
+    }
+
+    /// Follow mute changes from OSC, forgetting what was sent on unmute so the current state is resent
+    fn apply_mute(&mut self) {
+        let muted = KEEPER_CONTROL.muted();
+        if muted == self.muted {
+            return;
+        }
+        self.muted = muted;
+        if muted {
+            self.logger.info("Output muted");
+            return;
+        }
+        self.logger.info("Output unmuted, sending the current state");
+        // Forget what was sent, so every value counts as changed on the next update
+        self.td_trackers = (0..4).map(|_| TrackingDataTracker::new()).collect();
+        self.master_td_tracker = TrackingDataTracker::new();
+        self.energy = ChangeTrackedValue::new(f32::NAN);
+        self.crossfader = ChangeTrackedValue::new(f32::NAN);
+        self.resend_pending = true;
+    }
+
+    /// Let modules report that rkbx_link is alive, also while Rekordbox isn't running
+    fn idle_heartbeat(&mut self) {
+        let Some(interval) = self.idle_heartbeat_interval else {
+            return;
+        };
+        if self.last_idle_heartbeat.elapsed() < interval {
+            return;
+        }
+        self.last_idle_heartbeat = Instant::now();
+        for module in &mut self.running_modules {
+            module.idle_heartbeat();
+        }
+    }
+
+    /// Tear down modules that report they keep failing, and recreate them with an increasing backoff
+    fn run_watchdog(&mut self) {
+        let now = Instant::now();
+        let mut changed = false;
+        let mut i = 0;
+        while i < self.running_modules.len() {
+            if !self.running_modules[i].failed() {
+                i += 1;
+                continue;
+            }
+            // Dropped before recreating, to release its socket or device
+            let spec = self.running_modules.remove(i).spec;
+            self.logger.warn(&format!(
+                "Module {} keeps failing, restarting it in {:.1}s",
+                spec.definition.pretty_name,
+                self.watchdog_backoff.as_secs_f32()
+            ));
+            self.restarting_modules.push(RestartingModule {
+                spec,
+                retry_at: now + self.watchdog_backoff,
+                backoff: self.watchdog_backoff,
+            });
+            changed = true;
+        }
+
+        let (due, waiting): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.restarting_modules).into_iter().partition(|m| m.retry_at <= now);
+        self.restarting_modules = waiting;
+        for mut restarting in due {
+            let name = &restarting.spec.definition.pretty_name;
+            match restarting.spec.create() {
+                Ok(mut module) => {
+                    self.logger.good(&format!("Restarted module {name}"));
+                    // Only run from slow updates, which are connected
+                    module.connection_changed(true);
+                    self.running_modules.push(module);
+                    changed = true;
                 }
-            } else {
-                match Rekordbox::new(offsets.clone(), config.get_or_default("keeper.decks", 2)) {
-                    Ok(rb) => {
-                        rekordbox = Some(rb);
-                        println!();
-                        logger.good("Connected to Rekordbox!");
-                        keeper.last_error = None;
-                    }
-                    Err(e) => {
-                        keeper.report_error(e);
-                        logger.info("...");
-                        thread::sleep(Duration::from_secs(3));
-                    }
+                Err(()) => {
+                    restarting.backoff = (restarting.backoff * 2).min(MAX_WATCHDOG_BACKOFF);
+                    self.logger.warn(&format!("Failed to restart module {name}, trying again in {:.1}s", restarting.backoff.as_secs_f32()));
+                    restarting.retry_at = now + restarting.backoff;
+                    self.restarting_modules.push(restarting);
                 }
             }
         }
+        if changed {
+            self.send_module_states();
+        }
+    }
+
+    fn send_module_states(&self) {
+        let running = self.running_modules.iter().map(|m| (m.spec.definition.pretty_name.clone(), true));
+        let restarting = self.restarting_modules.iter().map(|m| (m.spec.definition.pretty_name.clone(), false));
+        self.send_event(KeeperEvent::Modules(running.chain(restarting).collect()));
     }
 
-    
+    pub fn is_connected(&self) -> bool {
+        self.rekordbox.is_some()
+    }
+
+    /// Whether any tracked deck is playing. Only the master deck is tracked without keeper.keep_warm
+    fn any_deck_playing(&self) -> bool {
+        self.td_trackers.iter().any(|tracker| tracker.playing.value)
+    }
 
     fn update(
         &mut self,
-        rb: &Rekordbox,
+        rb: &dyn DeckSource,
         slow_update: bool,
-<<<<<<< master
         very_slow_update: bool,
-    ) -> Result<(), ReadError> {
-=======
     ) -> Result<(), MemoryReadError> {
-        // let masterdeck_index_changed = self.masterdeck_index.set(td.masterdeck_index as usize);
->>>>>>> master
-        let masterdeck_index_changed = self.masterdeck_index.set(rb.read_masterdeck_index()?);
-        if self.masterdeck_index.value >= rb.deckcount {
-            return Ok(()); // No master deck selected - rekordbox is not initialised
+        let mut masterdeck_index = self.tracked_masterdeck_index(rb.read_masterdeck_index()?);
+        if let Some(forced) = KEEPER_CONTROL.forced_master() {
+            if forced < rb.deckcount() {
+                masterdeck_index = forced;
+            } else {
+                self.logger.warn(&format!("Can't force master to deck {forced}, only {} decks are configured", rb.deckcount()));
+                KEEPER_CONTROL.force_master(None);
+            }
+        }
+        let masterdeck_index_changed = self.debounce_masterdeck_index(masterdeck_index);
+        let layout_decks = self.read_optional(OptionalValue::DeckLayout, rb.read_deck_layout()).flatten();
+        if self.masterdeck_index.value >= rb.deckcount() {
+            // No master deck selected - rekordbox is not initialised, or the master is outside the configured decks
+            self.report_master_out_of_range(layout_decks, rb.deckcount());
+            return Ok(());
         }
+        self.master_out_of_range_reported = false;
+        // Skip configured decks that are not shown in the current layout
+        let active_decks = match &self.deck_map {
+            Some(deck_map) => deck_map.iter().take_while(|&&deck| layout_decks.is_none_or(|layout| deck < layout)).count(),
+            None => layout_decks.map_or(self.decks, |layout| layout.min(self.decks)),
+        };
 
         if very_slow_update {
             self.very_slow_update_flag = true;
         }
+        let resend = std::mem::take(&mut self.resend_pending);
+        self.resend_tracks |= resend;
+
+        let crossfader = self.read_optional(OptionalValue::Crossfader, rb.read_crossfader()).flatten();
+        let channel_faders = self.read_optional(OptionalValue::ChannelFader, rb.read_channel_faders()).flatten();
+        let cue_monitors = self.read_optional(OptionalValue::CueMonitor, rb.read_cue_monitors()).flatten();
+        let perf_states = self.read_optional(OptionalValue::PerfState, rb.read_perf_states()).flatten();
+        let crossfader_changed = crossfader.is_some_and(|position| self.crossfader.set(position));
 
         for module in &mut self.running_modules {
+            module.limit_rate();
             module.pre_update();
+            if crossfader_changed {
+                module.crossfader_changed(self.crossfader.value);
+            }
         }
 
-        for (i, (tracker, td_tracker)) in (self.track_trackers[0..self.decks])
+        for (i, (tracker, td_tracker)) in (self.track_trackers[0..active_decks])
             .iter_mut()
-                .zip(self.td_trackers[0..self.decks].iter_mut())
+                .zip(self.td_trackers[0..active_decks].iter_mut())
                 .enumerate()
         {
             let is_master = i == self.masterdeck_index.value;
-            if is_master | self.keep_warm {
+            // The position of the new track against the old beatgrid would make the beat jump
+            let frozen = self.frozen_until[i].is_some_and(|until| Instant::now() < until);
+            if is_master || self.keep_warm && !self.master_only {
                 let res =
-                    tracker.update(rb, self.offset_samples, i);
+                    tracker.update(rb, &self.phraseparser, self.offset_samples, i);
                 let Ok(res) = res else {
                     continue;
                 };
 
-                let bpm_changed = td_tracker.bpm_changed.set(res.timing_data_raw.current_bpm) || very_slow_update && self.hearbeat_config.bpm;
-                let original_bpm_changed = td_tracker.original_bpm_changed.set(res.original_bpm) || very_slow_update && self.hearbeat_config.original_bpm;
-                let beat_changed = td_tracker.beat_changed.set(res.beat) || very_slow_update && self.hearbeat_config.beat;
-                let pos_changed = td_tracker.pos_changed.set(res.timing_data_raw.sample_position) || very_slow_update && self.hearbeat_config.pos;
+                let bpm_changed = td_tracker.bpm_changed.set_or_heartbeat(res.timing_data_raw.current_bpm, very_slow_update && self.hearbeat_config.bpm);
+                let original_bpm_changed = td_tracker.original_bpm_changed.set_or_heartbeat(res.original_bpm, very_slow_update && self.hearbeat_config.original_bpm);
+                let beat_changed = td_tracker.beat_changed.set_or_heartbeat(res.beat, very_slow_update && self.hearbeat_config.beat);
+                // The position is only updated once per audio buffer, so it may stand still for a few updates while playing
+                if td_tracker.pos_changed.value != res.timing_data_raw.sample_position {
+                    td_tracker.last_moved = Some(Instant::now());
+                }
+                let playing = td_tracker.last_moved.is_some_and(|t| t.elapsed() < PLAYING_HOLD_TIME);
+                let playing_changed = td_tracker.playing.set(playing);
+                let pos_changed = td_tracker.pos_changed.set_or_heartbeat(res.timing_data_raw.sample_position, very_slow_update && self.hearbeat_config.pos);
                 // These clones could be optimised out
-                let phrase_changed = td_tracker.phrase.set(res.phrase.clone()) || very_slow_update && self.hearbeat_config.phrase;
-                let next_phrase_changed = td_tracker.next_phrase.set(res.next_phrase.clone()) || very_slow_update && self.hearbeat_config.phrase;
-                let next_phrase_in_changed = td_tracker.next_phrase_in.set(res.next_phrase_in) || very_slow_update && self.hearbeat_config.phrase;
-
-                for module in &mut self.running_modules {
-                    if beat_changed {
-                        module.beat_update(res.beat, i);
-                    }
-                    if pos_changed {
-                        module.time_update(res.timing_data_raw.sample_position as f32 / 44100., i);
-                    }
-                    if bpm_changed {
-                        module.bpm_changed(res.timing_data_raw.current_bpm, i);
-                    }
-                    if original_bpm_changed {
-                        module.original_bpm_changed(res.original_bpm, i);
-                    }
-                    if phrase_changed {
-                        module.phrase_changed(&res.phrase, i);
-                    }
-                    if next_phrase_changed {
-                        module.next_phrase_changed(&res.next_phrase, i);
+                let phrase_changed = td_tracker.phrase.set_or_heartbeat(res.phrase.clone(), very_slow_update && self.hearbeat_config.phrase);
+                let next_phrase_changed = td_tracker.next_phrase.set_or_heartbeat(res.next_phrase.clone(), very_slow_update && self.hearbeat_config.phrase);
+                let next_phrase_in_changed = td_tracker.next_phrase_in.set_or_heartbeat(res.next_phrase_in, very_slow_update && self.hearbeat_config.phrase);
+                let beat_valid_changed = td_tracker.beat_valid.set_or_heartbeat(res.beat_valid, very_slow_update && self.hearbeat_config.beat);
+                let bar_length_changed = td_tracker.bar_length.set_or_heartbeat(res.beats_per_bar, very_slow_update && self.hearbeat_config.beat);
+                let bar_countdown_changed = td_tracker.bar_countdown.set_or_heartbeat(res.bar_countdown, very_slow_update && self.hearbeat_config.beat);
+                let sixteenth = step_in_bar(res.beat, self.step_division);
+                let sixteenth_changed = td_tracker.sixteenth.set_or_heartbeat(sixteenth, very_slow_update && self.hearbeat_config.beat);
+                let mood_changed = td_tracker.mood.set_or_heartbeat(res.mood, very_slow_update && self.hearbeat_config.phrase);
+
+                // Before the beat, so modules can tell a jump from a crossed beat
+                if res.seeked {
+                    for module in &mut self.running_modules {
+                        module.seek_detected(i);
                     }
-                    if next_phrase_in_changed {
-                        module.next_phrase_in(res.next_phrase_in, i);
+                }
+
+                // Per-deck outputs are disabled in master only mode
+                if !self.master_only {
+                    for module in &mut self.running_modules {
+                        if beat_changed && !frozen && module.fast_due {
+                            module.beat_update(res.beat, i);
+                        }
+                        if pos_changed && !frozen && module.fast_due {
+                            module.time_update(res.timing_data_raw.sample_position as f32 / 44100., i);
+                        }
+                        if bpm_changed {
+                            module.bpm_changed(res.timing_data_raw.current_bpm, i);
+                        }
+                        if original_bpm_changed {
+                            module.original_bpm_changed(res.original_bpm, i);
+                        }
+                        if phrase_changed {
+                            module.phrase_changed(&res.phrase, i);
+                        }
+                        if next_phrase_changed {
+                            module.next_phrase_changed(&res.next_phrase, i);
+                        }
+                        if next_phrase_in_changed {
+                            module.next_phrase_in(res.next_phrase_in, i);
+                        }
+                        if playing_changed {
+                            module.play_state_changed(playing, i);
+                        }
+                        if beat_valid_changed {
+                            module.beat_valid_changed(res.beat_valid, i);
+                        }
+                        if bar_length_changed {
+                            module.bar_length_changed(res.beats_per_bar, i);
+                        }
+                        if bar_countdown_changed {
+                            module.bar_countdown_changed(res.bar_countdown, i);
+                        }
+                        if sixteenth_changed {
+                            module.sixteenth_changed(sixteenth, i);
+                        }
+                        if mood_changed {
+                            module.mood_changed(res.mood, i);
+                        }
                     }
                 }
 
                 if is_master {
+                    let idle = self.suppress_when_idle && !playing;
+                    let idle_changed = self.master_idle.set(idle);
                     let bpm_changed = self
                         .master_td_tracker
                         .bpm_changed
-                        .set(res.timing_data_raw.current_bpm) || very_slow_update && self.hearbeat_config.bpm;
+                        .set_or_heartbeat(res.timing_data_raw.current_bpm, very_slow_update && self.hearbeat_config.bpm);
                     let original_bpm_changed = self
                         .master_td_tracker
                         .original_bpm_changed
-                        .set(res.original_bpm) || very_slow_update && self.hearbeat_config.original_bpm;
-                    let beat_changed = self.master_td_tracker.beat_changed.set(res.beat) || very_slow_update && self.hearbeat_config.beat;
+                        .set_or_heartbeat(res.original_bpm, very_slow_update && self.hearbeat_config.original_bpm);
+                    let beat_changed = self.master_td_tracker.beat_changed.set_or_heartbeat(res.beat, very_slow_update && self.hearbeat_config.beat);
                     let pos_changed = self
                         .master_td_tracker
                         .pos_changed
-                        .set(res.timing_data_raw.sample_position) || very_slow_update && self.hearbeat_config.pos;
+                        .set_or_heartbeat(res.timing_data_raw.sample_position, very_slow_update && self.hearbeat_config.pos);
                     let phrase_changed = self
                         .master_td_tracker
                         .phrase
-                        .set(res.phrase) || very_slow_update && self.hearbeat_config.phrase;
+                        .set_or_heartbeat(res.phrase, very_slow_update && self.hearbeat_config.phrase);
                     let next_phrase_changed = self
                         .master_td_tracker
                         .next_phrase
-                        .set(res.next_phrase) || very_slow_update && self.hearbeat_config.phrase;
+                        .set_or_heartbeat(res.next_phrase, very_slow_update && self.hearbeat_config.phrase);
                     let next_phrase_in_changed = self
                         .master_td_tracker
                         .next_phrase_in
-                        .set(res.next_phrase_in) || very_slow_update && self.hearbeat_config.phrase;
+                        .set_or_heartbeat(res.next_phrase_in, very_slow_update && self.hearbeat_config.phrase);
+                    let beat_valid_changed = self.master_td_tracker.beat_valid.set_or_heartbeat(res.beat_valid, very_slow_update && self.hearbeat_config.beat);
+                    let bar_length_changed = self.master_td_tracker.bar_length.set_or_heartbeat(res.beats_per_bar, very_slow_update && self.hearbeat_config.beat);
+                    let bar_countdown_changed = self.master_td_tracker.bar_countdown.set_or_heartbeat(res.bar_countdown, very_slow_update && self.hearbeat_config.beat);
+                    let sixteenth = step_in_bar(res.beat, self.step_division);
+                    let sixteenth_changed = self.master_td_tracker.sixteenth.set_or_heartbeat(sixteenth, very_slow_update && self.hearbeat_config.beat);
+                    let mood_changed = self.master_td_tracker.mood.set_or_heartbeat(res.mood, very_slow_update && self.hearbeat_config.phrase);
+                    if phrase_changed {
+                        self.pending_phrase = Some(self.master_td_tracker.phrase.value.clone());
+                    }
+                    let quantized_phrase = if self.quantize.crossed(self.last_master_beat, res.beat) {
+                        self.pending_phrase.take()
+                    } else {
+                        None
+                    };
+                    self.last_master_beat = res.beat;
+                    let energy = self.energy_config.energy(&self.master_td_tracker.phrase.value, res.timing_data_raw.current_bpm);
+                    let energy_changed = self.energy.set_or_heartbeat(energy, very_slow_update && self.hearbeat_config.phrase);
+                    let end_warning = self.end_warning.update(res.remaining);
+                    // The beat is calculated for the position shifted by the delay compensation
+                    let offset = Duration::from_secs_f64(self.offset_samples.unsigned_abs() as f64 / 44100.);
+                    let beat_timestamp = if self.offset_samples >= 0 {
+                        res.timing_data_raw.read_at + offset
+                    } else {
+                        res.timing_data_raw.read_at - offset
+                    };
 
 
                     for module in &mut self.running_modules {
-                        if beat_changed {
+                        if idle_changed {
+                            module.idle_changed(idle);
+                        }
+                        // Before the beat, which is within the new bar length
+                        if bar_length_changed {
+                            module.bar_length_changed_master(res.beats_per_bar);
+                        }
+                        if beat_changed && !idle && !frozen && module.fast_due {
+                            module.beat_timestamp_master(beat_timestamp);
                             module.beat_update_master(res.beat);
+                            module.absolute_beat_update_master(res.absolute_beat);
+                        }
+                        // Not rate limited, as it only changes once per beat
+                        if bar_countdown_changed && !idle && !frozen {
+                            module.bar_countdown_changed_master(res.bar_countdown);
                         }
-                        if pos_changed {
+                        if sixteenth_changed && !idle && !frozen {
+                            module.sixteenth_changed_master(sixteenth);
+                        }
+                        if pos_changed && !idle && !frozen && module.fast_due {
                             module.time_update_master(
                                 res.timing_data_raw.sample_position as f32 / 44100.,
                             );
@@ -445,8 +1741,8 @@ impl BeatKeeper {
                         if original_bpm_changed {
                             module.original_bpm_changed_master(res.original_bpm);
                         }
-                        if phrase_changed {
-                            module.phrase_changed_master(&self.master_td_tracker.phrase.value);
+                        if let Some(phrase) = &quantized_phrase {
+                            module.phrase_changed_master(phrase);
                         }
                         if next_phrase_changed {
                             module.next_phrase_changed_master(&self.master_td_tracker.next_phrase.value);
@@ -454,35 +1750,143 @@ impl BeatKeeper {
                         if next_phrase_in_changed {
                             module.next_phrase_in_master(res.next_phrase_in);
                         }
+                        if beat_valid_changed {
+                            module.beat_valid_changed_master(res.beat_valid);
+                        }
+                        if mood_changed {
+                            module.mood_changed_master(res.mood);
+                        }
+                        if energy_changed {
+                            module.energy_changed(energy);
+                        }
+                        if let Some(ending) = end_warning {
+                            module.end_warning(ending);
+                        }
                     }
                 }
             }
         }
 
+        // Tempo and phase difference to the master deck, for beatmatching
+        let master_bpm = self.master_td_tracker.bpm_changed.value;
+        let master_beat = self.master_td_tracker.beat_changed.value;
+        let offset_decks = if self.master_only { 0 } else { active_decks };
+        for (i, td_tracker) in self.td_trackers[0..offset_decks].iter_mut().enumerate() {
+            if i != self.masterdeck_index.value && !self.keep_warm {
+                continue;
+            }
+            let bpm_offset = td_tracker.bpm_changed.value - master_bpm;
+            if td_tracker.bpm_offset.set_or_heartbeat(bpm_offset, very_slow_update && self.hearbeat_config.bpm) {
+                for module in &mut self.running_modules {
+                    module.bpm_offset_changed(bpm_offset, i);
+                }
+            }
+
+            // Wrapped to [-0.5, 0.5] beats, positive when the deck is ahead of the master
+            let mut phase_offset = (td_tracker.beat_changed.value - master_beat).rem_euclid(1.0);
+            if phase_offset > 0.5 {
+                phase_offset -= 1.0;
+            }
+            if td_tracker.phase_offset.set_or_heartbeat(phase_offset, very_slow_update && self.hearbeat_config.beat) {
+                for module in self.running_modules.iter_mut().filter(|m| m.fast_due) {
+                    module.phase_offset_changed(phase_offset, i);
+                }
+            }
+        }
+
+        // Playing and audible through the channel fader and crossfader, eg. for tally lights
+        for (i, td_tracker) in self.td_trackers[0..offset_decks].iter_mut().enumerate() {
+            let fader = channel_faders.as_ref().and_then(|faders| faders.get(i).copied());
+            let on_air = td_tracker.playing.value && self.on_air_config.audible(i, fader, crossfader);
+            if td_tracker.on_air.set(on_air) {
+                for module in &mut self.running_modules {
+                    module.on_air_changed(on_air, i);
+                }
+            }
+            // Both only reported where the offsets provide them
+            if let Some(&perf_state) = perf_states.as_ref().and_then(|states| states.get(i)) {
+                if td_tracker.perf_state.set(perf_state) {
+                    for module in &mut self.running_modules {
+                        module.performance_state_changed(perf_state, i);
+                    }
+                }
+            }
+            let Some(&cue) = cue_monitors.as_ref().and_then(|cues| cues.get(i)) else {
+                continue;
+            };
+            if td_tracker.cue_monitor.set(cue) {
+                for module in &mut self.running_modules {
+                    module.cue_monitor_changed(cue, i);
+                }
+            }
+        }
 
         let mut masterdeck_track_changed = false;
+        let mut masterdeck_track_replaced = false;
 
         if slow_update {
             // Send update for track info changes (title/artist/album)
-            for (i, track) in rb.get_track_infos()?.into_iter().enumerate() {
-                if self.track_infos[i].set(track) || self.very_slow_update_flag && self.hearbeat_config.track_info {
-                    for module in &mut self.running_modules {
-                        module.track_changed(&self.track_infos[i].value, i);
+            let track_infos = self.read_optional(OptionalValue::TrackInfo, rb.get_track_infos());
+            for (i, mut track) in track_infos.unwrap_or_default().into_iter().enumerate() {
+                fill_unknown(&mut track, &self.unknown_title, &self.unknown_artist);
+                let track_changed = self.track_infos[i].set(track);
+                if track_changed {
+                    // Until the new track's analysis files have been parsed
+                    self.set_analysis_status(i, AnalysisStatus::Loading);
+                    self.track_trackers[i].fallback = None;
+                }
+                if track_changed || self.very_slow_update_flag && self.hearbeat_config.track_info || self.resend_tracks {
+                    if !self.master_only {
+                        for module in &mut self.running_modules {
+                            module.track_changed(&self.track_infos[i].value, i);
+                        }
                     }
                     masterdeck_track_changed |= self.masterdeck_index.value == i;
+                    masterdeck_track_replaced |= track_changed && self.masterdeck_index.value == i;
+                }
+            }
+
+            if let Some(preview_track) = self.read_optional(OptionalValue::PreviewTrack, rb.read_preview_track()) {
+                if self.preview_track.set(preview_track) {
+                    if let Some(track) = &self.preview_track.value {
+                        for module in &mut self.running_modules {
+                            module.preview_track_changed(track);
+                        }
+                    }
+                }
+            }
+
+            let artwork_paths = self.read_optional(OptionalValue::ArtworkPath, rb.get_artwork_paths());
+            for (i, path) in artwork_paths.unwrap_or_default().into_iter().enumerate() {
+                if self.artwork_paths[i].set_or_heartbeat(path, self.very_slow_update_flag && self.hearbeat_config.track_info || self.resend_tracks) {
+                    let is_master = self.masterdeck_index.value == i;
+                    for module in &mut self.running_modules {
+                        if !self.master_only {
+                            module.artwork_changed(&self.artwork_paths[i].value, i);
+                        }
+                        if is_master {
+                            module.artwork_changed_master(&self.artwork_paths[i].value);
+                        }
+                    }
                 }
             }
 
 
-            // Check if the ANLZ file path has changed
-            let mut anlz_file_updates = [false; 4];
+            // Check if the ANLZ files have changed on disk
+            let mut dat_file_updates = [false; 4];
+            let mut ext_file_updates = [false; 4];
+            let mut ext2_file_updates = [false; 4];
             while let Ok(u) = self.watcher_rx.try_recv(){
                 match u {
                     Ok(event) => {
                         if let Some(path) = event.paths.first() {
                             let path = path.to_string_lossy().replace("\\", "/");
-                            if let Some(i) = self.anlz_paths.iter().position(|x| x.value == path || x.value.replace(".DAT", ".EXT") == path) {
-                                anlz_file_updates[i] = true;
+                            if let Some(i) = self.anlz_paths.iter().position(|x| x.value == path) {
+                                dat_file_updates[i] = true;
+                            } else if let Some(i) = self.anlz_paths.iter().position(|x| x.value.replace(".DAT", ".EXT") == path) {
+                                ext_file_updates[i] = true;
+                            } else if let Some(i) = self.anlz_paths.iter().position(|x| x.value.replace(".DAT", ".2EX") == path) {
+                                ext2_file_updates[i] = true;
                             }
                         }
                     }
@@ -492,123 +1896,115 @@ impl BeatKeeper {
                 }
             }
 
-            for (i, path) in rb.get_anlz_paths()?.into_iter().enumerate() {
+            let anlz_paths = self.read_optional(OptionalValue::AnlzPath, rb.get_anlz_paths());
+            for (i, path) in anlz_paths.unwrap_or_default().into_iter().enumerate() {
                 // Send ANLZ path update if path has changed or heartbeat requests it
-                if self.anlz_paths[i].value != path || self.very_slow_update_flag && self.hearbeat_config.anlz_path {
+                if (self.anlz_paths[i].value != path || self.very_slow_update_flag && self.hearbeat_config.anlz_path) && !self.master_only {
                     for module in &mut self.running_modules {
                         module.anlz_path_changed(&path, i);
                     }
                 }
 
-                // If the needed file itself has ACTUALLY changed, reload the ANLZ file
-                if self.anlz_paths[i].value != path || anlz_file_updates[i] {
-                    if self.anlz_paths[i].value != path {
-                        self.logger.debug(&format!("Deck {i} ANLZ file path changed: {path}"));
+                let path_changed = self.anlz_paths[i].value != path;
+                if path_changed {
+                    self.logger.debug(&format!("Deck {i} ANLZ file path changed: {path}"));
 
-<<<<<<< master
-                        // Stop watching the old DAT path before switching
+                    // Only unwatch if there was a previous path (not empty)
+                    if !self.anlz_paths[i].value.is_empty() {
                         self.watcher.unwatch(std::path::Path::new(&self.anlz_paths[i].value)).unwrap_or_else(|e| {
-                            self.logger.err(&format!("Deck {i}: Failed to unwatch path {}: {e}", &self.anlz_paths[i].value));
+                            self.logger.err(&format!("Deck {i}: Failed to unwatch path {}: {}", &self.anlz_paths[i].value, e));
                         });
-                        // Stop watching the old EXT path
                         self.watcher.unwatch(std::path::Path::new(&self.anlz_paths[i].value.replace(".DAT", ".EXT"))).unwrap_or_else(|e| {
-                            self.logger.err(&format!("Deck {i}: Failed to unwatch path {}: {e}", &self.anlz_paths[i].value.replace(".DAT", ".EXT")));
-                        });
-                        self.anlz_paths[i].set(path);
-                        // Start watching the new DAT path
-                        self.watcher.watch(std::path::Path::new(&self.anlz_paths[i].value), notify::RecursiveMode::NonRecursive).unwrap_or_else(|e| {
-                            self.logger.err(&format!("Deck {i}: Failed to watch path {}: {e}", &self.anlz_paths[i].value));
+                            self.logger.err(&format!("Deck {i}: Failed to unwatch path {}: {}", &self.anlz_paths[i].value.replace(".DAT", ".EXT"), e));
                         });
-                        // Start watching the new EXT path
-                        self.watcher.watch(std::path::Path::new(&self.anlz_paths[i].value.replace(".DAT", ".EXT")), notify::RecursiveMode::NonRecursive).unwrap_or_else(|e| {
-                            self.logger.err(&format!("Deck {i}: Failed to watch path {}: {e}", &self.anlz_paths[i].value.replace(".DAT", ".EXT")));
-                        });
-=======
-                        // Only unwatch if there was a previous path (not empty)
-                        if !self.anlz_paths[i].value.is_empty() {
-                            self.watcher.unwatch(std::path::Path::new(&self.anlz_paths[i].value)).unwrap_or_else(|e| {
-                                self.logger.err(&format!("Deck {i}: Failed to unwatch path {}: {}", &self.anlz_paths[i].value, e));
-                            });
-                            self.watcher.unwatch(std::path::Path::new(&self.anlz_paths[i].value.replace(".DAT", ".EXT"))).unwrap_or_else(|e| {
-                                self.logger.err(&format!("Deck {i}: Failed to unwatch path {}: {}", &self.anlz_paths[i].value.replace(".DAT", ".EXT"), e));
-                            });
-                        }
-
-                        self.anlz_paths[i].set(path);
-
-                        // Only watch if the new path is not empty
-                        if !self.anlz_paths[i].value.is_empty() {
-                            if let Err(e) = self.watcher.watch(std::path::Path::new(&self.anlz_paths[i].value), notify::RecursiveMode::NonRecursive) {
-                                self.logger.err(&format!("Deck {i}: Failed to watch path {}: {}", &self.anlz_paths[i].value, e));
-                            }
-                            if let Err(e) = self.watcher.watch(std::path::Path::new(&self.anlz_paths[i].value.replace(".DAT", ".EXT")), notify::RecursiveMode::NonRecursive) {
-                                self.logger.err(&format!("Deck {i}: Failed to watch path {}: {}", &self.anlz_paths[i].value.replace(".DAT", ".EXT"), e));
-                            }
-                        }
->>>>>>> master
+                        // Only watched if it existed
+                        let _ = self.watcher.unwatch(std::path::Path::new(&self.anlz_paths[i].value.replace(".DAT", ".2EX")));
                     }
 
-                    // Reparse ANLZ when the file changes or the path switches
-                    let Ok(bytes) = std::fs::read(&self.anlz_paths[i].value) else {
-                        self.logger.err(&format!("Failed to read anlz file for deck {i}: {}", &self.anlz_paths[i].value));
-                        self.logger.err("If you are loading a new streaming track for the first time, eject and load it again.");
-                        continue;
-                    };
-                    let mut reader = Cursor::new(bytes);
-                    let anlz = match rekordcrate::anlz::ANLZ::read(&mut reader){
-                        Ok(a) => a,
-                        Err(e) => {
-                            self.logger.err(&format!("Failed to parse DAT file for song {}, path {}: {e}", &self.track_infos[i].value.title, &self.anlz_paths[i].value));
-                            continue;
+                    self.anlz_paths[i].set(path);
+
+                    // Only watch if the new path is not empty
+                    if !self.anlz_paths[i].value.is_empty() {
+                        if let Err(e) = self.watcher.watch(std::path::Path::new(&self.anlz_paths[i].value), notify::RecursiveMode::NonRecursive) {
+                            self.logger.err(&format!("Deck {i}: Failed to watch path {}: {}", &self.anlz_paths[i].value, e));
                         }
-                    };
-                    for section in anlz.sections {
-                        #[allow(clippy::single_match)]
-                        match section.content {
-                            anlz::Content::BeatGrid(grid) => {
-                                self.track_trackers[i].beatgrid = Some(grid);
+                        if let Err(e) = self.watcher.watch(std::path::Path::new(&self.anlz_paths[i].value.replace(".DAT", ".EXT")), notify::RecursiveMode::NonRecursive) {
+                            self.logger.err(&format!("Deck {i}: Failed to watch path {}: {}", &self.anlz_paths[i].value.replace(".DAT", ".EXT"), e));
+                        }
+                        // Older libraries don't have .2EX files
+                        let ext2_path = self.anlz_paths[i].value.replace(".DAT", ".2EX");
+                        if std::path::Path::new(&ext2_path).exists() {
+                            if let Err(e) = self.watcher.watch(std::path::Path::new(&ext2_path), notify::RecursiveMode::NonRecursive) {
+                                self.logger.err(&format!("Deck {i}: Failed to watch path {ext2_path}: {e}"));
                             }
-                            _ => (),
                         }
                     }
+                }
 
-                    let bytes = match std::fs::read(self.anlz_paths[i].value.replace(".DAT", ".EXT")) {
-                        Ok(b) => b,
-                        Err(e) => {
-                            self.logger.err(&format!("Failed to read EXT file for song {}, path {}: {e}", &self.track_infos[i].value.title, &self.anlz_paths[i].value));
-                            continue;
-                        }
-                    };
-
-                    let mut reader = Cursor::new(bytes);
-                    let anlz = match rekordcrate::anlz::ANLZ::read(&mut reader) {
-                        Ok(a) => a,
-                        Err(e) => {
-                            self.logger.err(&format!("Failed to parse EXT file for song {}, path {}: {e}", &self.track_infos[i].value.title, &self.anlz_paths[i].value.replace(".DAT", ".EXT")));
-                            continue;
-                        }
-                    };
-                    for section in anlz.sections {
-                        #[allow(clippy::single_match)]
-                        match section.content {
-                            anlz::Content::SongStructure(phrases) => {
-                                self.track_trackers[i].songstructure = Some(phrases.data);
-                            }
-                            _ => (),
+                // Only reparse the files that ACTUALLY changed. The DAT holds the beatgrid, the EXT the phrases
+                let mut structure_loaded = false;
+                if path_changed || dat_file_updates[i] {
+                    self.set_analysis_status(i, AnalysisStatus::Loading);
+                    let mut status = if self.reload_dat(i) { AnalysisStatus::Ready } else { AnalysisStatus::Failed };
+                    if status == AnalysisStatus::Ready {
+                        self.send_grid_origin(i);
+                    }
+                    if path_changed || ext_file_updates[i] {
+                        let ext_loaded = self.reload_ext(i);
+                        structure_loaded |= ext_loaded;
+                        // Ready only once the phrases are in as well
+                        if !ext_loaded {
+                            status = AnalysisStatus::Failed;
                         }
                     }
+                    if path_changed || ext2_file_updates[i] {
+                        structure_loaded |= self.reload_2ex(i);
+                    }
+                    self.set_analysis_status(i, status);
+                } else {
+                    if ext_file_updates[i] {
+                        structure_loaded |= self.reload_ext(i);
+                    }
+                    if ext2_file_updates[i] {
+                        structure_loaded |= self.reload_2ex(i);
+                    }
+                }
+                if structure_loaded {
+                    self.send_song_structure(i);
                 }
             }
 
             for module in &mut self.running_modules {
                 module.slow_update();
+                if self.very_slow_update_flag {
+                    module.very_slow_update();
+                }
+            }
+            if self.watchdog {
+                self.run_watchdog();
+            }
+            if self.stats_line && self.very_slow_update_flag {
+                let modules: Vec<(String, String)> = self
+                    .running_modules
+                    .iter()
+                    .filter_map(|m| Some((m.spec.definition.pretty_name.clone(), m.stats()?)))
+                    .collect();
+                let master = &self.master_td_tracker;
+                self.logger.info(&stats_line(
+                    self.masterdeck_index.value,
+                    master.bpm_changed.value,
+                    master.beat_changed.value,
+                    &master.phrase.value,
+                    &modules,
+                ));
             }
 
             self.very_slow_update_flag = false;
+            self.resend_tracks = false;
         }
 
         // Send update if masterdeck index changed or heartbeat
-        if masterdeck_index_changed || very_slow_update && self.hearbeat_config.masterdeck_index {
+        if masterdeck_index_changed || very_slow_update && self.hearbeat_config.masterdeck_index || resend {
             for module in &mut self.running_modules {
                 module.masterdeck_index_changed(self.masterdeck_index.value);
             }
@@ -619,14 +2015,233 @@ impl BeatKeeper {
             let track = &self.track_infos[self.masterdeck_index.value].value;
             // self.logger
             //     .debug(&format!("Master track changed: {track:?}"));
+            let artwork = &self.artwork_paths[self.masterdeck_index.value].value;
+            let reason = if masterdeck_index_changed {
+                MasterChangeReason::DeckSwitched
+            } else if masterdeck_track_replaced {
+                MasterChangeReason::NewTrack
+            } else {
+                MasterChangeReason::Refresh
+            };
             for module in &mut self.running_modules {
+                module.track_change_reason_master(reason);
                 module.track_changed_master(track);
+                if masterdeck_index_changed {
+                    module.artwork_changed_master(artwork);
+                    module.master_changed(self.masterdeck_index.value, track);
+                }
+            }
+        }
+
+        if slow_update && self.events.is_some() {
+            let index = self.masterdeck_index.value;
+            let status = (index, self.master_td_tracker.bpm_changed.value, self.track_infos[index].value.clone());
+            if self.master_status.set(status) {
+                let (deck, bpm, track) = self.master_status.value.clone();
+                self.send_event(KeeperEvent::Master { deck, bpm, track });
             }
         }
 
         Ok(())
     }
 
+    /// Send the time of the first beat of the deck's beatgrid
+    fn send_grid_origin(&mut self, i: usize) {
+        let Some(first) = self.track_trackers[i].beatgrid.as_ref().and_then(|grid| grid.beats.first()) else {
+            return;
+        };
+        let origin = first.time as f32 / 1000.;
+        for module in &mut self.running_modules {
+            module.grid_origin_changed(origin, i);
+        }
+    }
+
+    /// Send the beat and name of every phrase in the deck's track
+    fn send_song_structure(&mut self, i: usize) {
+        let Some(songstructure) = &self.track_trackers[i].songstructure else {
+            return;
+        };
+        let phrases: Vec<(u16, String)> = songstructure
+            .phrases
+            .iter()
+            .map(|phrase| (phrase.beat, self.phraseparser.get_phrase_name(&songstructure.mood, phrase)))
+            .collect();
+        for module in &mut self.running_modules {
+            module.song_structure_loaded(&phrases, i);
+        }
+    }
+
+    /// Reparse the beatgrid from the deck's DAT file. Returns false on failure
+    fn reload_dat(&mut self, i: usize) -> bool {
+        // The position of the new track isn't a seek
+        self.track_trackers[i].last_position = None;
+        let Ok(bytes) = std::fs::read(&self.anlz_paths[i].value) else {
+            self.logger.err(&format!("Failed to read anlz file for deck {i}: {}", &self.anlz_paths[i].value));
+            self.logger.err("If you are loading a new streaming track for the first time, eject and load it again.");
+            return false;
+        };
+        let mut reader = Cursor::new(bytes);
+        let anlz = match rekordcrate::anlz::ANLZ::read(&mut reader){
+            Ok(a) => a,
+            Err(e) => {
+                self.logger.err(&format!("Failed to parse DAT file for song {}, path {}: {e}", &self.track_infos[i].value.title, &self.anlz_paths[i].value));
+                return false;
+            }
+        };
+        for section in anlz.sections {
+            match section.content {
+                anlz::Content::BeatGrid(grid) if grid.beats.is_empty() => {
+                    self.logger.warn(&format!("Beatgrid of {} has no beats, ignoring it", &self.track_infos[i].value.title));
+                    self.track_trackers[i].beatgrid = None;
+                }
+                anlz::Content::BeatGrid(grid) => {
+                    self.track_trackers[i].beats_per_bar = detect_beats_per_bar(grid.beats.iter().map(|b| b.beat_number));
+                    self.track_trackers[i].beatgrid = Some(grid);
+                }
+                _ => (),
+            }
+        }
+        true
+    }
+
+    /// Reparse the song structure from the deck's EXT file. Returns false on failure
+    fn reload_ext(&mut self, i: usize) -> bool {
+        let bytes = match std::fs::read(self.anlz_paths[i].value.replace(".DAT", ".EXT")) {
+            Ok(b) => b,
+            Err(e) => {
+                self.logger.err(&format!("Failed to read EXT file for song {}, path {}: {e}", &self.track_infos[i].value.title, &self.anlz_paths[i].value));
+                return false;
+            }
+        };
+
+        let mut reader = Cursor::new(bytes);
+        let anlz = match rekordcrate::anlz::ANLZ::read(&mut reader) {
+            Ok(a) => a,
+            Err(e) => {
+                self.logger.err(&format!("Failed to parse EXT file for song {}, path {}: {e}", &self.track_infos[i].value.title, &self.anlz_paths[i].value.replace(".DAT", ".EXT")));
+                return false;
+            }
+        };
+        for section in anlz.sections {
+            #[allow(clippy::single_match)]
+            match section.content {
+                anlz::Content::SongStructure(phrases) => {
+                    self.track_trackers[i].songstructure = Some(phrases.data);
+                }
+                _ => (),
+            }
+        }
+        true
+    }
+
+    /// Parse the deck's .2EX file, written by newer Rekordbox versions. Returns false if it is
+    /// missing or fails to parse
+    fn reload_2ex(&mut self, i: usize) -> bool {
+        let path = self.anlz_paths[i].value.replace(".DAT", ".2EX");
+        let bytes = match std::fs::read(&path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.logger.debug(&format!("No 2EX file for deck {i}"));
+                return false;
+            }
+            Err(e) => {
+                self.logger.err(&format!("Failed to read 2EX file for song {}, path {path}: {e}", &self.track_infos[i].value.title));
+                return false;
+            }
+        };
+
+        let mut reader = Cursor::new(bytes);
+        let anlz = match rekordcrate::anlz::ANLZ::read(&mut reader) {
+            Ok(a) => a,
+            Err(e) => {
+                self.logger.err(&format!("Failed to parse 2EX file for song {}, path {path}: {e}", &self.track_infos[i].value.title));
+                return false;
+            }
+        };
+        self.logger.debug(&format!("Deck {i}: 2EX file has {} sections", anlz.sections.len()));
+        for section in anlz.sections {
+            #[allow(clippy::single_match)]
+            match section.content {
+                // Normally in the EXT file, prefer this newer copy if present
+                anlz::Content::SongStructure(phrases) => {
+                    self.track_trackers[i].songstructure = Some(phrases.data);
+                }
+                _ => (),
+            }
+        }
+        true
+    }
+
+    fn set_analysis_status(&mut self, i: usize, status: AnalysisStatus) {
+        // A new track starts the freeze, and its analysis ends it
+        self.frozen_until[i] = match status {
+            AnalysisStatus::Loading if !self.load_freeze.is_zero() => Some(Instant::now() + self.load_freeze),
+            _ => None,
+        };
+        if self.analysis_status[i].set(status) {
+            for module in &mut self.running_modules {
+                module.analysis_status_changed(status, i);
+            }
+        }
+    }
+
+    /// Index of Rekordbox's master deck among the tracked decks. With keeper.deck_map, a master
+    /// deck that isn't tracked is reported as out of range, past the last tracked deck
+    fn tracked_masterdeck_index(&self, index: usize) -> usize {
+        match &self.deck_map {
+            Some(deck_map) => deck_map.iter().position(|&deck| deck == index).unwrap_or(deck_map.len() + index),
+            None => index,
+        }
+    }
+
+    /// Only accept a new master deck once it has been stable for master_switch_debounce
+    fn debounce_masterdeck_index(&mut self, index: usize) -> bool {
+        if self.master_switch_debounce.is_zero() {
+            return self.masterdeck_index.set(index);
+        }
+        if index == self.masterdeck_index.value {
+            self.pending_masterdeck_index = None;
+            return false;
+        }
+        match self.pending_masterdeck_index {
+            Some((pending, since)) if pending == index => {
+                if since.elapsed() < self.master_switch_debounce {
+                    return false;
+                }
+                self.pending_masterdeck_index = None;
+                self.masterdeck_index.set(index)
+            }
+            _ => {
+                self.pending_masterdeck_index = Some((index, Instant::now()));
+                false
+            }
+        }
+    }
+
+    fn report_master_out_of_range(&mut self, layout_decks: Option<usize>, deckcount: usize) {
+        if self.master_out_of_range_reported {
+            return;
+        }
+        let index = self.masterdeck_index.value;
+        if self.deck_map.is_some() {
+            self.logger.debug(&format!("Master deck {} is not in keeper.deck_map", index - deckcount + 1));
+            self.master_out_of_range_reported = true;
+            return;
+        }
+        match layout_decks {
+            Some(layout) if index < layout => {
+                self.logger.warn(&format!("Master deck is deck {}, but only {deckcount} decks are configured", index + 1));
+                self.logger.warn(&format!("Rekordbox is showing {layout} decks, set keeper.decks to {layout} in the config"));
+            }
+            Some(_) => return, // Outside the layout too - rekordbox is not initialised
+            None => {
+                self.logger.debug(&format!("Master deck index {index} is outside the {deckcount} configured decks"));
+                self.logger.debug("If Rekordbox has finished loading, try increasing keeper.decks in the config");
+            }
+        }
+        self.master_out_of_range_reported = true;
+    }
+
     fn report_error(&mut self, e: MemoryReadError) {
         if let Some(last) = &self.last_error {
             if e == *last {
@@ -638,16 +2253,27 @@ impl BeatKeeper {
         }else{
             ""
         };
+
+        // Rekordbox reports read failures until it has finished starting up
+        if e.error_type == MemoryReadErrorType::ReadMemoryFailed && self.start_time.elapsed() < self.startup_grace {
+            if !self.waiting_reported {
+                self.waiting_reported = true;
+                self.logger.info("Waiting for Rekordbox to finish loading...");
+            }
+            self.logger.debug(&format!("Read memory failed{detail}"));
+            return;
+        }
         match e.error_type {
             MemoryReadErrorType::ProcessNotFound => {
-                self.logger.err("Rekordbox process not found!");
+                self.logger.err(&format!("Rekordbox process not found{detail}"));
             }
             MemoryReadErrorType::SnapshotFailed => {
                 self.logger.err(&format!("Snapshot failed{detail}"));
                 self.logger.info("    Ensure Rekordbox is running!");
             }
             MemoryReadErrorType::ReadMemoryFailed => {
-                self.logger.err(&format!("Read memory failed{detail}"));
+                let label = e.label.as_ref().map(|l| format!(" reading {l}")).unwrap_or_default();
+                self.logger.err(&format!("Read memory failed{label} at {:X}{detail}", e.address));
                 self.logger.info("    Try the following:");
                 self.logger.info("    - Wait for Rekordbox to start and load a track");
                 self.logger.info("    - Ensure you have selected the correct Rekordbox version in the config");
@@ -672,35 +2298,330 @@ impl BeatKeeper {
         if e.address != 0 {
             self.logger.debug(&format!("Address: {:X}", e.address));
         }
+        self.send_event(KeeperEvent::Error(KeeperError::from(&e)));
         self.last_error = Some(e);
     }
+
+    /// Reading a non-essential value only disables its feature while it fails, instead of
+    /// dropping the connection. Essential values (BPM, position, master deck) still use `?`
+    fn read_optional<T>(&mut self, value: OptionalValue, result: Result<T, MemoryReadError>) -> Option<T> {
+        let failed = &mut self.optional_read_failed[value as usize];
+        match result {
+            Ok(v) => {
+                if *failed {
+                    *failed = false;
+                    self.logger.info(&format!("Reading {} again", value.name()));
+                }
+                Some(v)
+            }
+            Err(e) => {
+                if !*failed {
+                    *failed = true;
+                    self.logger.warn(&format!("Failed to read {}, continuing without it. The offsets may be outdated", value.name()));
+                }
+                if let Some(p) = &e.pointer {
+                    self.logger.debug(&format!("Pointer: {p}"));
+                }
+                None
+            }
+        }
+    }
+
+    fn send_event(&self, event: KeeperEvent) {
+        if let Some(events) = &self.events {
+            // The host may have stopped listening, which is fine
+            let _ = events.send(event);
+        }
+    }
+}
+
+/// Track info from Rekordbox's "Label: value" lines. The first three lines are the title, artist
+/// and album, without an ID. Lines without a label are taken whole, as localized Rekordbox
+/// versions may not label them the same way
+fn parse_track_info(raw: &[u8]) -> TrackInfo {
+    let raw = raw.iter().copied().take_while(|x| *x != 0x00).collect::<Vec<u8>>();
+    let Ok(text) = String::from_utf8(raw) else {
+        return TrackInfo { title: "ERR".to_string(), ..Default::default() };
+    };
+    let fields: Vec<(&str, &str)> = text
+        .lines()
+        .map(split_label)
+        .collect();
+    let mut lines = fields.iter().map(|(_, value)| value.to_string());
+    // Only present in the buffer for some tracks and versions, so found by label
+    let labeled = |label: &str| {
+        fields
+            .iter()
+            .skip(3)
+            .find(|(l, _)| l.trim() == label)
+            .map(|(_, value)| value.to_string())
+            .unwrap_or_default()
+    };
+    TrackInfo {
+        title: lines.next().unwrap_or("".to_string()),
+        artist: lines.next().unwrap_or("".to_string()),
+        album: lines.next().unwrap_or("".to_string()),
+        genre: labeled("Genre"),
+        comment: labeled("Comment"),
+        id: None,
+    }
+}
+
+/// A "Label: value" line as label and value, also with the full-width colon of Japanese and
+/// Chinese versions. Without a label the whole line is the value
+fn split_label(line: &str) -> (&str, &str) {
+    line.split_once(": ")
+        .or_else(|| line.split_once('：'))
+        .map_or(("", line), |(label, value)| (label, value.trim_start()))
+}
+
+/// Replace a blank title or artist of a loaded track with the configured text, if any. Empty
+/// decks have no track info at all and stay empty, so modules can still tell them apart
+fn fill_unknown(track: &mut TrackInfo, unknown_title: &str, unknown_artist: &str) {
+    let loaded = track.id.is_some() || [&track.title, &track.artist, &track.album].iter().any(|x| !x.is_empty());
+    if !loaded {
+        return;
+    }
+    if track.title.is_empty() {
+        track.title = unknown_title.to_string();
+    }
+    if track.artist.is_empty() {
+        track.artist = unknown_artist.to_string();
+    }
+}
+
+/// Rekordbox's deck indices (0-3) in the order they should be tracked, eg. "2,3". None if empty or invalid
+fn parse_deck_map(value: &str, logger: &ScopedLogger) -> Option<Vec<usize>> {
+    let mut deck_map = vec![];
+    for deck in value.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+        match deck.parse::<usize>() {
+            Ok(deck @ 0..=3) if !deck_map.contains(&deck) => deck_map.push(deck),
+            _ => {
+                logger.err(&format!("Invalid deck '{deck}' in keeper.deck_map, decks must be 0 to 3 and only listed once. Using keeper.decks instead"));
+                return None;
+            }
+        }
+    }
+    if deck_map.is_empty() { None } else { Some(deck_map) }
+}
+
+/// Bar length from where the grid's beat numbers wrap back to 1. Defaults to 4 when there are no
+/// complete bars, or too many bars disagree
+fn detect_beats_per_bar(beat_numbers: impl Iterator<Item = u16>) -> u32 {
+    let mut bar_lengths: HashMap<u16, usize> = HashMap::new();
+    let mut bars = 0;
+    let mut last = None;
+    for beat_number in beat_numbers {
+        if let Some(last) = last.filter(|last| beat_number == 1 && *last > 1) {
+            *bar_lengths.entry(last).or_default() += 1;
+            bars += 1;
+        }
+        last = Some(beat_number);
+    }
+
+    let Some((&length, &count)) = bar_lengths.iter().max_by_key(|(_, count)| **count) else {
+        return 4;
+    };
+    // Allow a few odd bars, eg. from grid edits
+    if count * 4 >= bars * 3 {
+        length as u32
+    } else {
+        4
+    }
+}
+
+/// Delay compensation in ms as samples to add to the track position. Positive values make beats
+/// fire earlier, to make up for latency further down the chain
+fn delay_compensation_samples(ms: f32) -> i64 {
+    (ms * 44100. / 1000.) as i64
+}
+
+/// Track position in seconds that the outputs should show, including delay compensation
+fn track_time(sample_position: i64, offset_samples: i64) -> f32 {
+    (sample_position + offset_samples) as f32 / 44100.
+}
+
+/// Beat within the bar, the grid's tempo and the index of the last grid beat at `time_now` seconds.
+/// None for a grid without beats
+fn grid_beat(beats: &[anlz::Beat], beats_per_bar: u32, time_now: f32) -> Option<(f32, f32, usize)> {
+    if beats.is_empty() {
+        return None;
+    }
+    let mut beat_idx: usize = 0;
+    for gridbeat in beats.iter() {
+        if gridbeat.time as f32 / 1000. >= time_now {
+            break;
+        }
+        beat_idx += 1;
+    }
+    beat_idx = beat_idx.saturating_sub(1);
+    let gridbeat = &beats[beat_idx];
+    let remainder = time_now - gridbeat.time as f32 / 1000.;
+    let original_bpm = gridbeat.tempo as f32 / 100.0;
+    let spb = 1. / (gridbeat.tempo as f32 / 100. / 60.0);
+
+    let b = (gridbeat.beat_number as u32 + beats_per_bar - 1) % beats_per_bar;
+    // A beat without a tempo holds its position rather than dividing by zero
+    let fraction = if spb.is_finite() { remainder / spb } else { 0. };
+    Some((b as f32 + fraction, original_bpm, beat_idx))
+}
+
+/// One line summary of the master deck and the modules' stats, for keeper.stats_line
+fn stats_line(deck: usize, bpm: f32, beat: f32, phrase: &str, modules: &[(String, String)]) -> String {
+    let phrase = if phrase.is_empty() { "-" } else { phrase };
+    let mut line = format!("Deck {deck} | {bpm:.2} BPM | beat {beat:.2} | {phrase}");
+    for (name, stats) in modules {
+        line.push_str(&format!(" | {name}: {stats}"));
+    }
+    line
+}
+
+/// Beats left in the bar, counting the current one: beats_per_bar down to 1
+fn bar_countdown(beat: f32, beats_per_bar: u32) -> i32 {
+    (beats_per_bar as i32 - beat.floor() as i32).clamp(1, beats_per_bar.max(1) as i32)
+}
+
+/// Step of the beat within the bar in `division`th notes, from 0 at the downbeat, eg. 0 to 15 for
+/// 16th notes in 4/4
+fn step_in_bar(beat: f32, division: u32) -> i32 {
+    (beat * division as f32 / 4.).floor() as i32
+}
+
+/// keeper.step_division, the note length of the steps sent by sixteenth_changed
+fn step_division(keeper_config: &Config, logger: &ScopedLogger) -> u32 {
+    match keeper_config.get_or_default("step_division", 16) {
+        division @ (8 | 16 | 32) => division,
+        division => {
+            logger.err(&format!("Invalid keeper.step_division {division}, expected 8, 16 or 32"));
+            16
+        }
+    }
+}
+
+/// Shift a beat by `offset` beats, keeping it within the bar
+fn nudge_beat(beat: f32, offset: f32, beats_per_bar: u32) -> f32 {
+    (beat + offset).rem_euclid(beats_per_bar as f32)
+}
+
+/// Values that aren't needed for beat tracking
+#[derive(Clone, Copy)]
+enum OptionalValue {
+    TrackInfo,
+    ArtworkPath,
+    AnlzPath,
+    DeckLayout,
+    Crossfader,
+    PreviewTrack,
+    ChannelFader,
+    CueMonitor,
+    PerfState,
+}
+
+impl OptionalValue {
+    fn name(&self) -> &'static str {
+        match self {
+            OptionalValue::TrackInfo => "track info",
+            OptionalValue::ArtworkPath => "artwork path",
+            OptionalValue::AnlzPath => "analysis file path",
+            OptionalValue::DeckLayout => "deck layout",
+            OptionalValue::Crossfader => "crossfader",
+            OptionalValue::PreviewTrack => "preview track",
+            OptionalValue::ChannelFader => "channel fader",
+            OptionalValue::CueMonitor => "headphone cue",
+            OptionalValue::PerfState => "slip and loop roll state",
+        }
+    }
+}
+
+/// How long a deck counts as playing after its position last moved
+const PLAYING_HOLD_TIME: Duration = Duration::from_millis(100);
+
+/// Fastest a deck can play, with the widest tempo range
+const MAX_PLAYBACK_SPEED: f64 = 2.;
+/// Allowed position change on top of playback, as the position is only updated once per audio buffer
+const SEEK_TOLERANCE: Duration = Duration::from_millis(250);
+
+/// Whether the position moved further than playback allows in the time between two reads
+fn is_seek(last_position: i64, last_read_at: SystemTime, position: i64, read_at: SystemTime) -> bool {
+    let elapsed = read_at.duration_since(last_read_at).unwrap_or_default();
+    let allowed = (elapsed.as_secs_f64() * MAX_PLAYBACK_SPEED + SEEK_TOLERANCE.as_secs_f64()) * 44100.;
+    (position - last_position).unsigned_abs() as f64 > allowed
 }
 
 struct TrackTrackerResult {
     beat: f32,
+    absolute_beat: f32, // Beats since the first beat of the grid, counting across bars
+    beat_valid: bool, // False if the beat is a fallback because there is no beatgrid
+    beats_per_bar: u32,
+    bar_countdown: i32,
     original_bpm: f32,
     timing_data_raw: TimingDataRaw,
     phrase: String,
     next_phrase: String,
     next_phrase_in: i32,
+    mood: u8,
+    seeked: bool, // The position jumped since the last update
+    remaining: Option<f32>, // Seconds until the last beat of the grid at the current tempo, None without a grid
 }
 
 struct TrackTracker {
     beatgrid: Option<BeatGrid>,
     songstructure: Option<rekordcrate::anlz::SongStructureData>,
+    beats_per_bar: u32,
+    fallback_beats: bool,
+    fallback: Option<FallbackPhase>, // Without a beatgrid, None until the next update anchors it
+    last_position: Option<(i64, SystemTime)>,
+}
+
+/// Free-running beat of keeper.fallback_beats
+struct FallbackPhase {
+    absolute_beat: f32,
+    moved_at: SystemTime, // When the position last moved
 }
 
 impl TrackTracker {
-    fn new() -> Self {
+    fn new(fallback_beats: bool) -> Self {
         Self {
             beatgrid: None,
             songstructure: None,
+            beats_per_bar: 4,
+            fallback_beats,
+            fallback: None,
+            last_position: None,
         }
     }
 
+    /// Beats since the anchor at the deck's current tempo. The tempo is already pitched, so the
+    /// beat follows the time the deck plays rather than the track position, which would pitch it
+    /// twice. Anchored to the position on the first update, after a seek and for a new track
+    fn fallback_beat(&mut self, td: &TimingDataRaw, previous: Option<(i64, SystemTime)>, time_now: f32, seeked: bool) -> f32 {
+        let phase = match &mut self.fallback {
+            Some(phase) if !seeked => phase,
+            fallback => {
+                let absolute_beat = time_now * td.current_bpm / 60.;
+                *fallback = Some(FallbackPhase { absolute_beat, moved_at: td.read_at });
+                return absolute_beat;
+            }
+        };
+        // Stands still while stopped, and between the audio buffers while playing
+        if previous.is_some_and(|(position, _)| position != td.sample_position) {
+            let since_moved = td.read_at.duration_since(phase.moved_at).unwrap_or_default();
+            // Started again, so only the time since the last update was played
+            let played = if since_moved > PLAYING_HOLD_TIME {
+                previous.and_then(|(_, read_at)| td.read_at.duration_since(read_at).ok()).unwrap_or_default()
+            } else {
+                since_moved
+            };
+            phase.absolute_beat += played.as_secs_f32() * td.current_bpm / 60.;
+            phase.moved_at = td.read_at;
+        }
+        phase.absolute_beat
+    }
+
     fn update(
         &mut self,
-        rb: &Rekordbox,
+        rb: &dyn DeckSource,
+        phraseparser: &PhraseParser,
         offset_samples: i64,
         deck: usize,
     ) -> Result<TrackTrackerResult, MemoryReadError> {
@@ -708,43 +2629,57 @@ impl TrackTracker {
         if td.current_bpm == 0.0 {
             td.current_bpm = 120.0;
         }
+        let previous = self.last_position;
+        let seeked = previous.is_some_and(|(position, read_at)| is_seek(position, read_at, td.sample_position, td.read_at));
+        self.last_position = Some((td.sample_position, td.read_at));
 
 
 
         let mut beat = 0.0;
         let mut original_bpm = 120.0;
 
-        let time_now = (td.sample_position + offset_samples) as f32 / 44100.;
+        let time_now = track_time(td.sample_position, offset_samples);
         let mut beat_idx: usize = 0;
-        if let Some(grid) = &self.beatgrid {
-            for gridbeat in grid.beats.iter() {
-                if gridbeat.time as f32 / 1000. >= time_now {
-                    break;
-                }
-                beat_idx += 1;
-            }
-            beat_idx = beat_idx.saturating_sub(1);
-            let gridbeat = &grid.beats[beat_idx];
-            // println!("{} - {}", time, time_now);
-            let remainder = time_now - gridbeat.time as f32 / 1000.;
-            original_bpm = gridbeat.tempo as f32 / 100.0;
-            let spb = 1. / (gridbeat.tempo as f32 / 100. / 60.0);
-
-            let b = (gridbeat.beat_number + 3) % 4;
-            // println!("{b} {idx}");
-            beat = b as f32 + remainder / spb;
+        let mut absolute_beat = 0.0;
+        // A grid without beats is handled as no grid
+        let grid_position = self.beatgrid.as_ref().and_then(|grid| grid_beat(&grid.beats, self.beats_per_bar, time_now));
+        let has_grid = grid_position.is_some();
+        if let Some(position) = grid_position {
+            (beat, original_bpm, beat_idx) = position;
+            absolute_beat = beat_idx as f32 + beat.fract();
+        } else if self.fallback_beats {
+            absolute_beat = self.fallback_beat(&td, previous, time_now, seeked);
+            beat = absolute_beat.rem_euclid(self.beats_per_bar as f32);
+        }
+        if has_grid || self.fallback_beats {
+            beat = nudge_beat(beat, KEEPER_CONTROL.beat_phase_offset(), self.beats_per_bar);
+            absolute_beat += KEEPER_CONTROL.beat_phase_offset();
         }
 
 
         let beat_num = beat_idx + 1;
 
+        // The grid runs to the end of the audio, so its last beat is used as the track length
+        let remaining = self.beatgrid.as_ref().and_then(|grid| {
+            let end = grid.beats.last()?.time as f32 / 1000.;
+            let speed = if original_bpm > 0. { td.current_bpm / original_bpm } else { 1. };
+            Some(((end - time_now) / speed).max(0.))
+        });
+
         let mut tout = TrackTrackerResult {
             beat,
+            absolute_beat,
+            beat_valid: has_grid,
+            beats_per_bar: self.beats_per_bar,
+            bar_countdown: bar_countdown(beat, self.beats_per_bar),
             original_bpm,
             timing_data_raw: td,
             phrase: "".to_string(),
             next_phrase: "".to_string(),
             next_phrase_in: 0,
+            mood: 0,
+            seeked,
+            remaining,
         };
 
         let mut phrase_idx: usize = 0;
@@ -759,17 +2694,593 @@ impl TrackTracker {
             }
             phrase_idx = phrase_idx.saturating_sub(1);
             // println!("{phrase_idx} {beat_idx} {:?}", &songstructure.phrases[phrase_idx].kind);
-            // println!("Phrase: {beat_num} {}", rb.phraseparser.get_phrase_name(&songstructure.mood, &songstructure.phrases[phrase_idx]));
-            tout.phrase = rb.phraseparser.get_phrase_name(&songstructure.mood, &songstructure.phrases[phrase_idx]);
+            // println!("Phrase: {beat_num} {}", phraseparser.get_phrase_name(&songstructure.mood, &songstructure.phrases[phrase_idx]));
+            tout.mood = PhraseParser::mood_to_index(&songstructure.mood);
+            tout.phrase = phraseparser.get_phrase_name(&songstructure.mood, &songstructure.phrases[phrase_idx]);
             if phrase_idx + 1 < songstructure.phrases.len() {
                 let next_phrase = &songstructure.phrases[phrase_idx + 1];
                 let next_phrase_in = next_phrase.beat as i32 - beat_num as i32;
-                tout.next_phrase = rb.phraseparser.get_phrase_name(&songstructure.mood, next_phrase);
+                tout.next_phrase = phraseparser.get_phrase_name(&songstructure.mood, next_phrase);
                 tout.next_phrase_in = next_phrase_in;
-                // println!("{}: {next_phrase_in}", rb.phraseparser.get_phrase_name(&songstructure.mood, next_phrase));
+                // println!("{}: {next_phrase_in}", phraseparser.get_phrase_name(&songstructure.mood, next_phrase));
             }
         }
 
         Ok(tout)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventSink, ModuleEvent};
+
+    fn beat_numbers(beats_per_bar: u16, first: u16, beats: usize) -> impl Iterator<Item = u16> {
+        (0..beats as u16).map(move |i| (first - 1 + i) % beats_per_bar + 1)
+    }
+
+    /// 120 BPM grid in 4/4, one beat every 500 ms starting at 0
+    fn grid(beats: u32) -> Vec<anlz::Beat> {
+        (0..beats)
+            .map(|i| anlz::Beat {
+                beat_number: (i % 4 + 1) as u16,
+                tempo: 12000,
+                time: i * 500,
+            })
+            .collect()
+    }
+
+    /// Reads of a deck playing at `bpm` for `seconds` from `start`, every 20 ms with the position
+    /// moving every other read, like it does once per audio buffer. `pitch` is the playback speed
+    fn pitched_reads(start: (i64, SystemTime), bpm: f32, pitch: f64, seconds: f64) -> Vec<TimingDataRaw> {
+        (1..=(seconds / 0.02).round() as u32)
+            .map(|n| {
+                let elapsed = Duration::from_millis(20 * n as u64);
+                let moved = if n % 2 == 0 { elapsed } else { elapsed - Duration::from_millis(20) };
+                TimingDataRaw {
+                    current_bpm: bpm,
+                    sample_position: start.0 + (moved.as_secs_f64() * pitch * 44100.) as i64,
+                    read_at: start.1 + elapsed,
+                }
+            })
+            .collect()
+    }
+
+    /// Feeds the reads to the fallback beat the way `update` does, returning the last beat
+    fn play(tracker: &mut TrackTracker, reads: &[TimingDataRaw]) -> f32 {
+        let mut beat = 0.;
+        for td in reads {
+            let previous = tracker.last_position;
+            let seeked = previous.is_some_and(|(position, read_at)| is_seek(position, read_at, td.sample_position, td.read_at));
+            tracker.last_position = Some((td.sample_position, td.read_at));
+            beat = tracker.fallback_beat(td, previous, track_time(td.sample_position, 0), seeked);
+        }
+        beat
+    }
+
+    #[test]
+    fn fallback_beats_follow_the_pitched_tempo() {
+        // 120 BPM at +8% pitch is shown as 129.6 BPM
+        let mut tracker = TrackTracker::new(true);
+        let start = (0, SystemTime::UNIX_EPOCH);
+        let reads = pitched_reads(start, 129.6, 1.08, 10.);
+        let anchor = play(&mut tracker, &reads[..1]);
+        let beat = play(&mut tracker, &reads[1..]);
+        // 10 s at 129.6 BPM, not 10.8 s of track time at 129.6 BPM, which would be 23.3 beats
+        assert!((beat - anchor - 21.6).abs() < 0.1, "{}", beat - anchor);
+    }
+
+    #[test]
+    fn fallback_beats_dont_jump_when_the_tempo_changes() {
+        let mut tracker = TrackTracker::new(true);
+        let reads = pitched_reads((0, SystemTime::UNIX_EPOCH), 120., 1., 5.);
+        let before = play(&mut tracker, &reads);
+        let last = reads.last().unwrap();
+        let faster = pitched_reads((last.sample_position, last.read_at), 132., 1.1, 0.04);
+        let after = play(&mut tracker, &faster);
+        // 40 ms at 132 BPM, instead of rescaling the 5 s played so far
+        assert!((after - before - 0.088).abs() < 0.01, "{}", after - before);
+    }
+
+    #[test]
+    fn fallback_beats_hold_while_stopped_and_reanchor_on_seek() {
+        let mut tracker = TrackTracker::new(true);
+        let start = SystemTime::UNIX_EPOCH;
+        let reads = pitched_reads((0, start), 120., 1., 2.);
+        let beat = play(&mut tracker, &reads);
+        let last = reads.last().unwrap();
+        let stopped: Vec<TimingDataRaw> = (1..=50)
+            .map(|n| TimingDataRaw { read_at: last.read_at + Duration::from_millis(20 * n), ..*last })
+            .collect();
+        assert_eq!(play(&mut tracker, &stopped), beat);
+
+        // Jumping to 60 s is anchored at 2 beats per second again
+        let seek = TimingDataRaw {
+            current_bpm: 120.,
+            sample_position: 60 * 44100,
+            read_at: stopped.last().unwrap().read_at + Duration::from_millis(20),
+        };
+        assert_eq!(play(&mut tracker, &[seek]), 120.);
+    }
+
+    #[test]
+    fn grid_beat_without_compensation() {
+        let grid = grid(16);
+        let (beat, bpm, idx) = grid_beat(&grid, 4, track_time(44100, 0)).unwrap();
+        assert!((beat - 2.).abs() < 1e-3, "{beat}");
+        assert_eq!(bpm, 120.);
+        assert_eq!(idx, 1);
+    }
+
+    #[test]
+    fn stats_line_lists_modules() {
+        let modules = [("OSC".to_string(), "120 sent".to_string()), ("MIDI".to_string(), "8 sent".to_string())];
+        assert_eq!(stats_line(1, 128., 2.5, "Chorus", &modules), "Deck 1 | 128.00 BPM | beat 2.50 | Chorus | OSC: 120 sent | MIDI: 8 sent");
+        assert_eq!(stats_line(0, 0., 0., "", &[]), "Deck 0 | 0.00 BPM | beat 0.00 | -");
+    }
+
+    #[test]
+    fn bar_counts_down_to_one() {
+        assert_eq!(bar_countdown(0., 4), 4);
+        assert_eq!(bar_countdown(0.99, 4), 4);
+        assert_eq!(bar_countdown(1., 4), 3);
+        assert_eq!(bar_countdown(3.5, 4), 1);
+        assert_eq!(bar_countdown(2.5, 3), 1);
+        assert_eq!(bar_countdown(4., 4), 1);
+    }
+
+    #[test]
+    fn steps_count_up_from_the_downbeat() {
+        assert_eq!(step_in_bar(0., 16), 0);
+        assert_eq!(step_in_bar(0.24, 16), 0);
+        assert_eq!(step_in_bar(0.25, 16), 1);
+        assert_eq!(step_in_bar(3.99, 16), 15);
+        assert_eq!(step_in_bar(3.99, 8), 7);
+        assert_eq!(step_in_bar(2.9, 32), 23);
+    }
+
+    #[test]
+    fn grid_without_beats_is_no_grid() {
+        assert_eq!(grid_beat(&[], 4, 1.), None);
+    }
+
+    #[test]
+    fn single_beat_grid_runs_at_its_tempo() {
+        let grid = grid(1);
+        let (beat, bpm, idx) = grid_beat(&grid, 4, 1.).unwrap();
+        assert!((beat - 2.).abs() < 1e-3, "{beat}");
+        assert_eq!(bpm, 120.);
+        assert_eq!(idx, 0);
+
+        let no_tempo = [anlz::Beat { beat_number: 1, tempo: 0, time: 0 }];
+        assert_eq!(grid_beat(&no_tempo, 4, 1.), Some((0., 0., 0)));
+    }
+
+    #[test]
+    fn positive_delay_compensation_fires_beats_earlier() {
+        let grid = grid(16);
+        // 250 ms is half a beat at 120 BPM
+        let offset = delay_compensation_samples(250.);
+        assert_eq!(offset, 11025);
+
+        // Half way between the first and second beat, the second beat is already reported
+        let (beat, _, _) = grid_beat(&grid, 4, track_time(11025, offset)).unwrap();
+        assert!((beat - 1.).abs() < 1e-3, "{beat}");
+
+        let (beat, _, _) = grid_beat(&grid, 4, track_time(44100, offset)).unwrap();
+        assert!((beat - 2.5).abs() < 1e-3, "{beat}");
+        let (beat, _, _) = grid_beat(&grid, 4, track_time(44100, -offset)).unwrap();
+        assert!((beat - 1.5).abs() < 1e-3, "{beat}");
+    }
+
+    #[test]
+    fn detects_four_four() {
+        assert_eq!(detect_beats_per_bar(beat_numbers(4, 1, 64)), 4);
+    }
+
+    #[test]
+    fn detects_three_four() {
+        assert_eq!(detect_beats_per_bar(beat_numbers(3, 1, 48)), 3);
+        // Grid starting mid-bar
+        assert_eq!(detect_beats_per_bar(beat_numbers(3, 2, 48)), 3);
+    }
+
+    #[test]
+    fn tolerates_odd_bars() {
+        let grid = beat_numbers(3, 1, 30).chain(beat_numbers(4, 1, 4)).chain(beat_numbers(3, 1, 30));
+        assert_eq!(detect_beats_per_bar(grid), 3);
+    }
+
+    #[test]
+    fn ambiguous_defaults_to_four() {
+        assert_eq!(detect_beats_per_bar(std::iter::empty()), 4);
+        assert_eq!(detect_beats_per_bar(beat_numbers(3, 1, 3)), 4);
+        let grid = beat_numbers(3, 1, 12).chain(beat_numbers(5, 1, 20));
+        assert_eq!(detect_beats_per_bar(grid), 4);
+    }
+
+    #[test]
+    fn detects_backward_seek() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let tick = Duration::from_millis(20);
+        // Playing normally, and standing still between audio buffers
+        assert!(!is_seek(441000, start, 441882, start + tick));
+        assert!(!is_seek(441000, start, 441000, start + tick));
+        // Jumped back from 10 s to 2 s
+        assert!(is_seek(441000, start, 88200, start + tick));
+        // And forward
+        assert!(is_seek(88200, start, 441000, start + tick));
+        // Nothing was read for a while, eg. while Rekordbox was busy
+        assert!(!is_seek(88200, start, 441000, start + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn parses_track_info_text() {
+        let mut raw = [0u8; 200];
+        let text = b"Title: Song\nArtist: Someone\nAlbum: Record\nGenre: House\nComment: Peak time";
+        raw[..text.len()].copy_from_slice(text);
+        let track = parse_track_info(&raw);
+        assert_eq!(track.title, "Song");
+        assert_eq!(track.artist, "Someone");
+        assert_eq!(track.album, "Record");
+        assert_eq!(track.genre, "House");
+        assert_eq!(track.comment, "Peak time");
+        assert_eq!(parse_track_info(&[0u8; 200]), TrackInfo::default());
+    }
+
+    #[test]
+    fn parses_unlabeled_track_info_by_position() {
+        let mut raw = [0u8; 200];
+        let text = "Lied\nJemand\nPlatte".as_bytes();
+        raw[..text.len()].copy_from_slice(text);
+        let track = parse_track_info(&raw);
+        assert_eq!((track.title.as_str(), track.artist.as_str(), track.album.as_str()), ("Lied", "Jemand", "Platte"));
+        assert_eq!(track.genre, "");
+
+        // Each line is parsed on its own, eg. only the album labelled
+        let mut raw = [0u8; 200];
+        let text = "曲名：歌\nSomeone\nAlbum: Record".as_bytes();
+        raw[..text.len()].copy_from_slice(text);
+        let track = parse_track_info(&raw);
+        assert_eq!((track.title.as_str(), track.artist.as_str(), track.album.as_str()), ("歌", "Someone", "Record"));
+    }
+
+    #[test]
+    fn fills_unknown_fields_of_loaded_tracks_only() {
+        let mut track = TrackInfo { album: "Record".to_string(), ..Default::default() };
+        fill_unknown(&mut track, "Unknown Track", "Unknown Artist");
+        assert_eq!((track.title.as_str(), track.artist.as_str()), ("Unknown Track", "Unknown Artist"));
+
+        let mut empty_deck = TrackInfo::default();
+        fill_unknown(&mut empty_deck, "Unknown Track", "Unknown Artist");
+        assert_eq!(empty_deck, TrackInfo::default());
+
+        let mut undecodable = parse_track_info(&[0xff; 4]);
+        fill_unknown(&mut undecodable, "Unknown Track", "Unknown Artist");
+        assert_eq!((undecodable.title.as_str(), undecodable.artist.as_str()), ("ERR", "Unknown Artist"));
+    }
+
+    #[test]
+    fn parses_deck_map() {
+        let logger = ScopedLogger::new(&std::rc::Rc::new(crate::log::Logger::new(false)), "Test");
+        assert_eq!(parse_deck_map("2,3", &logger), Some(vec![2, 3]));
+        assert_eq!(parse_deck_map(" 1 , 0 ", &logger), Some(vec![1, 0]));
+        assert_eq!(parse_deck_map("", &logger), None);
+        assert_eq!(parse_deck_map("1,4", &logger), None);
+        assert_eq!(parse_deck_map("1,1", &logger), None);
+    }
+
+    #[test]
+    fn end_warning_fires_once_and_resets() {
+        let mut warning = EndWarning { seconds: Some(30.), active: false };
+        assert_eq!(warning.update(Some(31.)), None);
+        assert_eq!(warning.update(Some(29.9)), Some(true));
+        assert_eq!(warning.update(Some(29.)), None);
+        // Jitter around the threshold doesn't flap
+        assert_eq!(warning.update(Some(30.5)), None);
+        // New track
+        assert_eq!(warning.update(Some(240.)), Some(false));
+        assert_eq!(warning.update(Some(10.)), Some(true));
+        // Track without a grid
+        assert_eq!(warning.update(None), Some(false));
+
+        let mut disabled = EndWarning { seconds: None, active: false };
+        assert_eq!(disabled.update(Some(0.)), None);
+    }
+
+    thread_local! {
+        static RECORDED: std::cell::RefCell<Vec<ModuleEvent>> = const { std::cell::RefCell::new(vec![]) };
+    }
+
+    /// Collects the events of the recording module, per test thread
+    struct Recorded;
+
+    impl EventSink for Recorded {
+        fn write(&mut self, event: ModuleEvent) -> std::io::Result<()> {
+            RECORDED.with(|recorded| recorded.borrow_mut().push(event));
+            Ok(())
+        }
+    }
+
+    fn recording_module(_conf: Config, logger: ScopedLogger) -> crate::outputmodules::ModuleCreateOutput {
+        Ok(Box::new(EventRecorder::new(Box::new(Recorded), logger)))
+    }
+
+    /// Decks playing the same track at a settable tempo, with a settable master deck. A single
+    /// deck unless built with more
+    struct MockDeck {
+        decks: usize,
+        master: std::cell::Cell<usize>,
+        bpm: std::cell::Cell<f32>,
+        title: std::cell::RefCell<String>,
+    }
+
+    impl MockDeck {
+        fn new(bpm: f32, title: &str) -> Self {
+            MockDeck {
+                decks: 1,
+                master: std::cell::Cell::new(0),
+                bpm: std::cell::Cell::new(bpm),
+                title: std::cell::RefCell::new(title.to_string()),
+            }
+        }
+
+        fn track(&self) -> TrackInfo {
+            TrackInfo {
+                title: self.title.borrow().clone(),
+                ..TrackInfo::default()
+            }
+        }
+    }
+
+    impl DeckSource for MockDeck {
+        fn deckcount(&self) -> usize {
+            self.decks
+        }
+
+        fn read_timing_data(&self, _deck: usize) -> Result<TimingDataRaw, MemoryReadError> {
+            Ok(TimingDataRaw {
+                current_bpm: self.bpm.get(),
+                sample_position: 0,
+                read_at: SystemTime::now(),
+            })
+        }
+
+        fn read_masterdeck_index(&self) -> Result<usize, MemoryReadError> {
+            Ok(self.master.get())
+        }
+
+        fn get_track_infos(&self) -> Result<Vec<TrackInfo>, MemoryReadError> {
+            Ok(vec![self.track()])
+        }
+    }
+
+    /// A keeper for the decks of `deck` with the recording module, after a first update so only
+    /// later changes are recorded
+    fn recording_keeper(entries: &[(&str, &str)], deck: &MockDeck) -> BeatKeeper {
+        let logger = ScopedLogger::new(&std::rc::Rc::new(crate::log::Logger::new(false)), "Test");
+        let offsets = RekordboxOffsets::from_lines(&["7.0.0".to_string(), "0 0".to_string()], &logger).unwrap();
+        let decks = deck.decks.to_string();
+        let mut all_entries = vec![("keeper.decks", decks.as_str()), ("recording.enabled", "true")];
+        all_entries.extend_from_slice(entries);
+        let modules = vec![ModuleDefinition::new("recording", "Recording", recording_module)];
+        let mut keeper = BeatKeeper::new(offsets, None, modules, Config::from_entries(&all_entries), logger, None).unwrap();
+        assert!(keeper.update(deck, true, true).is_ok());
+        RECORDED.with(|recorded| recorded.borrow_mut().clear());
+        keeper
+    }
+
+    /// Runs one update per `(slow_update, very_slow_update)` tick, returning the events of each
+    /// tick that `filter` keeps
+    fn run_ticks(
+        keeper: &mut BeatKeeper,
+        deck: &MockDeck,
+        ticks: &[(bool, bool)],
+        mut before_tick: impl FnMut(usize),
+        filter: fn(&ModuleEvent) -> bool,
+    ) -> Vec<Vec<ModuleEvent>> {
+        ticks
+            .iter()
+            .enumerate()
+            .map(|(n, &(slow_update, very_slow_update))| {
+                before_tick(n);
+                assert!(keeper.update(deck, slow_update, very_slow_update).is_ok());
+                RECORDED.with(|recorded| recorded.borrow_mut().drain(..).filter(filter).collect())
+            })
+            .collect()
+    }
+
+    fn is_bpm(event: &ModuleEvent) -> bool {
+        matches!(event, ModuleEvent::BpmChanged { .. } | ModuleEvent::BpmChangedMaster { .. })
+    }
+
+    fn is_track_or_slow_update(event: &ModuleEvent) -> bool {
+        matches!(
+            event,
+            ModuleEvent::TrackChanged { .. } | ModuleEvent::TrackChangedMaster { .. } | ModuleEvent::SlowUpdate | ModuleEvent::VerySlowUpdate
+        )
+    }
+
+    fn bpm_events(bpm: f32) -> Vec<ModuleEvent> {
+        vec![ModuleEvent::BpmChanged { bpm, deck: 0 }, ModuleEvent::BpmChangedMaster { bpm }]
+    }
+
+    /// Tempo per tick, with a very slow update on ticks 2 and 4. The change on tick 4 comes with
+    /// the heartbeat
+    const BPM_TICKS: [(f32, (bool, bool)); 6] = [
+        (120., (false, false)),
+        (121., (false, false)),
+        (121., (false, true)),
+        (121., (false, false)),
+        (122., (false, true)),
+        (122., (false, false)),
+    ];
+
+    fn bpm_run(entries: &[(&str, &str)]) -> Vec<Vec<ModuleEvent>> {
+        let deck = MockDeck::new(120., "Title");
+        let mut keeper = recording_keeper(entries, &deck);
+        let ticks: Vec<(bool, bool)> = BPM_TICKS.iter().map(|(_, tick)| *tick).collect();
+        run_ticks(&mut keeper, &deck, &ticks, |n| deck.bpm.set(BPM_TICKS[n].0), is_bpm)
+    }
+
+    #[test]
+    fn bpm_is_only_sent_on_change_without_heartbeat() {
+        assert_eq!(
+            bpm_run(&[]),
+            [vec![], bpm_events(121.), vec![], vec![], bpm_events(122.), vec![]]
+        );
+    }
+
+    #[test]
+    fn bpm_heartbeat_resends_on_very_slow_updates_only() {
+        // A change on a heartbeat tick is sent once
+        assert_eq!(
+            bpm_run(&[("keeper.heartbeat.bpm", "true")]),
+            [vec![], bpm_events(121.), bpm_events(121.), vec![], bpm_events(122.), vec![]]
+        );
+    }
+
+    #[test]
+    fn track_info_heartbeat_waits_for_the_next_slow_update() {
+        let deck = MockDeck::new(120., "First");
+        let mut keeper = recording_keeper(&[("keeper.heartbeat.track_info", "true")], &deck);
+        let first = deck.track();
+        // The very slow update on tick 0 isn't a slow update, so it's latched until tick 1
+        let ticks = [(false, true), (true, false), (true, false), (false, false), (true, false)];
+        let events = run_ticks(&mut keeper, &deck, &ticks, |n| if n == 3 { *deck.title.borrow_mut() = "Second".to_string() }, is_track_or_slow_update);
+        let second = deck.track();
+        assert_eq!(
+            events,
+            [
+                vec![],
+                vec![
+                    ModuleEvent::TrackChanged { track: first.clone(), deck: 0 },
+                    ModuleEvent::SlowUpdate,
+                    ModuleEvent::VerySlowUpdate,
+                    ModuleEvent::TrackChangedMaster { track: first },
+                ],
+                vec![ModuleEvent::SlowUpdate],
+                vec![],
+                vec![
+                    ModuleEvent::TrackChanged { track: second.clone(), deck: 0 },
+                    ModuleEvent::SlowUpdate,
+                    ModuleEvent::TrackChangedMaster { track: second },
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn latched_very_slow_update_without_track_info_heartbeat() {
+        let deck = MockDeck::new(120., "First");
+        let mut keeper = recording_keeper(&[], &deck);
+        let events = run_ticks(&mut keeper, &deck, &[(false, true), (true, false), (true, false)], |_| {}, is_track_or_slow_update);
+        assert_eq!(events, [vec![], vec![ModuleEvent::SlowUpdate, ModuleEvent::VerySlowUpdate], vec![ModuleEvent::SlowUpdate]]);
+    }
+
+    fn is_master_switch(event: &ModuleEvent) -> bool {
+        matches!(event, ModuleEvent::MasterdeckIndexChanged { .. })
+    }
+
+    /// Two decks with deck 0 as master, and a keeper that debounces master switches by 100 ms
+    fn debounced_keeper() -> (BeatKeeper, MockDeck) {
+        let deck = MockDeck { decks: 2, ..MockDeck::new(120., "Title") };
+        let keeper = recording_keeper(&[("keeper.master_switch_debounce_ms", "100")], &deck);
+        (keeper, deck)
+    }
+
+    #[test]
+    fn master_flip_flop_within_the_debounce_never_switches() {
+        let (mut keeper, deck) = debounced_keeper();
+        // 200 ms of flip-flopping, but never 100 ms on deck 1
+        let events = run_ticks(
+            &mut keeper,
+            &deck,
+            &[(false, false); 10],
+            |n| {
+                deck.master.set(1 - n % 2);
+                thread::sleep(Duration::from_millis(20));
+            },
+            is_master_switch,
+        );
+        assert!(events.iter().all(Vec::is_empty), "{events:?}");
+        assert_eq!(keeper.masterdeck_index.value, 0);
+    }
+
+    #[test]
+    fn stable_master_switches_once_the_debounce_has_passed() {
+        let (mut keeper, deck) = debounced_keeper();
+        deck.master.set(1);
+        let events = run_ticks(
+            &mut keeper,
+            &deck,
+            &[(false, false); 3],
+            |n| {
+                if n == 1 {
+                    thread::sleep(Duration::from_millis(120));
+                }
+            },
+            is_master_switch,
+        );
+        assert_eq!(events, [vec![], vec![ModuleEvent::MasterdeckIndexChanged { index: 1 }], vec![]]);
+    }
+
+    #[test]
+    fn on_air_follows_faders_and_crossfader_assignment() {
+        let logger = ScopedLogger::new(&std::rc::Rc::new(crate::log::Logger::new(false)), "Test");
+        let config = OnAirConfig {
+            volume_threshold: 0.05,
+            crossfader_threshold: 0.05,
+            assign: parse_crossfader_assign("a, B,thru", &logger),
+        };
+        assert_eq!(config.assign, [CrossfaderAssign::A, CrossfaderAssign::B, CrossfaderAssign::Thru]);
+
+        // Without fader offsets, every deck is audible
+        assert!((0..4).all(|deck| config.audible(deck, None, None)));
+        assert!(!config.audible(0, Some(0.), None));
+        assert!(config.audible(0, Some(0.5), Some(0.)));
+        // Crossfader fully right cuts side A, but not B or thru
+        assert!(!config.audible(0, Some(1.), Some(1.)));
+        assert!(config.audible(1, Some(1.), Some(1.)));
+        assert!(config.audible(2, Some(1.), Some(1.)));
+        assert!(config.audible(3, Some(1.), Some(-1.)));
+        assert!(!config.audible(1, Some(1.), Some(-1.)));
+    }
+
+    #[test]
+    fn loop_roll_wins_over_slip() {
+        assert_eq!(PerfState::from_flags(false, false), PerfState::Normal);
+        assert_eq!(PerfState::from_flags(true, false), PerfState::Slip);
+        assert_eq!(PerfState::from_flags(false, true), PerfState::LoopRoll);
+        assert_eq!(PerfState::from_flags(true, true), PerfState::LoopRoll);
+    }
+
+    #[test]
+    fn quantize_crossings() {
+        // Beats within a 4/4 bar, 0 to 4
+        assert!(!Quantize::Beat.crossed(1.2, 1.9));
+        assert!(Quantize::Beat.crossed(1.9, 2.1));
+        assert!(!Quantize::Bar.crossed(1.9, 2.1));
+        assert!(Quantize::Bar.crossed(3.9, 0.1));
+        assert!(Quantize::Beat.crossed(3.9, 0.1));
+        assert!(Quantize::Off.crossed(1.2, 1.2));
+    }
+
+    #[test]
+    fn stopped_master_crosses_nothing() {
+        for quantize in [Quantize::Beat, Quantize::Bar] {
+            assert!(!quantize.crossed(2.5, 2.5));
+            assert!(!quantize.crossed(0., 0.));
+        }
+    }
+
+    #[test]
+    fn seeking_back_counts_as_a_crossing() {
+        // A bar is detected by the beat wrapping around, so a jump back within the bar releases a
+        // held phrase too
+        assert!(Quantize::Bar.crossed(2.5, 1.5));
+        assert!(Quantize::Beat.crossed(2.5, 1.5));
+        // Within the same beat, there is no beat boundary
+        assert!(!Quantize::Beat.crossed(2.8, 2.2));
+    }
+}
@@ -66,6 +66,44 @@ impl Config {
         }
     }
 
+    /// For "every nth" values used as a modulus, which must be at least 1
+    pub fn get_every_nth(&self, key: &str, default: i32) -> i32 {
+        let val = self.get_or_default(key, default);
+        if val < 1 {
+            self.logger
+                .warn(&format!("Value {val} for key '{key}' must be at least 1, using 1"));
+            return 1;
+        }
+        val
+    }
+
+    /// Keys in the namespace starting with `prefix`, without the prefix, and their values. For
+    /// settings keyed by name, eg. osc.type.<address>
+    pub fn entries_with_prefix(&self, prefix: &str) -> Vec<(String, String)> {
+        let prefix = if let Some(namespace) = &self.namespace {
+            format!("{namespace}.{prefix}")
+        } else {
+            prefix.to_string()
+        };
+        let mut entries: Vec<(String, String)> = self
+            .entries
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(&prefix)?.to_string(), value.clone())))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    /// Config with the given entries instead of the config file, for tests
+    #[cfg(test)]
+    pub fn from_entries(entries: &[(&str, &str)]) -> Config {
+        Config {
+            entries: entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            namespace: None,
+            logger: ScopedLogger::new(&std::rc::Rc::new(crate::log::Logger::new(false)), "Test"),
+        }
+    }
+
     pub fn reduce_to_namespace(&self, namespace: &str) -> Config {
         Config {
             entries: self.entries.clone(),
@@ -74,3 +112,37 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(entries: &[(&str, &str)]) -> Config {
+        Config::from_entries(entries)
+    }
+
+    #[test]
+    fn every_nth_is_clamped_to_one() {
+        let conf = config(&[("zero", "0"), ("negative", "-3"), ("three", "3")]);
+        assert_eq!(conf.get_every_nth("zero", 2), 1);
+        assert_eq!(conf.get_every_nth("negative", 2), 1);
+        assert_eq!(conf.get_every_nth("three", 2), 3);
+        assert_eq!(conf.get_every_nth("missing", 2), 2);
+    }
+
+    #[test]
+    fn every_nth_in_namespace() {
+        let conf = config(&[("osc.send_every_nth", "0")]).reduce_to_namespace("osc");
+        assert_eq!(conf.get_every_nth("send_every_nth", 2), 1);
+    }
+
+    #[test]
+    fn entries_with_prefix_in_namespace() {
+        let conf = config(&[("osc.type./a", "int"), ("osc.type./b", "float"), ("osc.other", "1"), ("type./c", "int")])
+            .reduce_to_namespace("osc");
+        assert_eq!(
+            conf.entries_with_prefix("type."),
+            [("/a".to_string(), "int".to_string()), ("/b".to_string(), "float".to_string())]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::Duration;
+
+use eframe::egui;
+
+use crate::beatkeeper::{KeeperEvent, TrackInfo};
+
+/// Small status window for users who don't want to read the console. Only shows what the keeper
+/// reports through its events, and closes when the keeper stops
+struct StatusWindow {
+    events: mpsc::Receiver<KeeperEvent>,
+    connected: bool,
+    last_error: Option<String>,
+    master: Option<(usize, f32, TrackInfo)>,
+    modules: Vec<(String, bool)>,
+}
+
+impl StatusWindow {
+    fn handle_events(&mut self, ctx: &egui::Context) {
+        loop {
+            match self.events.try_recv() {
+                Ok(KeeperEvent::Connected | KeeperEvent::Reconnected) => {
+                    self.connected = true;
+                    self.last_error = None;
+                }
+                Ok(KeeperEvent::Disconnected) => {
+                    self.connected = false;
+                    self.master = None;
+                }
+                Ok(KeeperEvent::Error(e)) => self.last_error = Some(e.to_string()),
+                Ok(KeeperEvent::Master { deck, bpm, track }) => self.master = Some((deck, bpm, track)),
+                Ok(KeeperEvent::Modules(modules)) => self.modules = modules,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    // The keeper stopped, eg. on Ctrl+C in the console
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl eframe::App for StatusWindow {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.handle_events(ctx);
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            if self.connected {
+                ui.colored_label(egui::Color32::GREEN, "Connected to Rekordbox");
+            } else {
+                ui.colored_label(egui::Color32::YELLOW, "Looking for Rekordbox...");
+            }
+            if let Some(error) = &self.last_error {
+                ui.colored_label(egui::Color32::RED, error);
+            }
+
+            ui.separator();
+            ui.heading("Master");
+            match &self.master {
+                Some((deck, bpm, track)) => {
+                    ui.label(format!("Deck {deck}  {bpm:.2} BPM"));
+                    ui.label(&track.title);
+                    ui.label(&track.artist);
+                }
+                None => {
+                    ui.label("-");
+                }
+            }
+
+            ui.separator();
+            ui.heading("Modules");
+            if self.modules.is_empty() {
+                ui.label("No modules running, enable them in the config");
+            }
+            for (name, running) in &self.modules {
+                if *running {
+                    ui.colored_label(egui::Color32::GREEN, format!("{name}: running"));
+                } else {
+                    ui.colored_label(egui::Color32::RED, format!("{name}: restarting"));
+                }
+            }
+        });
+
+        // Events arrive without input, so keep polling
+        ctx.request_repaint_after(Duration::from_millis(100));
+    }
+}
+
+/// Show the status window until it is closed. Must run on the main thread
+pub fn run(events: mpsc::Receiver<KeeperEvent>) -> Result<(), eframe::Error> {
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([320., 260.]),
+        ..Default::default()
+    };
+    let window = StatusWindow {
+        events,
+        connected: false,
+        last_error: None,
+        master: None,
+        modules: vec![],
+    };
+    eframe::run_native(
+        &format!("rkbx_link v{}", crate::VERSION),
+        options,
+        Box::new(|_cc| Ok(Box::new(window))),
+    )
+}
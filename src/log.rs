@@ -1,4 +1,6 @@
+use std::fs::{File, OpenOptions};
 use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{cell::RefCell, rc::Rc};
 use termcolor::{ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -11,9 +13,41 @@ pub enum LogLevel {
     Error = 4,
 }
 
+impl LogLevel {
+    fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Good => "good",
+            LogLevel::Info => "info",
+            LogLevel::Warning => "warning",
+            LogLevel::Error => "error",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum LogFormat {
+    Pretty,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+impl LogFormat {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "pretty" => Some(LogFormat::Pretty),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
 pub struct Logger {
     pub debug_enabled: bool,
+    format: LogFormat,
     stdout: RefCell<StandardStream>,
+    // Replaces the console when running as a service
+    file: Option<RefCell<File>>,
     colours: [ColorSpec; 5],
 }
 
@@ -29,15 +63,62 @@ impl Logger {
         Logger {
             colours,
             debug_enabled: debug,
+            format: LogFormat::Pretty,
             stdout: RefCell::new(StandardStream::stdout(ColorChoice::Always)),
+            file: None,
         }
     }
 
+    /// Appends to the file at `path` instead of printing to the console
+    pub fn with_file(debug: bool, path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut logger = Self::new(debug);
+        logger.file = Some(RefCell::new(file));
+        Ok(logger)
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
     pub fn log(&self, source: &str, message: &str, level: LogLevel) {
         if !self.debug_enabled && level == LogLevel::Debug {
             return;
         }
 
+        let timestamp = || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or_default()
+        };
+
+        if self.format == LogFormat::Json {
+            let line = format!(
+                "{{\"timestamp\":{:.3},\"level\":\"{}\",\"scope\":{},\"message\":{}}}",
+                timestamp(),
+                level.name(),
+                serde_json::to_string(source).unwrap_or_default(),
+                serde_json::to_string(message).unwrap_or_default()
+            );
+            let _ = match &self.file {
+                Some(file) => writeln!(file.borrow_mut(), "{line}"),
+                None => writeln!(self.stdout.borrow_mut(), "{line}"),
+            };
+            return;
+        }
+
+        if let Some(file) = &self.file {
+            let _ = writeln!(
+                file.borrow_mut(),
+                "{:.3} {} [{source}]  {message}",
+                timestamp(),
+                level.name()
+            );
+            return;
+        }
+
         self.stdout
             .borrow_mut()
             .set_color(&self.colours[level as usize])
@@ -66,6 +147,13 @@ impl Logger {
     pub fn error(&self, source: &str, message: &str) {
         self.log(source, message, LogLevel::Error);
     }
+
+    /// Spacing between sections of the console output, left out of JSON logs and the log file
+    pub fn blank_line(&self) {
+        if self.format == LogFormat::Pretty && self.file.is_none() {
+            let _ = writeln!(self.stdout.borrow_mut());
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -101,4 +189,8 @@ impl ScopedLogger {
     pub fn err(&self, message: &str) {
         self.logger.error(&self.source, message);
     }
+
+    pub fn blank_line(&self) {
+        self.logger.blank_line();
+    }
 }
@@ -1,5 +1,5 @@
 use beatkeeper::BeatKeeper;
-use log::{Logger, ScopedLogger};
+use log::{LogFormat, Logger, ScopedLogger};
 use outputmodules::ModuleDefinition;
 use std::path::Path;
 use std::{fs, rc::Rc};
@@ -11,9 +11,15 @@ mod outputmodules;
 
 mod beatkeeper;
 mod config;
+mod events;
+#[cfg(feature = "gui")]
+mod gui;
 mod log;
 mod utils;
 mod memory;
+mod udpsource;
+#[cfg(target_os = "windows")]
+mod service;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -31,28 +37,109 @@ const REPO: &str = "grufkork/rkbx_link/refs/heads/master";
 const OFFSETS_PATH: &str = "./data/offsets";
 #[cfg(target_os = "macos")]
 const OFFSETS_PATH: &str = "./data/offsets-macos";
+// Only parsed to start the keeper, as elsewhere decks are read with keeper.source_backend udp
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+const OFFSETS_PATH: &str = "./data/offsets";
+
+// Replaces the console output in service mode
+const SERVICE_LOG_PATH: &str = "./rkbx_link.log";
 
 fn main() {
-    println!();
-    println!("======================================================================");
-    println!();
-    println!("Rekordbox Link v{VERSION}");
-    println!("Updates          https://github.com/grufkork/rkbx_link/releases/latest");
-    println!("Get a license    https://3gg.se/products/rkbx_link");
-    println!("Repo and docs    https://github.com/grufkork/rkbx_link");
-    println!("Missing a feature? Spotted a bug? Just shoot me a message!");
-    println!();
-    println!("======================================================================");
-    println!();
+    if std::env::args().any(|arg| arg == "--list-ports") {
+        outputmodules::midi::Midi::list_ports();
+        return;
+    }
+
+    if std::env::args().any(|arg| arg == "--service") {
+        #[cfg(target_os = "windows")]
+        if let Err(e) = service::start() {
+            eprintln!("Failed to start service, --service must be started by the service manager: {e}");
+        }
+        #[cfg(not(target_os = "windows"))]
+        run(true, Source::Rekordbox, None);
+        return;
+    }
+
+    let args: Vec<String> = std::env::args().collect();
+    // The value following a flag, eg. the file of --replay <file>
+    let value_of = |flag: &str| args.iter().position(|arg| arg == flag).map(|i| args.get(i + 1));
+
+    if let Some(path) = value_of("--replay") {
+        let Some(path) = path else {
+            eprintln!("--replay needs the event log to replay, eg. --replay ./data/events.jsonl");
+            return;
+        };
+        run(false, Source::Replay(path.clone()), None);
+        return;
+    }
+
+    if let Some(bpm) = value_of("--metronome") {
+        let Some(bpm) = bpm.and_then(|bpm| bpm.parse::<f32>().ok()).filter(|bpm| *bpm > 0.) else {
+            eprintln!("--metronome needs a tempo in BPM, eg. --metronome 120");
+            return;
+        };
+        let phrase_bars = match value_of("--phrase-bars") {
+            None => None,
+            Some(bars) => match bars.and_then(|bars| bars.parse::<u32>().ok()).filter(|bars| *bars > 0) {
+                Some(bars) => Some(bars),
+                None => {
+                    eprintln!("--phrase-bars needs a number of bars, eg. --phrase-bars 8");
+                    return;
+                }
+            },
+        };
+        run(false, Source::Metronome { bpm, phrase_bars }, None);
+        return;
+    }
+
+    #[cfg(feature = "gui")]
+    if !args.iter().any(|arg| arg == "--headless") {
+        // The window needs the main thread, so the keeper runs beside it
+        let (events_tx, events_rx) = std::sync::mpsc::channel();
+        let keeper = std::thread::spawn(move || run(false, Source::Rekordbox, Some(events_tx)));
+        match gui::run(events_rx) {
+            // Closing the window stops the keeper, letting the modules reset their outputs
+            Ok(()) => beatkeeper::KEEPER_CONTROL.request_stop(),
+            Err(e) => eprintln!("Failed to open the status window, running without it: {e}"),
+        }
+        let _ = keeper.join();
+        return;
+    }
+
+    run(false, Source::Rekordbox, None);
+}
+
+/// Where the values sent to the modules come from
+enum Source {
+    Rekordbox,
+    Replay(String), // Event log recorded with debug.record_events
+    Metronome { bpm: f32, phrase_bars: Option<u32> }, // Synthetic 4/4 beat, phrases change every phrase_bars
+}
+
+/// Runs until stopped. In service mode, nothing is printed to or read from the console, and
+/// logging goes to SERVICE_LOG_PATH next to the executable. Sources other than Rekordbox don't
+/// need offsets, and stop when they run out or on Ctrl+C. Keeper events are sent to `events`, eg.
+/// for the status window.
+fn run(service: bool, source: Source, events: Option<std::sync::mpsc::Sender<beatkeeper::KeeperEvent>>) {
+    let interactive = !service;
+
+    if service {
+        // Services start in the system directory, while config and data are kept next to the exe
+        if let Some(dir) = std::env::current_exe().ok().as_deref().and_then(Path::parent) {
+            let _ = std::env::set_current_dir(dir);
+        }
+    } else {
+        print_banner();
+    }
 
-    let logger = Rc::new(Logger::new(true));
+    let logger = Rc::new(create_logger(service, true));
 
     if let Err(e) = fs::create_dir("./data") {
         match e.kind() {
             std::io::ErrorKind::AlreadyExists => {} // Directory already exists, no problem
             _ => {
                 logger.error("App", &format!("Failed to create data directory: {e}"));
-                enter_to_exit();
+                enter_to_exit(interactive);
                 return;
             }
         }
@@ -60,9 +147,18 @@ fn main() {
 
     let mut config = config::Config::read(ScopedLogger::new(&logger, "Config"));
 
-    let logger = Rc::new(Logger::new(config.get_or_default("app.debug", true)));
+    let log_format = config.get_or_default("app.log_format", "pretty".to_string());
+    let logger = Rc::new(
+        create_logger(service, config.get_or_default("app.debug", true))
+            .with_format(LogFormat::from_str(&log_format).unwrap_or(LogFormat::Pretty)),
+    );
+
     config.logger = ScopedLogger::new(&logger, "Config");
     let applogger = ScopedLogger::new(&logger, "App");
+    if LogFormat::from_str(&log_format).is_none() {
+        applogger.err(&format!("Unknown log format: {log_format}"));
+    }
+    utils::PhraseParser::set_aliases(&config.reduce_to_namespace("keeper"), &applogger);
 
     let modules = vec![
         ModuleDefinition::new(
@@ -73,11 +169,20 @@ fn main() {
         ModuleDefinition::new("osc", "OSC", outputmodules::osc::Osc::create),
 		ModuleDefinition::new("sacn", "sACN", outputmodules::sacn::Sacn::create),
         ModuleDefinition::new("file", "File", outputmodules::file::File::create),
+        ModuleDefinition::new("midi", "MIDI", outputmodules::midi::Midi::create),
+        ModuleDefinition::new("hue", "Philips Hue", outputmodules::hue::Hue::create),
+        ModuleDefinition::new("gpio", "GPIO", outputmodules::gpio::Gpio::create),
         ModuleDefinition::new(
             "setlist",
             "Setlist",
             outputmodules::setlist::Setlist::create,
         ),
+        ModuleDefinition::new(
+            "telemetry",
+            "Telemetry",
+            outputmodules::telemetry::Telemetry::create,
+        ),
+        ModuleDefinition::new("udpjson", "UDP JSON", outputmodules::udpjson::UdpJson::create),
         ModuleDefinition::new(
             "display",
             "Live Display",
@@ -85,6 +190,20 @@ fn main() {
         ),
     ];
 
+    match source {
+        Source::Rekordbox => {}
+        Source::Replay(path) => {
+            set_ctrlc_handler(&applogger);
+            beatkeeper::replay(&path, modules, config, ScopedLogger::new(&logger, "Replay"));
+            return;
+        }
+        Source::Metronome { bpm, phrase_bars } => {
+            set_ctrlc_handler(&applogger);
+            beatkeeper::metronome(bpm, phrase_bars, modules, config, ScopedLogger::new(&logger, "Metronome"));
+            return;
+        }
+    }
+
     let mut update = config.get_or_default("app.auto_update", true);
     if !Path::new(OFFSETS_PATH).exists() {
         applogger.err("No offset file found, updating...");
@@ -92,7 +211,13 @@ fn main() {
     }
 
     let license = config.get_or_default::<String>("app.licensekey", "evaluation".to_string());
-    update_routine(&license, REPO, ScopedLogger::new(&logger, "Update"), update);
+    update_routine(
+        &license,
+        REPO,
+        ScopedLogger::new(&logger, "Update"),
+        update,
+        interactive,
+    );
 
     let offsets =
         match RekordboxOffsets::from_file(OFFSETS_PATH, ScopedLogger::new(&logger, "Parser")) {
@@ -100,7 +225,7 @@ fn main() {
             Err(e) => {
                 applogger.err(&format!("Failed to parse offsets: {e}"));
                 applogger.err("Enable debug in config for details");
-                enter_to_exit();
+                enter_to_exit(interactive);
                 return;
             }
         };
@@ -126,19 +251,77 @@ fn main() {
         applogger.err(&format!(
             "Offsets for Rekordbox version {selected_version} not available"
         ));
-        enter_to_exit();
+        enter_to_exit(interactive);
         return;
     };
 
+    // Tried in turn with the main offsets while reads keep failing, eg. after a layout change
+    let fallback_offset = match config.get_or_default("keeper.fallback_rekordbox_version", String::new()) {
+        version if version.is_empty() => None,
+        version => match offsets.get(&version) {
+            Some(offset) => {
+                applogger.info(&format!("Falling back to Rekordbox version {version} if reads keep failing"));
+                Some(offset.clone())
+            }
+            None => {
+                applogger.err(&format!("Offsets for fallback Rekordbox version {version} not available, continuing without"));
+                None
+            }
+        },
+    };
+
+    // Let the modules reset their outputs before exiting. The service control handler does the same in service mode
+    if interactive {
+        set_ctrlc_handler(&applogger);
+    }
+
     BeatKeeper::start(
         offset.clone(),
+        fallback_offset,
         modules,
         config,
         ScopedLogger::new(&logger, "BeatKeeper"),
+        events,
     );
 }
 
-fn update_routine(license: &str, repo: &str, logger: ScopedLogger, update_offsets: bool) {
+fn set_ctrlc_handler(logger: &ScopedLogger) {
+    if let Err(e) = ctrlc::set_handler(|| beatkeeper::KEEPER_CONTROL.request_stop()) {
+        logger.warn(&format!("Failed to set Ctrl+C handler: {e}"));
+    }
+}
+
+fn print_banner() {
+    println!();
+    println!("======================================================================");
+    println!();
+    println!("Rekordbox Link v{VERSION}");
+    println!("Updates          https://github.com/grufkork/rkbx_link/releases/latest");
+    println!("Get a license    https://3gg.se/products/rkbx_link");
+    println!("Repo and docs    https://github.com/grufkork/rkbx_link");
+    println!("Missing a feature? Spotted a bug? Just shoot me a message!");
+    println!();
+    println!("======================================================================");
+    println!();
+}
+
+fn create_logger(service: bool, debug: bool) -> Logger {
+    if service {
+        match Logger::with_file(debug, SERVICE_LOG_PATH) {
+            Ok(logger) => return logger,
+            Err(e) => eprintln!("Failed to open log file {SERVICE_LOG_PATH}: {e}"),
+        }
+    }
+    Logger::new(debug)
+}
+
+fn update_routine(
+    license: &str,
+    repo: &str,
+    logger: ScopedLogger,
+    update_offsets: bool,
+    interactive: bool,
+) {
     logger.info("Checking for updates...");
     // Exe update
     let new_exe_version = match get_git_file_http("version_exe", repo) {
@@ -214,6 +397,11 @@ fn update_routine(license: &str, repo: &str, logger: ScopedLogger, update_offset
         update_offsets = true;
     }
 
+    if update_offsets && !interactive {
+        logger.warn("Offset update available, run rkbx_link from a console to download it");
+        return;
+    }
+
     if update_offsets && y_n("Update offsets?") {
         // Offset update available
         logger.info("Downloading offsets...");
@@ -276,8 +464,11 @@ fn y_n(msg: &str) -> bool {
     matches!(input.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
-fn enter_to_exit() {
+fn enter_to_exit(interactive: bool) {
     use std::io::{self, Write};
+    if !interactive {
+        return;
+    }
     let mut input = String::new();
     print!("Press Enter to exit...");
     io::stdout().flush().unwrap();
@@ -65,8 +65,11 @@ pub struct MacMemory {
 }
 
 impl MacMemory {
-    pub fn new() -> Result<MacMemory, MemoryReadError>{
-        Ok(MacMemory::from_process_name("rekordbox").unwrap()) // FIX
+    pub fn new(pid: Option<u32>) -> Result<MacMemory, MemoryReadError>{
+        Ok(match pid {
+            Some(pid) => MacMemory::from_pid(pid as Pid),
+            None => MacMemory::from_process_name("rekordbox"),
+        }.unwrap()) // FIX
     }
 
     /// Find a process by name and get its handle
@@ -96,6 +99,12 @@ impl MacMemory {
         let pid = process.pid().as_u32() as Pid;
         eprintln!("Found Rekordbox process with PID: {}", pid);
 
+        MacMemory::from_pid(pid)
+    }
+
+    /// Get the handle of a specific process
+    pub fn from_pid(pid: Pid) -> Result<Self, MemoryError> {
+
         // Get task port for the process using task_for_pid
         let mut task: MachPort = 0;
         let result = unsafe {
@@ -205,7 +214,7 @@ impl MemBackend for MacMemory{
         };
 
         if result != 0 {
-            return Err(MemoryReadError { pointer: None, address, detail: Some(format!("mach error: {result}")), error_type: MemoryReadErrorType::ReadMemoryFailed })
+            return Err(MemoryReadError { pointer: None, label: None, address, detail: Some(format!("mach error: {result}")), error_type: MemoryReadErrorType::ReadMemoryFailed })
             
             // return Err(MemoryError::ReadFailed(format!(
             //             "address: 0x{:X}, mach error: {}",
@@ -1,7 +1,9 @@
+use std::cell::Cell;
 use std::marker::PhantomData;
 use core::fmt;
 
 use crate::log::ScopedLogger;
+use sysinfo::{ProcessesToUpdate, System};
 
 #[cfg(target_os = "windows")]
 pub mod windows_memory;
@@ -13,6 +15,50 @@ pub mod macos_memory;
 #[cfg(target_os = "macos")]
 pub type ActiveBackend = macos_memory::MacMemory;
 
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub mod unsupported_memory;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub type ActiveBackend = unsupported_memory::UnsupportedMem;
+
+#[cfg(target_os = "windows")]
+pub const PROCESS_NAME: &str = "rekordbox.exe";
+#[cfg(not(target_os = "windows"))]
+pub const PROCESS_NAME: &str = "rekordbox";
+
+/// Pick the Rekordbox process to attach to. A configured PID must belong to a running Rekordbox,
+/// without one None lets the backend take the first Rekordbox it finds
+pub fn resolve_pid(pid: Option<u32>, logger: &ScopedLogger) -> Result<Option<u32>, MemoryReadError> {
+    let mut sys = System::new();
+    sys.refresh_processes(ProcessesToUpdate::All, true);
+    let mut pids: Vec<u32> = sys
+        .processes()
+        .values()
+        .filter(|p| p.name().to_str().is_some_and(|name| name.eq_ignore_ascii_case(PROCESS_NAME)))
+        .map(|p| p.pid().as_u32())
+        .collect();
+    pids.sort();
+    for p in &pids {
+        logger.debug(&format!("Found {PROCESS_NAME} with PID {p}"));
+    }
+
+    match pid {
+        Some(pid) if pids.contains(&pid) => Ok(Some(pid)),
+        Some(pid) => Err(MemoryReadError {
+            pointer: None,
+            label: None,
+            address: 0,
+            detail: Some(format!("no {PROCESS_NAME} with PID {pid}, running: {pids:?}")),
+            error_type: MemoryReadErrorType::ProcessNotFound,
+        }),
+        None => {
+            if pids.len() > 1 {
+                logger.warn(&format!("Several Rekordbox processes running {pids:?}, attaching to the first found. Set keeper.process_pid to choose"));
+            }
+            Ok(None)
+        }
+    }
+}
+
 pub trait MemBackend{
     // fn new() -> Result<Box<dyn MemBackend>, String>;
     fn get_base_offset(&self) -> usize;
@@ -25,28 +71,40 @@ pub struct MemReader{
 }
 
 impl MemReader{
-    pub fn new() -> Result<Self, MemoryReadError>{
-        let backend = ActiveBackend::new()?;
+    /// Attach to Rekordbox, by PID if given or else by process name
+    pub fn new(pid: Option<u32>) -> Result<Self, MemoryReadError>{
+        let backend = ActiveBackend::new(pid)?;
         Ok(MemReader { base: backend.get_base_offset(), backend })
     }
 
-    pub fn new_value<T>(&self, offsets: &Pointer) -> Result<Value<T>, MemoryReadError>{
-        Value::new(self, offsets)
+    /// `label` names the value in errors, eg. "current_bpm"
+    pub fn new_value<T>(&self, offsets: &Pointer, label: &str) -> Result<Value<T>, MemoryReadError>{
+        Value::new(self, offsets, label)
     } 
-    pub fn new_values<T>(&self, pointers: &[Pointer]) -> Result<Vec<Value<T>>, MemoryReadError> {
-        pointers.iter().map(|x| self.new_value(x)).collect()
+    /// One value per deck, labeled with the deck number
+    pub fn new_values<T>(&self, pointers: &[Pointer], label: &str) -> Result<Vec<Value<T>>, MemoryReadError> {
+        pointers
+            .iter()
+            .enumerate()
+            .map(|(i, x)| self.new_value(x, &format!("{label} for deck {i}")))
+            .collect()
     }
 
-    pub fn new_pointerchain_value<T>(&self, offsets: Pointer) -> PointerChainValue<T>{
-        PointerChainValue::new(self, offsets)
+    /// `size_key` is the config key for `max_len`, to point to it when a string was cut off
+    pub fn new_pointerchain_string(&self, offsets: Pointer, label: &str, max_len: usize, size_key: &'static str) -> PointerChainString {
+        PointerChainString::new(offsets, label, max_len, size_key)
     }
-    pub fn new_pointerchain_values<T>(
+    pub fn new_pointerchain_strings(
         &self,
         pointers: &[Pointer],
-    ) -> Vec<PointerChainValue<T>> {
+        label: &str,
+        max_len: usize,
+        size_key: &'static str,
+    ) -> Vec<PointerChainString> {
         pointers
             .iter()
-            .map(|x| self.new_pointerchain_value(x.clone()))
+            .enumerate()
+            .map(|(i, x)| self.new_pointerchain_string(x.clone(), &format!("{label} for deck {i}"), max_len, size_key))
             .collect()
     }
 
@@ -59,17 +117,19 @@ impl MemReader{
 
 pub struct Value<T> {
     address: usize,
+    label: String,
     _marker: PhantomData<T>,
 }
 
 impl<T> Value<T> {
-    fn new(mem: &MemReader, pointer: &Pointer) -> Result<Value<T>, MemoryReadError> {
+    fn new(mem: &MemReader, pointer: &Pointer, label: &str) -> Result<Value<T>, MemoryReadError> {
         let mut address = mem.base;
 
         for offset in &pointer.offsets {
             address = mem.read::<usize>(address + offset).map_err(
                 |mut e|{
                     e.pointer = Some(pointer.clone());
+                    e.label = Some(label.to_string());
                     e
                 }
             )?
@@ -78,35 +138,80 @@ impl<T> Value<T> {
 
         Ok(Value::<T> {
             address,
+            label: label.to_string(),
             _marker: PhantomData::<T>,
         })
     }
 
     pub fn read(&self, mem: &MemReader) -> Result<T, MemoryReadError> {
-        mem.read::<T>(self.address)
+        mem.read::<T>(self.address).map_err(|mut e| {
+            e.label = Some(self.label.clone());
+            e
+        })
     }
 }
 
 
 
 
-pub struct PointerChainValue<T> {
+/// Bytes read at a time while looking for a string's null terminator
+const STRING_CHUNK: usize = 64;
+
+/// A null-terminated string behind a pointer chain, read in chunks until the terminator or
+/// `max_len` bytes
+pub struct PointerChainString {
     pointer: Pointer,
-    _marker: PhantomData<T>,
+    label: String,
+    max_len: usize,
+    size_key: &'static str,
+    truncation_reported: Cell<bool>,
 }
 
-impl<T> PointerChainValue<T> {
-    fn new(_mem: &MemReader, pointer: Pointer) -> PointerChainValue<T> {
+impl PointerChainString {
+    fn new(pointer: Pointer, label: &str, max_len: usize, size_key: &'static str) -> Self {
         Self {
             pointer,
-            _marker: PhantomData::<T>,
+            label: label.to_string(),
+            max_len: max_len.max(1),
+            size_key,
+            truncation_reported: Cell::new(false),
         }
     }
 
+    /// The bytes before the null terminator. A string without a terminator within `max_len` was
+    /// cut off, which is warned about once, and a character cut in half is dropped
+    pub fn read(&self, mem: &MemReader, logger: &ScopedLogger) -> Result<Vec<u8>, MemoryReadError> {
+        let start = Value::<u8>::new(mem, &self.pointer, &self.label)?.address;
+        let mut bytes = Vec::with_capacity(STRING_CHUNK);
+        while bytes.len() < self.max_len {
+            let chunk = mem.read::<[u8; STRING_CHUNK]>(start + bytes.len()).map_err(|mut e| {
+                e.label = Some(self.label.clone());
+                e
+            })?;
+            if let Some(end) = chunk.iter().position(|x| *x == 0x00) {
+                bytes.extend_from_slice(&chunk[..end]);
+                if bytes.len() <= self.max_len {
+                    return Ok(bytes);
+                }
+                break;
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        bytes.truncate(self.max_len);
 
-
-    pub fn read(&self, mem: &MemReader) -> Result<T, MemoryReadError> {
-        Value::<T>::new(mem, &self.pointer)?.read(mem)
+        if !self.truncation_reported.replace(true) {
+            logger.warn(&format!(
+                "{} is longer than {} bytes and was cut off, increase {}",
+                self.label, self.max_len, self.size_key
+            ));
+        }
+        if let Err(e) = std::str::from_utf8(&bytes) {
+            // Only an incomplete character at the end, other invalid text is left as is
+            if e.error_len().is_none() {
+                bytes.truncate(e.valid_up_to());
+            }
+        }
+        Ok(bytes)
     }
 }
 
@@ -123,6 +228,7 @@ pub enum MemoryReadErrorType{
 #[derive(PartialEq, Clone)]
 pub struct MemoryReadError {
     pub pointer: Option<Pointer>,
+    pub label: Option<String>, // Name of the value being read, eg. "current_bpm for deck 2"
     pub address: usize,
     pub detail: Option<String>,
     pub error_type: MemoryReadErrorType,
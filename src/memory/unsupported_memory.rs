@@ -0,0 +1,32 @@
+use crate::memory::{MemBackend, MemoryReadError, MemoryReadErrorType};
+
+/// Rekordbox only runs on Windows and macOS, so elsewhere, eg. on a Raspberry Pi, decks are read
+/// with keeper.source_backend udp instead
+#[allow(dead_code)] // Never created, as new always fails
+pub struct UnsupportedMem;
+
+impl UnsupportedMem {
+    pub fn new(_pid: Option<u32>) -> Result<Self, MemoryReadError> {
+        Err(error())
+    }
+}
+
+impl MemBackend for UnsupportedMem {
+    fn read<T>(&self, _address: usize) -> Result<T, MemoryReadError> {
+        Err(error())
+    }
+
+    fn get_base_offset(&self) -> usize {
+        0
+    }
+}
+
+fn error() -> MemoryReadError {
+    MemoryReadError {
+        pointer: None,
+        label: None,
+        address: 0,
+        detail: Some("Rekordbox's memory can only be read on Windows and macOS, set keeper.source_backend udp".to_string()),
+        error_type: MemoryReadErrorType::ProcessNotFound,
+    }
+}
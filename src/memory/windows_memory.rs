@@ -5,6 +5,9 @@ use crate::memory::Pointer;
 use toy_arms::external::error::TAExternalError;
 use toy_arms::external::{read, Process};
 use winapi::ctypes::c_void;
+use winapi::shared::minwindef::FALSE;
+use winapi::um::processthreadsapi::OpenProcess;
+use winapi::um::winnt::PROCESS_ALL_ACCESS;
 
 pub struct WindowsMem {
     process_handle: *mut c_void,
@@ -13,8 +16,8 @@ pub struct WindowsMem {
 }
 
 impl WindowsMem{
-    pub fn new() -> Result<Self, MemoryReadError> {
-        let rb = match Process::from_process_name("rekordbox.exe") {
+    pub fn new(pid: Option<u32>) -> Result<Self, MemoryReadError> {
+        let rb = match pid.map_or_else(|| Process::from_process_name("rekordbox.exe"), WindowsMem::open_process) {
             Ok(p) => p,
             Err(e) => {
                 return Err(WindowsMem::convert_error(None, 0, e))
@@ -37,6 +40,19 @@ impl WindowsMem{
         })
     }
 
+    /// Like Process::from_process_name, for a specific Rekordbox instance
+    fn open_process(pid: u32) -> Result<Process<'static>, TAExternalError> {
+        let process_handle = unsafe { OpenProcess(PROCESS_ALL_ACCESS, FALSE, pid) };
+        if process_handle.is_null() {
+            return Err(TAExternalError::ProcessNotFound);
+        }
+        Ok(Process {
+            process_name: "rekordbox.exe",
+            process_id: pid,
+            process_handle,
+        })
+    }
+
     fn convert_error(pointer: Option<Pointer>, address: usize, e: TAExternalError) -> MemoryReadError{
         let (detail, error_type) = match e{
             TAExternalError::SnapshotFailed(snapshot_failed_detail) => (Some(snapshot_failed_detail.to_string()), MemoryReadErrorType::SnapshotFailed),
@@ -46,7 +62,7 @@ impl WindowsMem{
             TAExternalError::WriteMemoryFailed(read_write_memory_failed_detail) => (Some(read_write_memory_failed_detail.to_string()), MemoryReadErrorType::WriteMemoryFailed),
         };
 
-        MemoryReadError { pointer, address, detail, error_type }
+        MemoryReadError { pointer, label: None, address, detail, error_type }
     }
 }
 
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fs::File, io::Read};
+use std::{collections::HashMap, fs::File, io::Read, iter::Peekable, slice::Iter};
 use crate::memory::Pointer;
 
 use crate::log::ScopedLogger;
@@ -15,10 +15,29 @@ impl RekordboxOffsets {
             logger,
         )?;
 
+        let mut deck_layout = None;
+        let mut crossfader = None;
+        let mut preview_track = None;
+
+        // Optional pointers are given by name, eg. "deck_layout: 05737C48 20 278 130"
+        while let Some((name, pointer)) = Self::next_named(&mut rows, logger)? {
+            match name {
+                "deck_layout" => deck_layout = Some(pointer),
+                "crossfader" => crossfader = Some(pointer),
+                "preview_track" => preview_track = Some(pointer),
+                _ => logger.warn(&format!("Unknown optional pointer '{name}' for version {rb_version}, ignoring")),
+            }
+        }
+
         let mut sample_position = vec![];
         let mut current_bpm = vec![];
         let mut track_info = vec![];
         let mut anlz_path = vec![];
+        let mut artwork_path = vec![];
+        let mut channel_fader = vec![];
+        let mut cue_monitor = vec![];
+        let mut slip_active = vec![];
+        let mut loop_roll = vec![];
 
         while rows.peek().is_some() {
             logger.debug("Current BPM");
@@ -41,6 +60,18 @@ impl RekordboxOffsets {
                 rows.next().ok_or("Missing ANLZ path pointer")?,
                 logger,
             )?);
+
+            // Optional per-deck pointers follow the required ones
+            while let Some((name, pointer)) = Self::next_named(&mut rows, logger)? {
+                match name {
+                    "artwork_path" => artwork_path.push(pointer),
+                    "channel_fader" => channel_fader.push(pointer),
+                    "cue_monitor" => cue_monitor.push(pointer),
+                    "slip_active" => slip_active.push(pointer),
+                    "loop_roll" => loop_roll.push(pointer),
+                    _ => logger.warn(&format!("Unknown optional pointer '{name}' for version {rb_version}, ignoring")),
+                }
+            }
         }
 
         Ok(RekordboxOffsets {
@@ -48,11 +79,28 @@ impl RekordboxOffsets {
             sample_position,
             current_bpm,
             masterdeck_index,
+            deck_layout,
+            crossfader,
+            preview_track,
             track_info,
             anlz_path,
+            artwork_path,
+            channel_fader,
+            cue_monitor,
+            slip_active,
+            loop_roll,
         })
     }
 
+    fn next_named<'a>(rows: &mut Peekable<Iter<'a, String>>, logger: &ScopedLogger) -> Result<Option<(&'a str, Pointer)>, String> {
+        let Some((name, pointer)) = rows.peek().copied().and_then(|x| x.split_once(": ")) else {
+            return Ok(None);
+        };
+        rows.next();
+        logger.debug(name);
+        Ok(Some((name, Pointer::from_string(pointer, logger)?)))
+    }
+
     pub fn from_file(
         name: &str,
         logger: ScopedLogger,
@@ -95,10 +143,18 @@ impl RekordboxOffsets {
 pub struct RekordboxOffsets {
     pub rbversion: String,
     pub masterdeck_index: Pointer,
+    pub deck_layout: Option<Pointer>,
+    pub crossfader: Option<Pointer>, // Mixer crossfader, -1 to 1
+    pub preview_track: Option<Pointer>, // Track selected in the browser, same layout as track_info
     pub sample_position: Vec<Pointer>,
     pub current_bpm: Vec<Pointer>,
     pub track_info: Vec<Pointer>,
     pub anlz_path: Vec<Pointer>,
+    pub artwork_path: Vec<Pointer>, // Optional, empty if not available
+    pub channel_fader: Vec<Pointer>, // Optional, 0 to 1, empty if not available
+    pub cue_monitor: Vec<Pointer>, // Optional, headphone cue (PFL) on when non-zero, empty if not available
+    pub slip_active: Vec<Pointer>, // Optional, slip mode on when non-zero, empty if not available
+    pub loop_roll: Vec<Pointer>, // Optional, a loop roll held when non-zero, empty if not available
 }
 
 
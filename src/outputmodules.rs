@@ -1,13 +1,19 @@
-use crate::beatkeeper::TrackInfo;
+use crate::beatkeeper::{AnalysisStatus, MasterChangeReason, PerfState, TrackInfo};
 use crate::config::Config;
 use crate::log::ScopedLogger;
+use std::time::{Duration, Instant, SystemTime};
 
 pub mod abletonlink;
 pub mod display;
 pub mod file;
+pub mod gpio;
+pub mod hue;
+pub mod midi;
 pub mod osc;
 pub mod setlist;
 pub mod sacn;
+pub mod telemetry;
+pub mod udpjson;
 
 pub trait OutputModule {
 
@@ -19,18 +25,56 @@ pub trait OutputModule {
     fn original_bpm_changed(&mut self, _bpm: f32, _deck: usize) {}
     fn original_bpm_changed_master(&mut self, _bpm: f32) {}
 
+    fn bpm_offset_changed(&mut self, _offset: f32, _deck: usize) {} // Deck BPM minus master BPM
+
     fn beat_update(&mut self, _beat: f32, _deck: usize) {}
+    fn beat_timestamp_master(&mut self, _timestamp: SystemTime) {} // Wall-clock time the following beat_update_master corresponds to, including delay compensation
     fn beat_update_master(&mut self, _beat: f32) {}
+    fn absolute_beat_update_master(&mut self, _beat: f32) {} // Beats since the first beat of the grid, counting across bars. Follows beat_update_master
+
+    fn beat_valid_changed(&mut self, _valid: bool, _deck: usize) {} // False when there is no beatgrid and the beat is a fallback
+    fn beat_valid_changed_master(&mut self, _valid: bool) {}
+
+    fn bar_length_changed(&mut self, _beats_per_bar: u32, _deck: usize) {} // Beats per bar detected from the beatgrid, 4 if unknown. Beat values run from 0 to this
+    fn bar_length_changed_master(&mut self, _beats_per_bar: u32) {}
+
+    fn bar_countdown_changed(&mut self, _beats: i32, _deck: usize) {} // Beats left in the bar including the current one, eg. 4, 3, 2, 1 in 4/4
+    fn bar_countdown_changed_master(&mut self, _beats: i32) {}
+    fn sixteenth_changed(&mut self, _step: i32, _deck: usize) {} // Step within the bar from 0 at the downbeat, in 16th notes or keeper.step_division, eg. 0 to 15 in 4/4
+    fn sixteenth_changed_master(&mut self, _step: i32) {}
+
+    fn seek_detected(&mut self, _deck: usize) {} // The position jumped, eg. a needle drop. Called before the beat update of the new position
+
+    fn phase_offset_changed(&mut self, _offset: f32, _deck: usize) {} // Beat phase relative to master, -0.5 to 0.5
 
     fn time_update(&mut self, _time: f32, _deck: usize) {}
     fn time_update_master(&mut self, _time: f32) {}
 
     fn track_changed(&mut self, _track: &TrackInfo, _deck: usize) {}
     fn track_changed_master(&mut self, _track: &TrackInfo) {}
+    fn preview_track_changed(&mut self, _track: &TrackInfo) {} // Track selected in Rekordbox's browser, if the offsets support it
+
+    fn track_change_reason_master(&mut self, _reason: MasterChangeReason) {} // Called right before track_changed_master
+
+    fn artwork_changed(&mut self, _path: &str, _deck: usize) {} // Path to the artwork in Rekordbox's cache, if the offsets support it
+    fn artwork_changed_master(&mut self, _path: &str) {}
+
+    fn analysis_status_changed(&mut self, _status: AnalysisStatus, _deck: usize) {} // Beat and phrase values are unreliable unless Ready
+
+    fn grid_origin_changed(&mut self, _seconds: f32, _deck: usize) {} // Track time of the first beat in the beatgrid, once per track load
+
+    fn song_structure_loaded(&mut self, _phrases: &[(u16, String)], _deck: usize) {} // Start beat and name of every phrase, once per track load
 
     fn anlz_path_changed(&mut self, _path: &str, _deck: usize) {} // Allow modules to receive ANLZ/EXT path updates per deck
 
     fn masterdeck_index_changed(&mut self, _index: usize) {} // Allow modules to receive master deck index changes
+    fn master_changed(&mut self, _index: usize, _track: &TrackInfo) {} // Another deck became master, with the track on it. Once per switch, after masterdeck_index_changed
+
+    fn play_state_changed(&mut self, _playing: bool, _deck: usize) {}
+    fn on_air_changed(&mut self, _on_air: bool, _deck: usize) {} // Playing and audible through the channel fader and crossfader
+    fn cue_monitor_changed(&mut self, _cue: bool, _deck: usize) {} // Headphone cue (PFL) switched, if the offsets support it
+    fn performance_state_changed(&mut self, _state: PerfState, _deck: usize) {} // Slip mode or a loop roll, if the offsets support it
+    fn idle_changed(&mut self, _idle: bool) {} // Master deck stopped, only with keeper.suppress_when_idle
 
     fn phrase_changed(&mut self, _phrase: &str, _deck: usize) {}
     fn phrase_changed_master(&mut self, _phrase: &str) {}
@@ -41,9 +85,27 @@ pub trait OutputModule {
     fn next_phrase_in(&mut self, _beats: i32, _deck: usize) {}
     fn next_phrase_in_master(&mut self, _beats: i32) {}
 
+    fn mood_changed(&mut self, _mood: u8, _deck: usize) {} // Phrase bank, 1 = Low, 2 = Mid, 3 = High, 0 = no phrase analysis
+    fn mood_changed_master(&mut self, _mood: u8) {}
+
+    fn energy_changed(&mut self, _energy: f32) {} // 0-1, derived from the master phrase and BPM
+
+    fn crossfader_changed(&mut self, _position: f32) {} // -1 fully left to 1 fully right, if the offsets support it
+
+    fn end_warning(&mut self, _ending: bool) {} // Master track is within keeper.end_warning_seconds of its end, false again for the next track
+
+    fn connection_changed(&mut self, _connected: bool) {} // Rekordbox was found or lost
+    fn reconnected(&mut self) {} // Rekordbox was found again after the connection was lost, right after connection_changed(true)
+    fn shutdown(&mut self) {} // The keeper is stopping, eg. on Ctrl+C
+    fn idle_heartbeat(&mut self) {} // Every keeper.idle_heartbeat_ms, whether Rekordbox is connected or not
+    fn failed(&self) -> bool { false } // Keeps failing, eg. its device is gone. With keeper.watchdog the module is recreated
+    fn stats(&self) -> Option<String> { None } // Short summary for the keeper.stats_line status line, eg. "1200 sent"
+
     fn slow_update(&mut self) {}
+    fn very_slow_update(&mut self) {} // Every keeper.very_slow_update_every_nth update, right after slow_update
 }
 
+#[derive(Clone)]
 pub struct ModuleDefinition {
     pub config_name: String,
     pub pretty_name: String,
@@ -65,3 +127,71 @@ impl ModuleDefinition {
 }
 
 pub type ModuleCreateOutput = Result<Box<dyn OutputModule>, ()>;
+
+/// Schedules the "off" of a trigger, for receivers that latch on the value and need an explicit
+/// release, eg. Note-Off after a Note-On
+pub struct Pulse {
+    width: Duration,
+    started: Option<Instant>,
+}
+
+impl Pulse {
+    pub fn new(width: Duration) -> Self {
+        Self { width, started: None }
+    }
+
+    /// Call when the "on" was sent
+    pub fn start(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    /// True once, when the "off" is due. Check every update, eg. in pre_update
+    pub fn off_due(&mut self) -> bool {
+        if self.started.is_some_and(|t| t.elapsed() >= self.width) {
+            self.started = None;
+            return true;
+        }
+        false
+    }
+}
+
+/// What a module outputs when the connection to Rekordbox is lost or rkbx_link stops, from
+/// <module>.on_disconnect
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DisconnectPolicy {
+    Hold,  // Keep the last values
+    Zero,  // Zero the beat and time values
+    Reset, // Return every value to neutral, including the track and phrase
+}
+
+impl DisconnectPolicy {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "hold" => Some(DisconnectPolicy::Hold),
+            "zero" => Some(DisconnectPolicy::Zero),
+            "reset" => Some(DisconnectPolicy::Reset),
+            _ => None,
+        }
+    }
+
+    /// Read on_disconnect from the module's config, `default` if it's empty or unknown
+    pub fn from_config(conf: &Config, default: Self, logger: &ScopedLogger) -> Self {
+        let policy = conf.get_or_default("on_disconnect", String::new());
+        if policy.is_empty() {
+            return default;
+        }
+        Self::from_str(&policy).unwrap_or_else(|| {
+            logger.err(&format!("Unknown on_disconnect policy: {policy}"));
+            default
+        })
+    }
+}
+
+/// A local socket for tests to receive a module's datagrams on, and its address
+#[cfg(test)]
+pub fn test_receiver() -> (std::net::UdpSocket, String) {
+    let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let address = receiver.local_addr().unwrap().to_string();
+    (receiver, address)
+}
@@ -1,6 +1,6 @@
 use rusty_link::{AblLink, SessionState};
 
-use crate::{config::Config, log::ScopedLogger, outputmodules::OutputModule};
+use crate::{beatkeeper::MasterChangeReason, config::Config, log::ScopedLogger, outputmodules::OutputModule};
 
 use super::ModuleCreateOutput;
 
@@ -12,8 +12,16 @@ pub struct AbletonLink {
     last_beat: f32,
     cumulative_error: f32,
     cumulative_error_tolerance: f32,
+    quantum: f64,
+    phase_correction_gain: f32, // 0 disables the phase-lock loop
+    snap_threshold: f32,        // Beats
+    snap_pending: bool,
+    masterdeck_index: usize,
 }
 
+/// Errors smaller than this, in beats, are left alone instead of recommitting the session
+const PHASE_DEADBAND: f32 = 0.0005;
+
 impl AbletonLink {
     pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
         let link = AblLink::new(120.);
@@ -32,10 +40,34 @@ impl AbletonLink {
             last_beat: 0.,
             cumulative_error: 0.0,
             cumulative_error_tolerance: conf.get_or_default("cumulative_error_tolerance", 0.05),
+            quantum: 4.,
+            phase_correction_gain: conf.get_or_default("phase_correction_gain", 0.0f32).clamp(0., 1.),
+            snap_threshold: conf.get_or_default("snap_threshold", 0.25),
+            snap_pending: true,
+            masterdeck_index: 0,
         }))
     }
 }
 
+impl AbletonLink {
+    /// Pulls Link's beat a fraction of the way towards Rekordbox's every update instead of
+    /// hard-setting it, so the per-tick jitter of the read position is smoothed out. Snaps
+    /// directly on seeks, track changes and errors larger than the snap threshold.
+    fn lock_phase(&mut self, beat: f32, diff: f32) {
+        let target = if self.snap_pending || diff.abs() > self.snap_threshold {
+            beat
+        } else if diff.abs() < PHASE_DEADBAND {
+            return;
+        } else {
+            beat + diff * (1. - self.phase_correction_gain)
+        };
+        self.snap_pending = false;
+        self.state
+            .force_beat_at_time(target.into(), self.link.clock_micros(), self.quantum);
+        self.link.commit_app_session_state(&self.state);
+    }
+}
+
 impl OutputModule for AbletonLink {
     fn bpm_changed_master(&mut self, bpm: f32) {
         self.state.set_tempo(bpm as f64, self.link.clock_micros());
@@ -49,21 +81,47 @@ impl OutputModule for AbletonLink {
         }
         // let target_beat = (beat as f64) % 4.;
 
-        let link_beat = self.state.beat_at_time(self.link.clock_micros(), 4.0) as f32;
-        let diff = (link_beat - beat + 2.0) % 4.0 - 2.0;
+        let quantum = self.quantum as f32;
+        let link_beat = self.state.beat_at_time(self.link.clock_micros(), self.quantum) as f32;
+        let diff = (link_beat - beat + quantum / 2.) % quantum - quantum / 2.;
         // println!("{diff}");
+        if self.phase_correction_gain > 0. {
+            self.lock_phase(beat, diff);
+            self.last_beat = beat;
+            return;
+        }
         self.cumulative_error += diff;
         // println!("cumerr {}", self.cumulative_error);
         if self.cumulative_error.abs() > self.cumulative_error_tolerance {
             self.cumulative_error = 0.0;
             // println!("SET -----------------------------------------------------");
             self.state
-                .force_beat_at_time(beat.into(), self.link.clock_micros() as i64, 4.);
+                .force_beat_at_time(beat.into(), self.link.clock_micros(), self.quantum);
             self.link.commit_app_session_state(&self.state);
         }
         self.last_beat = beat;
     }
 
+    fn seek_detected(&mut self, deck: usize) {
+        if deck == self.masterdeck_index {
+            self.snap_pending = true;
+        }
+    }
+
+    fn masterdeck_index_changed(&mut self, index: usize) {
+        self.masterdeck_index = index;
+    }
+
+    fn track_change_reason_master(&mut self, reason: MasterChangeReason) {
+        if reason != MasterChangeReason::Refresh {
+            self.snap_pending = true;
+        }
+    }
+
+    fn bar_length_changed_master(&mut self, beats_per_bar: u32) {
+        self.quantum = beats_per_bar as f64;
+    }
+
     fn slow_update(&mut self) {
         let num_links = self.link.num_peers();
         if num_links != self.last_num_links {
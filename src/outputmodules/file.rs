@@ -1,29 +1,116 @@
 use std::fs;
 
-use crate::{config::Config, log::ScopedLogger};
+use crate::{beatkeeper::TrackInfo, config::Config, log::ScopedLogger};
 
 use super::{ModuleCreateOutput, OutputModule};
 
+struct FileOutput {
+    path: String,
+    template: String,
+    last_contents: Option<String>,
+}
+
 pub struct File {
-    filename: String,
+    outputs: Vec<FileOutput>,
+    track: TrackInfo,
+    bpm: f32,
     logger: ScopedLogger,
 }
 
 impl File {
     pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let mut outputs = Self::parse_outputs(&conf);
+        if outputs.is_empty() {
+            outputs.push(FileOutput {
+                path: conf.get_or_default("filename", "current_track.txt".to_string()),
+                template: "{title}\n{artist}\n{album}".to_string(),
+                last_contents: None,
+            });
+        }
+
         Ok(Box::new(File {
-            filename: conf.get_or_default("filename", "current_track.txt".to_string()),
+            outputs,
+            track: TrackInfo::default(),
+            bpm: 0.,
             logger,
         }))
     }
+
+    /// One output per `output.<path> <template>` entry, eg. `file.output.now.txt {artist}, {title}`
+    fn parse_outputs(conf: &Config) -> Vec<FileOutput> {
+        conf.entries_with_prefix("output.")
+            .into_iter()
+            .filter(|(_, template)| !template.trim().is_empty())
+            .map(|(path, template)| FileOutput {
+                path,
+                template: template.trim().replace("\\n", "\n"),
+                last_contents: None,
+            })
+            .collect()
+    }
+
+    fn render(&self, template: &str) -> String {
+        template
+            .replace("{title}", &self.track.title)
+            .replace("{artist}", &self.track.artist)
+            .replace("{album}", &self.track.album)
+            .replace("{genre}", &self.track.genre)
+            .replace("{comment}", &self.track.comment)
+            .replace("{bpm}", &format!("{:.1}", self.bpm))
+    }
+
+    /// Rewrites every output whose rendered contents changed
+    fn write_outputs(&mut self) {
+        for i in 0..self.outputs.len() {
+            let contents = self.render(&self.outputs[i].template);
+            if self.outputs[i].last_contents.as_ref() == Some(&contents) {
+                continue;
+            }
+            if let Err(e) = write_atomic(&self.outputs[i].path, &contents) {
+                self.logger.err(&format!("Failed to write to file {}: {e}", self.outputs[i].path));
+                continue;
+            }
+            self.outputs[i].last_contents = Some(contents);
+        }
+    }
 }
+
+/// Write to a temporary file and rename it, so readers never see a half-written file
+fn write_atomic(path: &str, contents: &str) -> std::io::Result<()> {
+    let tmp = format!("{path}.tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
 impl OutputModule for File {
-    fn track_changed_master(&mut self, track: &crate::beatkeeper::TrackInfo) {
-        if let Err(e) = fs::write(
-            &self.filename,
-            format!("{}\n{}\n{}", track.title, track.artist, track.album),
-        ) {
-            self.logger.err(&format!("Failed to write to file: {e}"));
-        }
+    fn track_changed_master(&mut self, track: &TrackInfo) {
+        self.track = track.clone();
+        self.write_outputs();
+    }
+
+    fn bpm_changed_master(&mut self, bpm: f32) {
+        self.bpm = bpm;
+        self.write_outputs();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_may_contain_commas() {
+        let conf = Config::from_entries(&[
+            ("file.output.now.txt", "{artist}, {title}"),
+            ("file.output.bpm.txt", "{bpm}\\nBPM"),
+            ("file.output.empty.txt", ""),
+            ("file.filename", "current_track.txt"),
+        ])
+        .reduce_to_namespace("file");
+        let outputs: Vec<(String, String)> = File::parse_outputs(&conf).into_iter().map(|output| (output.path, output.template)).collect();
+        assert_eq!(
+            outputs,
+            [("bpm.txt".to_string(), "{bpm}\nBPM".to_string()), ("now.txt".to_string(), "{artist}, {title}".to_string())]
+        );
     }
 }
@@ -0,0 +1,126 @@
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+#[cfg(target_os = "linux")]
+use rppal::gpio::OutputPin;
+
+use crate::{config::Config, log::ScopedLogger};
+
+#[cfg(target_os = "linux")]
+use super::Pulse;
+use super::{ModuleCreateOutput, OutputModule};
+
+/// GPIO output module, eg. for status LEDs on a Raspberry Pi
+///
+/// Holds one pin high while Rekordbox is connected, and pulses another on every master downbeat.
+#[cfg(target_os = "linux")]
+pub struct Gpio {
+    status_pin: Option<OutputPin>,
+    beat_pin: Option<OutputPin>,
+    beat_pulse: Pulse,
+    last_beat: f32,
+}
+
+#[cfg(target_os = "linux")]
+impl Gpio {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let gpio = match rppal::gpio::Gpio::new() {
+            Ok(g) => g,
+            Err(e) => {
+                logger.err(&format!("Failed to access GPIO: {e}"));
+                return Err(());
+            }
+        };
+
+        // BCM pin numbers, empty to disable
+        let open_pin = |key: &str| -> Result<Option<OutputPin>, ()> {
+            let Ok(number) = conf.get_or_default(key, String::new()).parse::<u8>() else {
+                return Ok(None);
+            };
+            match gpio.get(number) {
+                Ok(pin) => {
+                    logger.info(&format!("Using GPIO {number} as {key}"));
+                    Ok(Some(pin.into_output_low()))
+                }
+                Err(e) => {
+                    logger.err(&format!("Failed to open GPIO {number} for {key}: {e}"));
+                    Err(())
+                }
+            }
+        };
+        let status_pin = open_pin("status_pin")?;
+        let beat_pin = open_pin("beat_pin")?;
+        if status_pin.is_none() && beat_pin.is_none() {
+            logger.err("Neither gpio.status_pin nor gpio.beat_pin is set");
+            return Err(());
+        }
+
+        Ok(Box::new(Gpio {
+            status_pin,
+            beat_pin,
+            beat_pulse: Pulse::new(Duration::from_millis(conf.get_or_default("pulse_width_ms", 100))),
+            last_beat: 0.,
+        }))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl OutputModule for Gpio {
+    fn pre_update(&mut self) {
+        // Also while the master beat isn't sent, eg. when muted or idle
+        if self.beat_pulse.off_due() {
+            if let Some(pin) = &mut self.beat_pin {
+                pin.set_low();
+            }
+        }
+    }
+
+    fn connection_changed(&mut self, connected: bool) {
+        if let Some(pin) = &mut self.status_pin {
+            if connected {
+                pin.set_high();
+            } else {
+                pin.set_low();
+            }
+        }
+        // No more updates to end a running pulse
+        if !connected {
+            if let Some(pin) = &mut self.beat_pin {
+                pin.set_low();
+            }
+        }
+    }
+
+    fn beat_update_master(&mut self, beat: f32) {
+        if beat < self.last_beat {
+            // New bar
+            if let Some(pin) = &mut self.beat_pin {
+                pin.set_high();
+                self.beat_pulse.start();
+            }
+        }
+        self.last_beat = beat;
+    }
+
+    fn shutdown(&mut self) {
+        for pin in [&mut self.status_pin, &mut self.beat_pin].into_iter().flatten() {
+            pin.set_low();
+        }
+    }
+}
+
+/// GPIO is only supported on Linux, eg. a Raspberry Pi
+#[cfg(not(target_os = "linux"))]
+#[allow(dead_code)] // Never created, as create always fails
+pub struct Gpio;
+
+#[cfg(not(target_os = "linux"))]
+impl Gpio {
+    pub fn create(_conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        logger.err("GPIO output is only available on Linux");
+        Err(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl OutputModule for Gpio {}
@@ -0,0 +1,156 @@
+use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{config::Config, log::ScopedLogger, utils::PhraseParser};
+
+use super::{ModuleCreateOutput, OutputModule, Pulse};
+
+/// The bridge handles about 10 light commands per second, more get queued up and lag behind
+const MAX_COMMANDS_PER_SECOND: usize = 10;
+
+/// Philips Hue output module
+///
+/// Pulses the brightness of the lights on every downbeat, and changes their colour with the
+/// master phrase. Requests are sent from a separate thread so a slow bridge doesn't stall tracking.
+pub struct Hue {
+    logger: ScopedLogger,
+    light_urls: Vec<String>,
+    command_tx: mpsc::SyncSender<(String, String)>,
+    error_rx: mpsc::Receiver<String>,
+    sent: VecDeque<Instant>,
+    dropped: usize,
+    phrase_hues: [u16; 6], // Indexed by PhraseParser::phrase_name_to_index
+    hue: u16,
+    base_brightness: u8,
+    pulse_brightness: u8,
+    last_beat: f32,
+    downbeat_pulse: Pulse,
+}
+
+impl Hue {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let bridge_ip = conf.get_or_default("bridge_ip", String::new());
+        let api_key = conf.get_or_default("api_key", String::new());
+        if bridge_ip.is_empty() || api_key.is_empty() {
+            logger.err("hue.bridge_ip and hue.api_key must be set");
+            return Err(());
+        }
+
+        let light_urls: Vec<String> = conf
+            .get_or_default("light_ids", String::new())
+            .split(',')
+            .map(|id| id.trim())
+            .filter(|id| !id.is_empty())
+            .map(|id| format!("http://{bridge_ip}/api/{api_key}/lights/{id}/state"))
+            .collect();
+        if light_urls.is_empty() {
+            logger.err("No lights configured in hue.light_ids");
+            return Err(());
+        }
+
+        let (command_tx, error_rx) = Self::spawn_sender();
+        logger.info(&format!("Controlling {} lights on bridge {bridge_ip}", light_urls.len()));
+
+        Ok(Box::new(Hue {
+            logger,
+            light_urls,
+            command_tx,
+            error_rx,
+            sent: VecDeque::new(),
+            dropped: 0,
+            phrase_hues: [
+                conf.get_or_default("color.none", 8000),
+                conf.get_or_default("color.intro", 46000),
+                conf.get_or_default("color.verse", 25500),
+                conf.get_or_default("color.chorus", 0),
+                conf.get_or_default("color.bridge", 50000),
+                conf.get_or_default("color.outro", 46000),
+            ],
+            hue: 8000,
+            base_brightness: conf.get_or_default("base_brightness", 100),
+            pulse_brightness: conf.get_or_default("pulse_brightness", 254),
+            last_beat: 0.,
+            downbeat_pulse: Pulse::new(Duration::from_millis(conf.get_or_default("pulse_width_ms", 250))),
+        }))
+    }
+
+    /// Send requests on a background thread. Errors are passed back to be logged on the keeper thread
+    fn spawn_sender() -> (mpsc::SyncSender<(String, String)>, mpsc::Receiver<String>) {
+        let (command_tx, command_rx) = mpsc::sync_channel::<(String, String)>(MAX_COMMANDS_PER_SECOND);
+        let (error_tx, error_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let client = reqwest::blocking::Client::builder()
+                .timeout(Duration::from_secs(1))
+                .build()
+                .unwrap_or_default();
+            for (url, body) in command_rx {
+                let result = client
+                    .put(&url)
+                    .header("Content-Type", "application/json")
+                    .body(body)
+                    .send();
+                if let Err(e) = result.and_then(|res| res.error_for_status()) {
+                    if error_tx.send(e.to_string()).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (command_tx, error_rx)
+    }
+
+    /// Set the state of all lights. Commands over the bridge's rate limit are dropped, as they
+    /// would be late anyway
+    fn set_lights(&mut self, body: String) {
+        while self.sent.front().is_some_and(|t| t.elapsed() > Duration::from_secs(1)) {
+            self.sent.pop_front();
+        }
+        for url in &self.light_urls {
+            if self.sent.len() >= MAX_COMMANDS_PER_SECOND
+                || self.command_tx.try_send((url.clone(), body.clone())).is_err()
+            {
+                self.dropped += 1;
+                continue;
+            }
+            self.sent.push_back(Instant::now());
+        }
+    }
+}
+
+impl OutputModule for Hue {
+    fn pre_update(&mut self) {
+        if self.downbeat_pulse.off_due() {
+            // Fade back down over 200 ms
+            let body = format!(r#"{{"bri":{},"transitiontime":2}}"#, self.base_brightness);
+            self.set_lights(body);
+        }
+    }
+
+    fn beat_update_master(&mut self, beat: f32) {
+        if beat < self.last_beat {
+            // New bar
+            let body = format!(r#"{{"on":true,"bri":{},"hue":{},"sat":254,"transitiontime":0}}"#, self.pulse_brightness, self.hue);
+            self.set_lights(body);
+            self.downbeat_pulse.start();
+        }
+        self.last_beat = beat;
+    }
+
+    fn phrase_changed_master(&mut self, phrase: &str) {
+        self.hue = self.phrase_hues[PhraseParser::phrase_name_to_index(phrase) as usize];
+        let body = format!(r#"{{"hue":{},"sat":254,"transitiontime":4}}"#, self.hue);
+        self.set_lights(body);
+    }
+
+    fn slow_update(&mut self) {
+        while let Ok(e) = self.error_rx.try_recv() {
+            self.logger.err(&format!("Failed to send to Hue bridge: {e}"));
+        }
+        if self.dropped > 0 {
+            self.logger.debug(&format!("Dropped {} commands over the bridge's rate limit", self.dropped));
+            self.dropped = 0;
+        }
+    }
+}
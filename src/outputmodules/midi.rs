@@ -0,0 +1,182 @@
+use midir::{MidiOutput, MidiOutputConnection};
+
+use crate::{config::Config, log::ScopedLogger};
+
+use std::time::Duration;
+
+use super::{ModuleCreateOutput, OutputModule, Pulse};
+
+/// MIDI CC output module
+///
+/// Sends the master beat phase and BPM as 7-bit control change values, for
+/// controllers and lights that only understand MIDI.
+pub struct Midi {
+    conn: MidiOutputConnection,
+    logger: ScopedLogger,
+    channel: u8,
+    beat_cc: Option<u8>,
+    bpm_cc: Option<u8>,
+    bpm_min: f32,
+    bpm_max: f32,
+    send_period: i32,
+    send_period_counter: i32,
+    last_beat_value: Option<u8>,
+    last_bpm_value: Option<u8>,
+    downbeat_note: Option<u8>,
+    downbeat_pulse: Pulse,
+    last_beat: f32,
+    send_failures: u32, // In a row
+    sent: u64, // Messages, for stats
+}
+
+/// Sends failing in a row before the module reports itself as failed to the watchdog, eg. when
+/// the device was unplugged
+const MAX_SEND_FAILURES: u32 = 20;
+
+impl Midi {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let midi_out = match MidiOutput::new("rkbx_link") {
+            Ok(m) => m,
+            Err(e) => {
+                logger.err(&format!("Failed to initialise MIDI: {e}"));
+                return Err(());
+            }
+        };
+
+        let port_name = conf.get_or_default("port", String::new());
+        let ports = midi_out.ports();
+        // An index from --list-ports, or (part of) a name
+        let port = match port_name.trim().parse::<usize>() {
+            Ok(index) => ports.get(index),
+            Err(_) => ports.iter().find(|p| {
+                midi_out
+                    .port_name(p)
+                    .map(|name| name.contains(&port_name))
+                    .unwrap_or(false)
+            }),
+        };
+        let Some(port) = port else {
+            logger.err(&format!("No MIDI output port matching '{port_name}', run with --list-ports to see the available ports"));
+            return Err(());
+        };
+        let name = midi_out.port_name(port).unwrap_or_default();
+
+        let conn = match midi_out.connect(port, "rkbx_link") {
+            Ok(c) => c,
+            Err(e) => {
+                logger.err(&format!("Failed to open MIDI port {name}: {e}"));
+                return Err(());
+            }
+        };
+        logger.info(&format!("Sending to MIDI port {name}"));
+
+        let channel: u8 = conf.get_or_default("channel", 1u8).clamp(1, 16) - 1;
+
+        Ok(Box::new(Midi {
+            conn,
+            logger,
+            channel,
+            // Empty to disable
+            beat_cc: conf.get_or_default("beat_cc", String::new()).parse().ok().map(|cc: u8| cc.min(127)),
+            bpm_cc: conf.get_or_default("bpm_cc", String::new()).parse().ok().map(|cc: u8| cc.min(127)),
+            bpm_min: conf.get_or_default("bpm_min", 60.),
+            bpm_max: conf.get_or_default("bpm_max", 187.),
+            send_period: conf.get_every_nth("send_every_nth", 2),
+            send_period_counter: 0,
+            last_beat_value: None,
+            last_bpm_value: None,
+            downbeat_note: conf.get_or_default("downbeat_note", String::new()).parse().ok().map(|note: u8| note.min(127)),
+            downbeat_pulse: Pulse::new(Duration::from_millis(conf.get_or_default("pulse_width_ms", 100))),
+            last_beat: 0.,
+            send_failures: 0,
+            sent: 0,
+        }))
+    }
+
+    /// Print the available output ports with the indices `midi.port` accepts
+    pub fn list_ports() {
+        let midi_out = match MidiOutput::new("rkbx_link") {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Failed to initialise MIDI: {e}");
+                return;
+            }
+        };
+        let ports = midi_out.ports();
+        println!("MIDI output ports:");
+        if ports.is_empty() {
+            println!("  (none)");
+        }
+        for (i, port) in ports.iter().enumerate() {
+            println!("  {i}: {}", midi_out.port_name(port).unwrap_or_else(|_| "<unknown>".to_string()));
+        }
+    }
+
+    fn send_cc(&mut self, cc: u8, value: u8) {
+        self.send_message(&[0xB0 | self.channel, cc, value]);
+    }
+
+    fn send_message(&mut self, message: &[u8]) {
+        if let Err(e) = self.conn.send(message) {
+            self.logger.err(&format!("Failed to send MIDI message: {e}"));
+            self.send_failures = self.send_failures.saturating_add(1);
+        } else {
+            self.send_failures = 0;
+            self.sent += 1;
+        }
+    }
+}
+
+impl OutputModule for Midi {
+    fn pre_update(&mut self) {
+        self.send_period_counter = (self.send_period_counter + 1) % self.send_period;
+        if let Some(note) = self.downbeat_note {
+            if self.downbeat_pulse.off_due() {
+                self.send_message(&[0x80 | self.channel, note, 0]);
+            }
+        }
+    }
+
+    fn beat_update_master(&mut self, beat: f32) {
+        // New bar
+        if let Some(note) = self.downbeat_note {
+            if beat < self.last_beat {
+                self.send_message(&[0x90 | self.channel, note, 127]);
+                self.downbeat_pulse.start();
+            }
+        }
+        self.last_beat = beat;
+
+        if self.send_period_counter != 0 {
+            return;
+        }
+        let Some(cc) = self.beat_cc else {
+            return;
+        };
+        let value = ((beat % 1.0) * 127.) as u8;
+        if self.last_beat_value != Some(value) {
+            self.last_beat_value = Some(value);
+            self.send_cc(cc, value);
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.send_failures >= MAX_SEND_FAILURES
+    }
+
+    fn stats(&self) -> Option<String> {
+        Some(format!("{} sent", self.sent))
+    }
+
+    fn bpm_changed_master(&mut self, bpm: f32) {
+        let Some(cc) = self.bpm_cc else {
+            return;
+        };
+        let range = (self.bpm_max - self.bpm_min).max(1.);
+        let value = (((bpm - self.bpm_min) / range).clamp(0., 1.) * 127.).round() as u8;
+        if self.last_bpm_value != Some(value) {
+            self.last_bpm_value = Some(value);
+            self.send_cc(cc, value);
+        }
+    }
+}
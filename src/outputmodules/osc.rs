@@ -1,10 +1,15 @@
-use std::net::UdpSocket;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
-use rosc::{encoder::encode, OscMessage, OscPacket};
+use rosc::{decoder::decode_udp, encoder::encode, OscBundle, OscMessage, OscPacket, OscTime};
 
-use crate::{beatkeeper::TrackInfo, config::Config, log::ScopedLogger, utils::PhraseParser};
+use crate::{beatkeeper::{AnalysisStatus, MasterChangeReason, PerfState, TrackInfo, KEEPER_CONTROL}, config::Config, log::ScopedLogger, utils::PhraseParser};
 
-use super::{ModuleCreateOutput, OutputModule};
+use super::{DisconnectPolicy, ModuleCreateOutput, OutputModule, Pulse};
 
 enum OutputFormat{
     String,
@@ -23,6 +28,158 @@ impl OutputFormat {
     }
 }
 
+/// Argument type forced for the addresses matching `pattern`, from osc.type.<pattern>
+struct TypeOverride {
+    pattern: String,
+    format: OutputFormat,
+}
+
+/// Whether an address matches a pattern, where a `*` segment matches any single segment, eg.
+/// `/*/bpm/current` matches `/master/bpm/current` and `/0/bpm/current`
+fn address_matches(pattern: &str, address: &str) -> bool {
+    let mut pattern = pattern.split('/');
+    let mut address = address.split('/');
+    loop {
+        match (pattern.next(), address.next()) {
+            (None, None) => return true,
+            (Some(p), Some(a)) if p == "*" || p == a => {}
+            _ => return false,
+        }
+    }
+}
+
+/// Convert an argument to the forced type. Strings that aren't numbers are left as they are, and
+/// floats are truncated to ints
+fn coerce_arg(arg: rosc::OscType, format: &OutputFormat) -> rosc::OscType {
+    use rosc::OscType;
+    match (format, arg) {
+        (OutputFormat::Int, OscType::Float(value)) => OscType::Int(value as i32),
+        (OutputFormat::Float, OscType::Int(value)) => OscType::Float(value as f32),
+        (OutputFormat::String, OscType::Int(value)) => OscType::String(value.to_string()),
+        (OutputFormat::String, OscType::Float(value)) => OscType::String(value.to_string()),
+        (OutputFormat::Int, OscType::String(value)) => match value.trim().parse::<f32>() {
+            Ok(number) => OscType::Int(number as i32),
+            Err(_) => OscType::String(value),
+        },
+        (OutputFormat::Float, OscType::String(value)) => match value.trim().parse::<f32>() {
+            Ok(number) => OscType::Float(number),
+            Err(_) => OscType::String(value),
+        },
+        (_, arg) => arg,
+    }
+}
+
+fn family_name(addr: &SocketAddr) -> &'static str {
+    if addr.is_ipv4() { "IPv4" } else { "IPv6" }
+}
+
+/// First of the resolved addresses in the family of the socket's address, as a socket can only
+/// send to its own family, eg. when a host name resolves to both
+fn matching_family(candidates: &[SocketAddr], local: &SocketAddr) -> Option<SocketAddr> {
+    candidates.iter().copied().find(|addr| addr.is_ipv4() == local.is_ipv4())
+}
+
+/// Encoding of string arguments, for receivers that don't handle UTF-8
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum StringEncoding {
+    Utf8,
+    Latin1,
+    Ascii,
+}
+
+impl StringEncoding {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "utf8" => Some(StringEncoding::Utf8),
+            "latin1" => Some(StringEncoding::Latin1),
+            "ascii" => Some(StringEncoding::Ascii),
+            _ => None,
+        }
+    }
+
+    /// Characters that can't be represented are replaced with '?'. Also returns whether any were
+    fn encode(&self, s: &str) -> (Vec<u8>, bool) {
+        let max = match self {
+            StringEncoding::Utf8 => return (s.as_bytes().to_vec(), false),
+            StringEncoding::Latin1 => 0xFF,
+            StringEncoding::Ascii => 0x7F,
+        };
+        let mut lossy = false;
+        let bytes = s
+            .chars()
+            .map(|c| {
+                if c as u32 <= max {
+                    c as u8
+                } else {
+                    lossy = true;
+                    b'?'
+                }
+            })
+            .collect();
+        (bytes, lossy)
+    }
+}
+
+/// Append a null-terminated string padded to 4 bytes, as OSC strings are encoded
+fn push_osc_string(packet: &mut Vec<u8>, bytes: &[u8]) {
+    packet.extend_from_slice(bytes);
+    packet.push(0);
+    while !packet.len().is_multiple_of(4) {
+        packet.push(0);
+    }
+}
+
+/// How to handle characters that are not allowed in OSC addresses
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum AddressValidation {
+    /// Refuse to start
+    Strict,
+    /// Replace them with underscores
+    Lenient,
+}
+
+impl AddressValidation {
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "strict" => Some(AddressValidation::Strict),
+            "lenient" => Some(AddressValidation::Lenient),
+            _ => None,
+        }
+    }
+}
+
+/// Characters reserved by OSC for pattern matching, or not allowed at all
+fn is_illegal_address_char(c: char) -> bool {
+    matches!(c, ' ' | '#' | '*' | ',' | '?' | '[' | ']' | '{' | '}') || !c.is_ascii() || c.is_ascii_control()
+}
+
+/// Check a user-provided address part. Returns the address to use, or the offending characters
+fn validate_address(address: &str, mode: AddressValidation) -> Result<String, String> {
+    let illegal: String = address.chars().filter(|c| is_illegal_address_char(*c)).collect();
+    let address = if illegal.is_empty() {
+        address.to_string()
+    } else {
+        match mode {
+            AddressValidation::Strict => return Err(illegal),
+            AddressValidation::Lenient => address
+                .chars()
+                .map(|c| if is_illegal_address_char(c) { '_' } else { c })
+                .collect(),
+        }
+    };
+
+    // Prefixes are joined with addresses that already start with a slash
+    let address = address.trim_end_matches('/');
+    if address.is_empty() || address.starts_with('/') {
+        Ok(address.to_string())
+    } else {
+        match mode {
+            AddressValidation::Strict => Err("missing leading /".to_string()),
+            AddressValidation::Lenient => Ok(format!("/{address}")),
+        }
+    }
+}
+
 struct MessageToggles{
     /*beat: bool,
     beat_master: bool,*/
@@ -32,6 +189,8 @@ struct MessageToggles{
     beat_master_subdivs: Vec<f32>,
     beat_triggers: Vec<f32>,
     beat_master_triggers: Vec<f32>,
+    beat_combined: bool,
+    beat_master_combined: bool,
 
     beat_trigger_autorelease: bool,
     time: bool,
@@ -39,6 +198,13 @@ struct MessageToggles{
     phrase: bool,
     phrase_master: bool,
     phrase_output_format: OutputFormat,
+    decks_overview: bool,
+    song_structure: bool,
+    downbeat: bool,
+    link_style: bool,
+    snapshot: bool,
+    phrase_bangs: bool,
+    decks: Vec<usize>, // Decks to send per-deck messages for, empty for all
 }
 
 
@@ -67,12 +233,33 @@ impl MessageToggles{
             beat_triggers: subdivs.next().unwrap(),
             beat_master_subdivs: subdivs.next().unwrap(),
             beat_master_triggers: subdivs.next().unwrap(),
+            beat_combined: conf.get_or_default("msg.n/beat/combined", false),
+            beat_master_combined: conf.get_or_default("msg.master/beat/combined", false),
 
             beat_trigger_autorelease: conf.get_or_default("trigger_autorelease", false),
             time: conf.get_or_default("msg.n/time", false), 
             time_master: conf.get_or_default("msg.master/time", true), 
             phrase: conf.get_or_default("msg.n/phrase", false), 
             phrase_master:  conf.get_or_default("msg.master/phrase", true),
+            decks_overview: conf.get_or_default("msg.decks_overview", false),
+            song_structure: conf.get_or_default("msg.song_structure", false),
+            downbeat: conf.get_or_default("msg.downbeat", false),
+            link_style: conf.get_or_default("msg.link_style", false),
+            snapshot: conf.get_or_default("msg.snapshot", false),
+            phrase_bangs: conf.get_or_default("msg.phrase_bangs", false),
+            decks: conf.get_or_default("msg.decks", String::new()).trim_matches(['[', ']']).split(',').filter_map(|x|{
+                let x = x.trim();
+                if x.is_empty(){
+                    return None;
+                }
+                match x.parse::<usize>(){
+                    Ok(deck) => Some(deck),
+                    Err(_) => {
+                        logger.err(&format!("Error parsing deck '{x}' in key msg.decks"));
+                        None
+                    }
+                }
+            }).collect(),
             phrase_output_format: {
                 let fmt = conf.get_or_default("phrase_output_format", "string".to_string());
                 match OutputFormat::from_str(&fmt) {
@@ -87,38 +274,169 @@ impl MessageToggles{
     } 
 }
 
+/// Last value sent to each address, to skip new values that are within `epsilon` of it. Unlike
+/// the keeper's change tracking, small drifts such as a jittering BPM are held back too
+struct EpsilonFilter {
+    epsilon: f32,
+    last_sent: HashMap<String, f32>,
+}
+
+impl EpsilonFilter {
+    fn new(epsilon: f32) -> Self {
+        EpsilonFilter { epsilon, last_sent: HashMap::new() }
+    }
+
+    /// Whether `value` should be sent to `addr`, remembering it if so
+    fn passes(&mut self, addr: &str, value: f32) -> bool {
+        if self.epsilon <= 0. {
+            return true;
+        }
+        match self.last_sent.get_mut(addr) {
+            Some(last) if (value - *last).abs() <= self.epsilon => false,
+            Some(last) => {
+                *last = value;
+                true
+            }
+            None => {
+                self.last_sent.insert(addr.to_string(), value);
+                true
+            }
+        }
+    }
+}
+
+/// Latest master values, for answering state requests
+#[derive(Default)]
+struct MasterState {
+    masterdeck_index: usize,
+    bpm: f32,
+    original_bpm: f32,
+    beat: f32,
+    time: f32,
+    track: TrackInfo,
+    phrase: String,
+    next_phrase: String,
+}
+
+/// Latest values of a single deck, for mirroring it to the overlay addresses
+#[derive(Default, Clone)]
+struct DeckState {
+    bpm: f32,
+    beat: f32,
+    track: TrackInfo,
+    playing: bool,
+    on_air: bool,
+}
+
 pub struct Osc {
     socket: UdpSocket,
+    destination: SocketAddr,
+    address_prefix: String,
+    string_encoding: StringEncoding,
+    type_overrides: Vec<TypeOverride>,
+    query_rx: Option<mpsc::Receiver<(OscPacket, SocketAddr)>>,
+    respond_to_queries: bool,
+    accept_control: bool,
+    state: MasterState,
     info_sent: bool,
     logger: ScopedLogger,
     message_toggles: MessageToggles,
     send_period: i32,
     send_period_counter: i32,
-<<<<<<< master
-=======
     last_beat_master: f32,
     last_beats: Vec<f32>,
->>>>>>> master
+    seeked_master: bool,
+    seeked: Vec<bool>,
+    bundle_beats: bool,
+    beat_timestamp: SystemTime,
+    overlay_deck: Option<usize>,
+    deck_states: Vec<DeckState>,
+    on_disconnect: DisconnectPolicy,
+    masterdeck_address: String,
+    deck_number_map: Vec<i32>, // Deck number sent for each internal deck index, empty to send the index
+    deck_offset: usize, // Added to the deck in per-deck addresses
+    deck_colors: [[u8; 3]; 4],
+    downbeat_pulse: Pulse,
+    link_quantum: Option<f32>, // None follows the master bar length, like the Link module
+    bar_length: u32,
+    analysis_ready: [bool; 4],
+    epsilon_bpm: EpsilonFilter,
+    epsilon_beat: EpsilonFilter,
+    epsilon_time: EpsilonFilter,
+    connected: bool,
+    send_failures: Cell<u32>, // In a row
+    sent: Cell<u64>, // Packets, for stats
 }
 
+/// Blue, orange, green and purple, used for decks without osc.deck_color.<n>
+const DEFAULT_DECK_COLORS: [[u8; 3]; 4] = [[0, 120, 255], [255, 140, 0], [0, 200, 120], [180, 60, 220]];
+
+/// Sends failing in a row before the module reports itself as failed to the watchdog
+const MAX_SEND_FAILURES: u32 = 100;
+
 
 
 
 impl Osc {
     fn send_float(&self, addr: &str, value: f32) {
-        let msg = OscPacket::Message(OscMessage {
-            addr: addr.to_string(),
-            args: vec![rosc::OscType::Float(value)],
-        });
-        self.send(msg);
+        self.send(Self::float_message(addr, value));
     }
 
     fn send_string(&self, addr: &str, value: &str) {
-        let msg = OscPacket::Message(OscMessage {
-            addr: addr.to_string(),
-            args: vec![rosc::OscType::String(value.to_string())],
-        });
-        self.send(msg);
+        self.send_strings(addr, &[value]);
+    }
+
+    /// Send a message with several string arguments
+    fn send_strings(&self, addr: &str, values: &[&str]) {
+        // Typed addresses go through rosc to be converted, numbers don't need re-encoding
+        if self.string_encoding == StringEncoding::Utf8 || self.type_override(addr).is_some() {
+            let msg = OscPacket::Message(OscMessage {
+                addr: addr.to_string(),
+                args: values.iter().map(|value| rosc::OscType::String(value.to_string())).collect(),
+            });
+            self.send(msg);
+            return;
+        }
+
+        // Latin-1 isn't valid UTF-8, which is all rosc can encode, so the message is built by hand
+        let mut packet = vec![];
+        push_osc_string(&mut packet, format!("{}{addr}", self.address_prefix).as_bytes());
+        push_osc_string(&mut packet, format!(",{}", "s".repeat(values.len())).as_bytes());
+        for value in values {
+            let (bytes, lossy) = self.string_encoding.encode(value);
+            if lossy {
+                self.logger.debug(&format!("Replaced characters not available in {:?} in '{value}'", self.string_encoding));
+            }
+            push_osc_string(&mut packet, &bytes);
+        }
+        self.send_raw(&packet, self.destination);
+    }
+
+    fn send_deck_colors(&self) {
+        for (deck, color) in self.deck_colors.iter().enumerate() {
+            if !self.deck_enabled(deck) {
+                continue;
+            }
+            self.send(OscPacket::Message(OscMessage {
+                addr: format!("/deck/{}/color", self.deck_address(deck)),
+                args: color.iter().map(|&c| rosc::OscType::Int(c as i32)).collect(),
+            }));
+        }
+    }
+
+    /// The deck as written in per-deck addresses, shifted by osc.deck_offset
+    fn deck_address(&self, deck: usize) -> usize {
+        deck + self.deck_offset
+    }
+
+    /// Whether per-deck messages are sent for the deck, see osc.msg.decks
+    fn deck_enabled(&self, deck: usize) -> bool {
+        self.message_toggles.decks.is_empty() || self.message_toggles.decks.contains(&deck)
+    }
+
+    /// The number of a deck as labelled on the user's controller, from `deck_number_map`
+    fn deck_number(&self, index: usize) -> i32 {
+        self.deck_number_map.get(index).copied().unwrap_or(index as i32)
     }
 
     fn send_int(&self, addr: &str, value: i32) {
@@ -129,7 +447,52 @@ impl Osc {
         self.send(msg);
     }
 
+    fn float_message(addr: &str, value: f32) -> OscPacket {
+        OscPacket::Message(OscMessage {
+            addr: addr.to_string(),
+            args: vec![rosc::OscType::Float(value)],
+        })
+    }
+
+    /// Beat phase within each division, normalised to 0-1 the same way for master and per-deck messages.
+    /// One message per division, or with `combined` a single `{prefix}/beat` message with the beat
+    /// followed by the value of every division, in the configured order
+    fn subdiv_messages(prefix: &str, beat: f32, divisions: &[f32], combined: bool) -> Vec<OscPacket> {
+        if combined {
+            let mut args = vec![rosc::OscType::Float(beat)];
+            args.extend(divisions.iter().map(|d| rosc::OscType::Float((beat % d) / d)));
+            return vec![OscPacket::Message(OscMessage { addr: format!("{prefix}/beat"), args })];
+        }
+        divisions
+            .iter()
+            .map(|d| Self::float_message(&format!("{prefix}/beat/subdiv/{d}"), (beat % d) / d))
+            .collect()
+    }
+
+    /// Send the master beat messages, bundled and time tagged with when the beat happens if enabled
+    fn send_beat_messages(&self, messages: Vec<OscPacket>) {
+        if messages.is_empty() {
+            return;
+        }
+        if self.bundle_beats {
+            let timetag = OscTime::try_from(self.beat_timestamp).unwrap_or(OscTime::from((0, 1)));
+            self.send(OscPacket::Bundle(OscBundle { timetag, content: messages }));
+        } else {
+            for msg in messages {
+                self.send(msg);
+            }
+        }
+    }
+
     fn send(&self, msg: OscPacket) {
+        self.send_to(msg, self.destination);
+    }
+
+    fn send_to(&self, mut msg: OscPacket, addr: SocketAddr) {
+        self.apply_types(&mut msg);
+        if !self.address_prefix.is_empty() {
+            Self::add_prefix(&mut msg, &self.address_prefix);
+        }
         let packet = match encode(&msg){
             Ok(packet) => packet,
             Err(e) => {
@@ -137,14 +500,63 @@ impl Osc {
                 return;
             }
         };
-        if let Err(e) = self.socket.send(&packet) {
+        self.send_raw(&packet, addr);
+    }
+
+    fn send_raw(&self, packet: &[u8], addr: SocketAddr) {
+        if let Err(e) = self.socket.send_to(packet, addr) {
             self.logger.err(&format!("Failed to send OSC message: {e}"));
-        };
+            self.send_failures.set(self.send_failures.get().saturating_add(1));
+        } else {
+            self.send_failures.set(0);
+            self.sent.set(self.sent.get() + 1);
+        }
+    }
+
+    fn type_override(&self, addr: &str) -> Option<&OutputFormat> {
+        self.type_overrides
+            .iter()
+            .find(|o| address_matches(&o.pattern, addr))
+            .map(|o| &o.format)
+    }
+
+    /// Convert the arguments of addresses with a type set in osc.type.<address>
+    fn apply_types(&self, packet: &mut OscPacket) {
+        if self.type_overrides.is_empty() {
+            return;
+        }
+        match packet {
+            OscPacket::Message(msg) => {
+                let Some(format) = self.type_override(&msg.addr) else {
+                    return;
+                };
+                for arg in &mut msg.args {
+                    *arg = coerce_arg(std::mem::replace(arg, rosc::OscType::Nil), format);
+                }
+            }
+            OscPacket::Bundle(bundle) => {
+                for packet in &mut bundle.content {
+                    self.apply_types(packet);
+                }
+            }
+        }
+    }
+
+    fn add_prefix(packet: &mut OscPacket, prefix: &str) {
+        match packet {
+            OscPacket::Message(msg) => msg.addr.insert_str(0, prefix),
+            OscPacket::Bundle(bundle) => {
+                for packet in &mut bundle.content {
+                    Self::add_prefix(packet, prefix);
+                }
+            }
+        }
     }
 }
 
 impl Osc {
     pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        // The socket is created in the family of the address, eg. [::1]:8888 for IPv6
         let socket =
             match UdpSocket::bind(conf.get_or_default("source", "127.0.0.1:8888".to_string())) {
                 Ok(socket) => socket,
@@ -153,150 +565,847 @@ impl Osc {
                     return Err(());
                 }
             };
+        let local = match socket.local_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                logger.err(&format!("Failed to get the source socket address: {e}"));
+                return Err(());
+            }
+        };
 
-        if let Err(e) =
-            socket.connect(conf.get_or_default("destination", "127.0.0.1:9999".to_string()))
-        {
-            logger.err(&format!("Failed to open connection to receiver: {e}"));
+        // UDP doesn't require an established connection, so the receiver doesn't need to be up yet
+        let destination_str = conf.get_or_default("destination", "127.0.0.1:9999".to_string());
+        let candidates: Vec<SocketAddr> = match destination_str.to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(_) => vec![],
+        };
+        let Some(first) = candidates.first() else {
+            logger.err(&format!("Invalid OSC destination address: {destination_str}"));
+            return Err(());
+        };
+        let Some(destination) = matching_family(&candidates, &local) else {
+            logger.err(&format!(
+                "OSC destination {destination_str} is {} but osc.source {local} is {}, use addresses of the same family",
+                family_name(first),
+                family_name(&local)
+            ));
+            return Err(());
+        };
+
+        // Replaces the destination, so any number of receivers can subscribe to the group
+        let multicast = conf.get_or_default("multicast", String::new());
+        let destination = if multicast.is_empty() {
+            destination
+        } else {
+            let group = match multicast.parse::<SocketAddr>() {
+                Ok(addr) if addr.ip().is_multicast() => addr,
+                Ok(_) => {
+                    logger.err(&format!("{multicast} is not a multicast address (224.0.0.0 - 239.255.255.255, or ff00::/8 for IPv6)"));
+                    return Err(());
+                }
+                Err(_) => {
+                    logger.err(&format!("Invalid OSC multicast address: {multicast}"));
+                    return Err(());
+                }
+            };
+            if group.is_ipv4() != local.is_ipv4() {
+                logger.err(&format!(
+                    "OSC multicast group {group} is {} but osc.source {local} is {}, use addresses of the same family",
+                    family_name(&group),
+                    family_name(&local)
+                ));
+                return Err(());
+            }
+            let loopback = conf.get_or_default("multicast_loopback", true);
+            let joined = match group {
+                SocketAddr::V4(group) => {
+                    let ttl = conf.get_or_default("multicast_ttl", 1);
+                    if let Err(e) = socket.set_multicast_ttl_v4(ttl) {
+                        logger.err(&format!("Failed to set multicast TTL: {e}"));
+                        return Err(());
+                    }
+                    if let Err(e) = socket.set_multicast_loop_v4(loopback) {
+                        logger.err(&format!("Failed to set multicast loopback: {e}"));
+                        return Err(());
+                    }
+                    // Also receive queries and control messages sent to the group
+                    socket.join_multicast_v4(group.ip(), &Ipv4Addr::UNSPECIFIED)
+                }
+                SocketAddr::V6(group) => {
+                    // IPv6 has no TTL setting in std, packets keep the system's default hop limit
+                    if let Err(e) = socket.set_multicast_loop_v6(loopback) {
+                        logger.err(&format!("Failed to set multicast loopback: {e}"));
+                        return Err(());
+                    }
+                    // Interface 0 lets the system pick
+                    socket.join_multicast_v6(group.ip(), 0)
+                }
+            };
+            if let Err(e) = joined {
+                logger.warn(&format!("Failed to join multicast group {}: {e}", group.ip()));
+            }
+            if local.ip().is_loopback() {
+                logger.warn("osc.source is a loopback address, multicast will only reach this computer");
+            }
+            logger.info(&format!("Sending to multicast group {group}"));
+            group
+        };
+
+        let validation = conf.get_or_default("address_validation", "strict".to_string());
+        let Some(validation) = AddressValidation::from_str(&validation) else {
+            logger.err(&format!("Unknown address validation mode: {validation}"));
             return Err(());
+        };
+        let prefix = conf.get_or_default("address_prefix", String::new());
+        let address_prefix = match validate_address(&prefix, validation) {
+            Ok(address) => {
+                if address != prefix.trim_end_matches('/') {
+                    logger.warn(&format!("Address prefix '{prefix}' is not a valid OSC address, using '{address}'"));
+                }
+                address
+            }
+            Err(e) => {
+                logger.err(&format!("Address prefix '{prefix}' is not a valid OSC address: {e}"));
+                return Err(());
+            }
+        };
+
+        let masterdeck_address = conf.get_or_default("masterdeck_address", "/masterdeck/index".to_string());
+        let masterdeck_address = match validate_address(&masterdeck_address, validation) {
+            Ok(address) => address,
+            Err(e) => {
+                logger.err(&format!("Masterdeck address '{masterdeck_address}' is not a valid OSC address: {e}"));
+                return Err(());
+            }
+        };
+
+        let mut deck_number_map = vec![];
+        for number in conf.get_or_default("deck_number_map", String::new()).split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            match number.parse::<i32>() {
+                Ok(number) => deck_number_map.push(number),
+                Err(_) => {
+                    logger.err(&format!("Error parsing value '{number}' in key deck_number_map"));
+                    return Err(());
+                }
+            }
         }
-        // Try to connect to destination, but don't fail if receiver isn't ready yet
-        // UDP doesn't require an established connection to send
-        let destination = conf.get_or_default("destination", "127.0.0.1:9999".to_string());
-        if let Err(e) = socket.connect(&destination) {
-            logger.warn(&format!("Could not open UDP socket to OSC receiver at {}: {}", destination, e));
-            logger.info("OSC will continue attempting to send messages");
+
+        let mut deck_colors = DEFAULT_DECK_COLORS;
+        for (deck, color) in deck_colors.iter_mut().enumerate() {
+            let key = format!("deck_color.{deck}");
+            let value = conf.get_or_default(&key, String::new());
+            if value.is_empty() {
+                continue;
+            }
+            let channels: Vec<u8> = value.split(',').filter_map(|x| x.trim().parse().ok()).collect();
+            match channels.try_into() {
+                Ok(rgb) => *color = rgb,
+                Err(_) => logger.err(&format!("Invalid colour '{value}' in key {key}, expected r,g,b from 0 to 255")),
+            }
+        }
+
+        let encoding = conf.get_or_default("string_encoding", "utf8".to_string());
+        let Some(string_encoding) = StringEncoding::from_str(&encoding) else {
+            logger.err(&format!("Unknown string encoding: {encoding}"));
+            return Err(());
+        };
+
+        let mut type_overrides = vec![];
+        for (pattern, format) in conf.entries_with_prefix("type.") {
+            match OutputFormat::from_str(&format) {
+                Some(format) if pattern.starts_with('/') => type_overrides.push(TypeOverride { pattern, format }),
+                Some(_) => logger.err(&format!("Invalid address '{pattern}' in osc.type.{pattern}, addresses start with /")),
+                None => logger.err(&format!("Unknown type '{format}' for osc.type.{pattern}, expected float, int or string")),
+            }
         }
 
+        let respond_to_queries = conf.get_or_default("respond_to_queries", false);
+        let accept_control = conf.get_or_default("accept_control", false);
+        let query_rx = if respond_to_queries || accept_control {
+            match socket.try_clone() {
+                Ok(socket) => Some(Self::spawn_listener(socket)),
+                Err(e) => {
+                    logger.err(&format!("Failed to start listening for queries: {e}"));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // send_reset_on_disconnect is the older name for zero, only read by configs without on_disconnect
+        let on_disconnect = if conf.get_or_default("on_disconnect", String::new()).is_empty()
+            && conf.get_or_default("send_reset_on_disconnect", false)
+        {
+            DisconnectPolicy::Zero
+        } else {
+            DisconnectPolicy::from_config(&conf, DisconnectPolicy::Hold, &logger)
+        };
+
         Ok(Box::new(Osc {
             socket,
+            destination,
+            address_prefix,
+            string_encoding,
+            type_overrides,
+            query_rx,
+            respond_to_queries,
+            accept_control,
+            state: MasterState::default(),
             info_sent: false,
             logger: logger.clone(),
             message_toggles: MessageToggles::new(&conf, logger),
-            send_period: conf.get_or_default("send_every_nth", 2),
+            send_period: conf.get_every_nth("send_every_nth", 2),
             send_period_counter: 0,
             last_beat_master: 0.0,
             last_beats: vec![0.0; 4],
+            seeked_master: false,
+            seeked: vec![false; 4],
+            bundle_beats: conf.get_or_default("bundle_beats", false),
+            beat_timestamp: SystemTime::now(),
+            overlay_deck: conf.get_or_default("overlay_deck", String::new()).parse().ok().filter(|deck: &usize| *deck < 4),
+            deck_states: vec![DeckState::default(); 4],
+            on_disconnect,
+            masterdeck_address,
+            deck_number_map,
+            deck_offset: conf.get_or_default("deck_offset", 0),
+            deck_colors,
+            downbeat_pulse: Pulse::new(Duration::from_millis(conf.get_or_default("pulse_width_ms", 100))),
+            link_quantum: conf.get_or_default("link_quantum", String::new()).parse().ok().filter(|q: &f32| *q > 0.),
+            bar_length: 4,
+            analysis_ready: [false; 4],
+            epsilon_bpm: EpsilonFilter::new(conf.get_or_default("epsilon.bpm", 0.)),
+            epsilon_beat: EpsilonFilter::new(conf.get_or_default("epsilon.beat", 0.)),
+            epsilon_time: EpsilonFilter::new(conf.get_or_default("epsilon.time", 0.)),
+            connected: false,
+            send_failures: Cell::new(0),
+            sent: Cell::new(0),
         }))
     }
 }
 
+impl Osc {
+    /// Receive and decode incoming packets on a background thread, they are handled in pre_update
+    fn spawn_listener(socket: UdpSocket) -> mpsc::Receiver<(OscPacket, SocketAddr)> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; rosc::decoder::MTU];
+            loop {
+                // Errors are expected on Windows when a previous send was refused, keep listening
+                let Ok((size, addr)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                if let Ok((_, packet)) = decode_udp(&buf[..size]) {
+                    if tx.send((packet, addr)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    fn handle_queries(&mut self) {
+        let Some(query_rx) = &self.query_rx else {
+            return;
+        };
+        let packets: Vec<(OscPacket, SocketAddr)> = query_rx.try_iter().collect();
+        for (packet, addr) in packets {
+            self.handle_packet(packet, addr);
+        }
+    }
+
+    fn handle_packet(&mut self, packet: OscPacket, addr: SocketAddr) {
+        match packet {
+            OscPacket::Bundle(bundle) => {
+                for packet in bundle.content {
+                    self.handle_packet(packet, addr);
+                }
+            }
+            OscPacket::Message(msg) => match msg.addr.as_str() {
+                "/ping" if self.respond_to_queries => {
+                    self.send_to(OscPacket::Message(OscMessage { addr: "/pong".to_string(), args: vec![] }), addr);
+                }
+                "/state/request" if self.respond_to_queries => {
+                    self.send_to(self.state_bundle(), addr);
+                }
+                "/control/force_master" if self.accept_control => {
+                    let deck = match msg.args.first() {
+                        Some(rosc::OscType::Int(i)) => *i,
+                        Some(rosc::OscType::Float(f)) => *f as i32,
+                        _ => -1,
+                    };
+                    if deck < 0 {
+                        self.logger.err(&format!("Invalid deck for /control/force_master from {addr}: {:?}", msg.args));
+                        return;
+                    }
+                    self.logger.info(&format!("Master forced to deck {deck}"));
+                    KEEPER_CONTROL.force_master(Some(deck as usize));
+                }
+                "/control/auto_master" if self.accept_control => {
+                    self.logger.info("Master follows Rekordbox");
+                    KEEPER_CONTROL.force_master(None);
+                }
+                "/control/beat_phase_offset" if self.accept_control => {
+                    let offset = match msg.args.first() {
+                        Some(rosc::OscType::Float(f)) => *f,
+                        Some(rosc::OscType::Int(i)) => *i as f32,
+                        _ => {
+                            self.logger.err(&format!("Invalid offset for /control/beat_phase_offset from {addr}: {:?}", msg.args));
+                            return;
+                        }
+                    };
+                    self.logger.info(&format!("Beat phase offset set to {offset}"));
+                    KEEPER_CONTROL.set_beat_phase_offset(offset);
+                }
+                "/control/mute" if self.accept_control => {
+                    let muted = match msg.args.first() {
+                        Some(rosc::OscType::Int(i)) => *i != 0,
+                        Some(rosc::OscType::Float(f)) => *f != 0.,
+                        Some(rosc::OscType::Bool(b)) => *b,
+                        _ => {
+                            self.logger.err(&format!("Invalid value for /control/mute from {addr}: {:?}", msg.args));
+                            return;
+                        }
+                    };
+                    KEEPER_CONTROL.set_muted(muted);
+                }
+                "/control/overlay_deck" if self.accept_control => {
+                    let deck = match msg.args.first() {
+                        Some(rosc::OscType::Int(i)) => *i,
+                        Some(rosc::OscType::Float(f)) => *f as i32,
+                        _ => -1,
+                    };
+                    // Negative values disable the overlay
+                    self.overlay_deck = if (0..4).contains(&deck) { Some(deck as usize) } else { None };
+                    self.logger.info(&format!("Overlay deck set to {:?}", self.overlay_deck));
+                    self.send_overlay();
+                }
+                _ => {
+                    self.logger.debug(&format!("Unhandled OSC message {} from {addr}", msg.addr));
+                }
+            },
+        }
+    }
+
+    /// Send all values of the overlay deck, eg. when it was switched
+    fn send_overlay(&self) {
+        let Some(deck) = self.overlay_deck else {
+            return;
+        };
+        let state = &self.deck_states[deck];
+        self.send_int("/overlay/deck", deck as i32);
+        self.send_float("/overlay/bpm/current", state.bpm);
+        self.send_float("/overlay/beat", state.beat);
+        self.send_string("/overlay/track/title", &state.track.title);
+        self.send_string("/overlay/track/artist", &state.track.artist);
+        self.send_string("/overlay/track/album", &state.track.album);
+    }
+
+    /// Zero the enabled beat and time messages, so receivers don't hold on to the last values
+    fn send_reset(&mut self) {
+        let toggles = &self.message_toggles;
+        for msg in Self::subdiv_messages("/master", 0., &toggles.beat_master_subdivs, toggles.beat_master_combined) {
+            self.send(msg);
+        }
+        for d in &toggles.beat_master_triggers {
+            self.send_float(&format!("/master/beat/trigger/{d}"), 0.);
+        }
+        if toggles.time_master {
+            self.send_float("/master/time", 0.);
+        }
+        for deck in (0..4).filter(|&deck| self.deck_enabled(deck)) {
+            for msg in Self::subdiv_messages(&format!("/{}", self.deck_address(deck)), 0., &toggles.beat_subdivs, toggles.beat_combined) {
+                self.send(msg);
+            }
+            for d in &toggles.beat_triggers {
+                self.send_float(&format!("/{}/beat/trigger/{d}", self.deck_address(deck)), 0.);
+            }
+            if toggles.time {
+                self.send_float(&format!("/{}/time", self.deck_address(deck)), 0.);
+            }
+        }
+        self.send_int("/status/connected", 0);
+
+        self.last_beat_master = 0.;
+        self.last_beats = vec![0.; 4];
+    }
+
+    /// Send what osc.on_disconnect asks for, ending with /status/connected 0
+    fn send_disconnect(&mut self) {
+        match self.on_disconnect {
+            DisconnectPolicy::Hold => self.send_int("/status/connected", 0),
+            DisconnectPolicy::Zero => self.send_reset(),
+            DisconnectPolicy::Reset => {
+                // Keep the master deck, so replies to /state/request stay meaningful
+                self.state = MasterState { masterdeck_index: self.state.masterdeck_index, ..Default::default() };
+                self.send_float("/master/bpm/current", 0.);
+                self.send_float("/master/bpm/original", 0.);
+                for field in ["title", "artist", "album", "genre", "comment"] {
+                    self.send_string(&format!("/master/track/{field}"), "");
+                }
+                if self.message_toggles.phrase_master {
+                    self.output_phrase("/master/phrase/current", "");
+                    self.output_phrase("/master/phrase/next", "");
+                }
+                self.send_int("/analysis/master/ready", 0);
+                self.send_reset();
+            }
+        }
+    }
+
+    fn master_analysis_ready(&self) -> bool {
+        self.analysis_ready.get(self.state.masterdeck_index).copied().unwrap_or(false)
+    }
+
+    fn state_bundle(&self) -> OscPacket {
+        OscPacket::Bundle(OscBundle {
+            timetag: OscTime::from((0, 1)), // Immediately
+            content: self.master_state_messages(),
+        })
+    }
+
+    /// The state bundle plus the bpm, play state and on air state of every deck, so a receiver
+    /// that dropped an update or joined late is back in sync within one snapshot
+    fn snapshot_bundle(&self) -> OscPacket {
+        let mut content = self.master_state_messages();
+        for (deck, state) in self.deck_states.iter().enumerate() {
+            if !self.deck_enabled(deck) {
+                continue;
+            }
+            let deck_address = self.deck_address(deck);
+            content.push(OscPacket::Message(OscMessage { addr: format!("/{deck_address}/bpm/current"), args: vec![rosc::OscType::Float(state.bpm)] }));
+            content.push(OscPacket::Message(OscMessage { addr: format!("/deck/{deck_address}/playing"), args: vec![rosc::OscType::Int(state.playing as i32)] }));
+            content.push(OscPacket::Message(OscMessage { addr: format!("/deck/{deck_address}/onair"), args: vec![rosc::OscType::Int(state.on_air as i32)] }));
+        }
+        OscPacket::Bundle(OscBundle {
+            timetag: OscTime::from((0, 1)), // Immediately
+            content,
+        })
+    }
+
+    fn master_state_messages(&self) -> Vec<OscPacket> {
+        let float = |addr: &str, value: f32| OscPacket::Message(OscMessage { addr: addr.to_string(), args: vec![rosc::OscType::Float(value)] });
+        let string = |addr: &str, value: &str| OscPacket::Message(OscMessage { addr: addr.to_string(), args: vec![rosc::OscType::String(value.to_string())] });
+        vec![
+            OscPacket::Message(OscMessage { addr: self.masterdeck_address.clone(), args: vec![rosc::OscType::Int(self.deck_number(self.state.masterdeck_index))] }),
+            float("/master/bpm/current", self.state.bpm),
+            float("/master/bpm/original", self.state.original_bpm),
+            float("/master/beat", self.state.beat),
+            float("/master/time", self.state.time),
+            string("/master/track/title", &self.state.track.title),
+            string("/master/track/artist", &self.state.track.artist),
+            string("/master/track/album", &self.state.track.album),
+            string("/master/phrase/current", &self.state.phrase),
+            string("/master/phrase/next", &self.state.next_phrase),
+            OscPacket::Message(OscMessage { addr: "/analysis/master/ready".to_string(), args: vec![rosc::OscType::Int(self.master_analysis_ready() as i32)] }),
+        ]
+    }
+}
+
 // TODO: Avoid formatting strings every loop
 impl OutputModule for Osc {
     fn pre_update(&mut self) {
         self.send_period_counter = (self.send_period_counter + 1) % self.send_period;
+        self.handle_queries();
+        if self.downbeat_pulse.off_due() {
+            self.send_int("/downbeat", 0);
+        }
     }
 
     fn bpm_changed_master(&mut self, bpm: f32) {
-        self.send_float("/master/bpm/current", bpm);
+        self.state.bpm = bpm;
+        if self.epsilon_bpm.passes("/master/bpm/current", bpm) {
+            self.send_float("/master/bpm/current", bpm);
+        }
     }
 
     fn bpm_changed(&mut self, bpm: f32, deck: usize) {
-        self.send_float(&format!("/{deck}/bpm/current"), bpm);
+        self.deck_states[deck].bpm = bpm;
+        let addr = format!("/{}/bpm/current", self.deck_address(deck));
+        if !self.epsilon_bpm.passes(&addr, bpm) {
+            return;
+        }
+        if self.overlay_deck == Some(deck) {
+            self.send_float("/overlay/bpm/current", bpm);
+        }
+        if self.deck_enabled(deck) {
+            self.send_float(&addr, bpm);
+        }
     }
 
     fn original_bpm_changed_master(&mut self, bpm: f32) {
-        self.send_float("/master/bpm/original", bpm);
+        self.state.original_bpm = bpm;
+        if self.epsilon_bpm.passes("/master/bpm/original", bpm) {
+            self.send_float("/master/bpm/original", bpm);
+        }
     }
 
     fn original_bpm_changed(&mut self, bpm: f32, deck: usize) {
-        self.send_float(&format!("/{deck}/bpm/original"), bpm);
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        let addr = format!("/{}/bpm/original", self.deck_address(deck));
+        if self.epsilon_bpm.passes(&addr, bpm) {
+            self.send_float(&addr, bpm);
+        }
     }
 
-    fn bpm_changed(&mut self, bpm: f32, deck: usize) {
-        self.send_float(&format!("/bpm/{deck}/current"), bpm);
+    fn bpm_offset_changed(&mut self, offset: f32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_float(&format!("/deck/{}/bpm_offset", self.deck_address(deck)), offset);
+    }
+
+    fn beat_timestamp_master(&mut self, timestamp: SystemTime) {
+        self.beat_timestamp = timestamp;
     }
 
     fn beat_update_master(&mut self, beat: f32) {
+        self.state.beat = beat;
         if self.send_period_counter != 0 {
             return;
         }
 
-        for d in &self.message_toggles.beat_master_subdivs{
-            let value = (beat % d) / d;
-            self.send_float(&format!("/master/beat/subdiv/{d}"), value);
-        }
+        // Triggers are still checked for every beat, as they fire on crossings
+        let mut messages = if self.epsilon_beat.passes("/master/beat", beat) {
+            Self::subdiv_messages("/master", beat, &self.message_toggles.beat_master_subdivs, self.message_toggles.beat_master_combined)
+        } else {
+            vec![]
+        };
 
-        for d in &self.message_toggles.beat_master_triggers{
+        // The last beat is from before a jump, so any crossing would be a false trigger
+        let seeked = std::mem::take(&mut self.seeked_master);
+        let triggers: &[f32] = if seeked { &[] } else { &self.message_toggles.beat_master_triggers };
+        for d in triggers {
             if beat % d < self.last_beat_master % d {
-                self.send_float(&format!("/master/beat/trigger/{d}"), 1.);
+                messages.push(Self::float_message(&format!("/master/beat/trigger/{d}"), 1.));
             }else if self.message_toggles.beat_trigger_autorelease && (beat + d * 0.2) % d < (self.last_beat_master + d * 0.2) % d{
-                self.send_float(&format!("/master/beat/trigger/{d}"), 0.);
+                messages.push(Self::float_message(&format!("/master/beat/trigger/{d}"), 0.));
             }
         }
+        if self.message_toggles.downbeat && !seeked && beat < self.last_beat_master {
+            messages.push(OscPacket::Message(OscMessage { addr: "/downbeat".to_string(), args: vec![rosc::OscType::Int(1)] }));
+            self.downbeat_pulse.start();
+        }
         
         self.last_beat_master = beat;
+        self.send_beat_messages(messages);
+    }
+
+
+    fn absolute_beat_update_master(&mut self, beat: f32) {
+        if !self.message_toggles.link_style || self.send_period_counter != 0 {
+            return;
+        }
+        if !self.epsilon_beat.passes("/link/beat", beat) {
+            return;
+        }
+        let quantum = self.link_quantum.unwrap_or(self.bar_length as f32);
+        self.send_float("/link/beat", beat);
+        self.send_float("/link/phase", beat.rem_euclid(quantum));
+    }
+
+    fn beat_valid_changed(&mut self, valid: bool, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_int(&format!("/beat/{}/valid", self.deck_address(deck)), valid as i32);
+    }
+
+    fn beat_valid_changed_master(&mut self, valid: bool) {
+        self.send_int("/beat/master/valid", valid as i32);
+    }
+
+    fn bar_length_changed(&mut self, beats_per_bar: u32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_int(&format!("/beat/{}/bar_length", self.deck_address(deck)), beats_per_bar as i32);
+    }
+
+    fn bar_length_changed_master(&mut self, beats_per_bar: u32) {
+        self.bar_length = beats_per_bar;
+        self.send_int("/beat/master/bar_length", beats_per_bar as i32);
+    }
+
+    fn bar_countdown_changed(&mut self, beats: i32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_int(&format!("/beat/{}/bar_countdown", self.deck_address(deck)), beats);
+    }
+
+    fn bar_countdown_changed_master(&mut self, beats: i32) {
+        self.send_int("/beat/master/bar_countdown", beats);
+    }
+
+    fn sixteenth_changed(&mut self, step: i32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_int(&format!("/beat/{}/sixteenth", self.deck_address(deck)), step);
+    }
+
+    fn sixteenth_changed_master(&mut self, step: i32) {
+        self.send_int("/beat/master/sixteenth", step);
+    }
+
+    fn seek_detected(&mut self, deck: usize) {
+        self.seeked[deck] = true;
+        if deck == self.state.masterdeck_index {
+            self.seeked_master = true;
+        }
     }
 
+    fn phase_offset_changed(&mut self, offset: f32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        if self.send_period_counter != 0 {
+            return;
+        }
+        self.send_float(&format!("/deck/{}/phase_offset", self.deck_address(deck)), offset);
+    }
 
     fn time_update_master(&mut self, time: f32) {
+        self.state.time = time;
         if self.send_period_counter != 0 {
             return;
         }
-        if self.message_toggles.time_master{
+        if self.message_toggles.time_master && self.epsilon_time.passes("/master/time", time) {
             self.send_float("/master/time", time);
         }
     }
 
     fn beat_update(&mut self, beat: f32, deck: usize) {
+        self.deck_states[deck].beat = beat;
         if self.send_period_counter != 0 {
             return;
         }
-
-        for d in &self.message_toggles.beat_subdivs{
-            let value = (beat % d) / d;
-            self.send_float(&format!("/{deck}/beat/subdiv/{d}"), value);
+        let prefix = format!("/{}", self.deck_address(deck));
+        if self.epsilon_beat.passes(&prefix, beat) {
+            if self.overlay_deck == Some(deck) {
+                self.send_float("/overlay/beat", beat);
+            }
+            if self.deck_enabled(deck) {
+                for msg in Self::subdiv_messages(&prefix, beat, &self.message_toggles.beat_subdivs, self.message_toggles.beat_combined) {
+                    self.send(msg);
+                }
+            }
         }
 
 
-        for d in &self.message_toggles.beat_triggers{
+        let triggers: &[f32] = if self.seeked[deck] || !self.deck_enabled(deck) { &[] } else { &self.message_toggles.beat_triggers };
+        self.seeked[deck] = false;
+        for d in triggers {
             if beat % d < self.last_beats[deck] % d {
-                self.send_float(&format!("/{deck}/beat/trigger/{d}"), 1.);
+                self.send_float(&format!("/{}/beat/trigger/{d}", self.deck_address(deck)), 1.);
             }else if self.message_toggles.beat_trigger_autorelease && (beat + d * 0.2) % d < (self.last_beats[deck] + d * 0.2) % d{
-                self.send_float(&format!("/{deck}/beat/trigger/{d}"), 0.);
+                self.send_float(&format!("/{}/beat/trigger/{d}", self.deck_address(deck)), 0.);
             }
         }
         self.last_beats[deck] = beat;
     }
 
     fn time_update(&mut self, time: f32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
         if self.send_period_counter != 0 {
             return;
         }
-        if self.message_toggles.time{
-            self.send_float(&format!("/{deck}/time"), time);
+        let addr = format!("/{}/time", self.deck_address(deck));
+        if self.message_toggles.time && self.epsilon_time.passes(&addr, time) {
+            self.send_float(&addr, time);
         }
     }
 
     fn track_changed(&mut self, track: &TrackInfo, deck: usize) {
-        self.send_string(&format!("/{deck}/track/title"), &track.title);
-        self.send_string(&format!("/{deck}/track/artist"), &track.artist);
-        self.send_string(&format!("/{deck}/track/album"), &track.album);
+        self.deck_states[deck].track = track.clone();
+        if self.overlay_deck == Some(deck) {
+            self.send_string("/overlay/track/title", &track.title);
+            self.send_string("/overlay/track/artist", &track.artist);
+            self.send_string("/overlay/track/album", &track.album);
+        }
+        if self.deck_enabled(deck) {
+            self.send_string(&format!("/{}/track/title", self.deck_address(deck)), &track.title);
+            self.send_string(&format!("/{}/track/artist", self.deck_address(deck)), &track.artist);
+            self.send_string(&format!("/{}/track/album", self.deck_address(deck)), &track.album);
+            self.send_string(&format!("/{}/track/genre", self.deck_address(deck)), &track.genre);
+            self.send_string(&format!("/{}/track/comment", self.deck_address(deck)), &track.comment);
+        }
+
+        if self.message_toggles.decks_overview {
+            let titles: Vec<&str> = self.deck_states.iter().map(|state| state.track.title.as_str()).collect();
+            self.send_strings("/decks/titles", &titles);
+        }
     }
 
     fn track_changed_master(&mut self, track: &TrackInfo) {
+        self.state.track = track.clone();
         self.send_string("/master/track/title", &track.title);
         self.send_string("/master/track/artist", &track.artist);
         self.send_string("/master/track/album", &track.album);
+        self.send_string("/master/track/genre", &track.genre);
+        self.send_string("/master/track/comment", &track.comment);
+    }
+
+    fn preview_track_changed(&mut self, track: &TrackInfo) {
+        self.send_string("/track/preview/title", &track.title);
+        self.send_string("/track/preview/artist", &track.artist);
+        self.send_string("/track/preview/album", &track.album);
+        self.send_string("/track/preview/genre", &track.genre);
+        self.send_string("/track/preview/comment", &track.comment);
+    }
+
+    fn track_change_reason_master(&mut self, reason: MasterChangeReason) {
+        self.send_int("/track/master/change_reason", reason as i32);
+    }
+
+    fn artwork_changed(&mut self, path: &str, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_string(&format!("/track/{}/artwork", self.deck_address(deck)), path);
+    }
+
+    fn artwork_changed_master(&mut self, path: &str) {
+        self.send_string("/track/master/artwork", path);
+    }
+
+    fn analysis_status_changed(&mut self, status: AnalysisStatus, deck: usize) {
+        let ready = status == AnalysisStatus::Ready;
+        if let Some(deck_ready) = self.analysis_ready.get_mut(deck) {
+            *deck_ready = ready;
+        }
+        if self.deck_enabled(deck) {
+            self.send_int(&format!("/deck/{}/analysis", self.deck_address(deck)), status as i32);
+            self.send_int(&format!("/deck/{}/analysis_ready", self.deck_address(deck)), ready as i32);
+        }
+        if deck == self.state.masterdeck_index {
+            self.send_int("/analysis/master/ready", ready as i32);
+        }
+    }
+
+    fn play_state_changed(&mut self, playing: bool, deck: usize) {
+        // Only sent in the snapshot
+        self.deck_states[deck].playing = playing;
+    }
+
+    fn on_air_changed(&mut self, on_air: bool, deck: usize) {
+        self.deck_states[deck].on_air = on_air;
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_int(&format!("/deck/{}/onair", self.deck_address(deck)), on_air as i32);
+    }
+
+    fn cue_monitor_changed(&mut self, cue: bool, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_int(&format!("/deck/{}/cue", self.deck_address(deck)), cue as i32);
+    }
+
+    fn performance_state_changed(&mut self, state: PerfState, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_int(&format!("/deck/{}/perf", self.deck_address(deck)), state as i32);
+    }
+
+    fn song_structure_loaded(&mut self, phrases: &[(u16, String)], deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        if !self.message_toggles.song_structure {
+            return;
+        }
+        let mut content = vec![OscPacket::Message(OscMessage {
+            addr: format!("/song_structure/{}/count", self.deck_address(deck)),
+            args: vec![rosc::OscType::Int(phrases.len() as i32)],
+        })];
+        for (i, (beat, name)) in phrases.iter().enumerate() {
+            content.push(OscPacket::Message(OscMessage {
+                addr: format!("/song_structure/{}/phrase", self.deck_address(deck)),
+                args: vec![rosc::OscType::Int(i as i32), rosc::OscType::Int(*beat as i32), rosc::OscType::String(name.clone())],
+            }));
+        }
+        self.send(OscPacket::Bundle(OscBundle { timetag: OscTime::from((0, 1)), content }));
     }
 
     fn anlz_path_changed(&mut self, path: &str, deck: usize) {
-        self.send_string(&format!("/track/{deck}/anlz_path"), path);
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_string(&format!("/track/{}/anlz_path", self.deck_address(deck)), path);
     }
 
     fn masterdeck_index_changed(&mut self, index: usize) {
-        self.send_int("/masterdeck/index", index as i32);
+        self.state.masterdeck_index = index;
+        self.send_int(&self.masterdeck_address, self.deck_number(index));
+        self.send_int("/analysis/master/ready", self.master_analysis_ready() as i32);
+    }
+
+    fn master_changed(&mut self, index: usize, track: &TrackInfo) {
+        self.send(OscPacket::Message(OscMessage {
+            addr: "/master".to_string(),
+            args: vec![
+                rosc::OscType::Int(self.deck_number(index)),
+                rosc::OscType::String(track.title.clone()),
+                rosc::OscType::String(track.artist.clone()),
+            ],
+        }));
+    }
+
+    fn idle_changed(&mut self, idle: bool) {
+        self.send_int("/status/idle", idle as i32);
+    }
+
+    fn connection_changed(&mut self, connected: bool) {
+        self.connected = connected;
+        if connected {
+            self.send_int("/status/connected", 1);
+            self.send_deck_colors();
+        } else {
+            self.send_disconnect();
+            // The receiver may hold other values now, eg. zeros, so the next values are sent as they are
+            for filter in [&mut self.epsilon_bpm, &mut self.epsilon_beat, &mut self.epsilon_time] {
+                filter.last_sent.clear();
+            }
+        }
+    }
+
+    fn reconnected(&mut self) {
+        // Receivers may have dropped values while Rekordbox was gone, eg. after a reset
+        self.send(self.state_bundle());
+    }
+
+    fn shutdown(&mut self) {
+        if self.on_disconnect != DisconnectPolicy::Hold {
+            self.send_disconnect();
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.send_failures.get() >= MAX_SEND_FAILURES
+    }
+
+    fn stats(&self) -> Option<String> {
+        Some(format!("{} sent", self.sent.get()))
+    }
+
+    fn idle_heartbeat(&mut self) {
+        self.send_int("/status/connected", self.connected as i32);
     }
 
     fn slow_update(&mut self) {
         if !self.info_sent {
             self.info_sent = true;
 
-            let target_addr = if let Ok(addr) = self.socket.peer_addr() {
-                addr.to_string()
-            } else {
-                "No target!!".to_string()
-            };
+            let target_addr = self.destination.to_string();
 
             let source_addr = if let Ok(addr) = self.socket.local_addr() {
                 addr.to_string()
@@ -308,13 +1417,25 @@ impl OutputModule for Osc {
             }
     }
 
+    fn very_slow_update(&mut self) {
+        if self.message_toggles.snapshot {
+            self.send(self.snapshot_bundle());
+        }
+    }
+
     fn phrase_changed_master(&mut self, phrase: &str) {
+        self.state.phrase = phrase.to_string();
         if self.message_toggles.phrase_master{
             self.output_phrase("/master/phrase/current", phrase);
         }
+        let segment = address_segment(phrase);
+        if self.message_toggles.phrase_bangs && !segment.is_empty() {
+            self.send_int(&format!("/phrase/master/{segment}"), 1);
+        }
     }
 
     fn next_phrase_changed_master(&mut self, phrase: &str) {
+        self.state.next_phrase = phrase.to_string();
         if self.message_toggles.phrase_master{
             self.output_phrase("/master/phrase/next", phrase);
         }
@@ -326,21 +1447,64 @@ impl OutputModule for Osc {
         }
     }
 
+    fn mood_changed(&mut self, mood: u8, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        if self.message_toggles.phrase{
+            self.send_int(&format!("/phrase/{}/mood", self.deck_address(deck)), mood as i32);
+        }
+    }
+
+    fn mood_changed_master(&mut self, mood: u8) {
+        if self.message_toggles.phrase_master{
+            self.send_int("/phrase/master/mood", mood as i32);
+        }
+    }
+
+    fn energy_changed(&mut self, energy: f32) {
+        self.send_float("/energy/master", energy);
+    }
+
+    fn grid_origin_changed(&mut self, seconds: f32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
+        self.send_float(&format!("/deck/{}/grid_origin", self.deck_address(deck)), seconds);
+    }
+
+    fn crossfader_changed(&mut self, position: f32) {
+        self.send_float("/mixer/crossfader", position);
+    }
+
+    fn end_warning(&mut self, ending: bool) {
+        self.send_int("/track/master/ending", ending as i32);
+    }
+
     fn phrase_changed(&mut self, phrase: &str, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
         if self.message_toggles.phrase{
-            self.output_phrase(&format!("/{deck}/phrase/current"), phrase);
+            self.output_phrase(&format!("/{}/phrase/current", self.deck_address(deck)), phrase);
         }
     }
 
     fn next_phrase_changed(&mut self, phrase: &str, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
         if self.message_toggles.phrase{
-            self.output_phrase(&format!("/{deck}/phrase/next"), phrase);
+            self.output_phrase(&format!("/{}/phrase/next", self.deck_address(deck)), phrase);
         }
     }
 
     fn next_phrase_in(&mut self, beats: i32, deck: usize) {
+        if !self.deck_enabled(deck) {
+            return;
+        }
         if self.message_toggles.phrase{
-            self.send_float(&format!("/{deck}/phrase/countin"), beats as f32);
+            self.send_float(&format!("/{}/phrase/countin", self.deck_address(deck)), beats as f32);
         }
     }
 }
@@ -354,3 +1518,201 @@ impl Osc{
         }
     }
 }
+
+/// A name as a single address segment, eg. "Verse 1" to "verse_1". Characters other than letters,
+/// digits and underscores are left out, as receivers may treat them as patterns
+fn address_segment(name: &str) -> String {
+    name.trim()
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('_'),
+            c if c.is_alphanumeric() || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phrase_names_become_address_segments() {
+        assert_eq!(address_segment("Verse 1"), "verse_1");
+        assert_eq!(address_segment("Chorus"), "chorus");
+        assert_eq!(address_segment("Drop!/*"), "drop");
+        assert_eq!(address_segment(""), "");
+    }
+
+    #[test]
+    fn epsilon_holds_back_small_changes_per_address() {
+        let mut filter = EpsilonFilter::new(0.05);
+        assert!(filter.passes("/master/bpm/current", 128.));
+        assert!(!filter.passes("/master/bpm/current", 128.04));
+        // Compared with the last sent value, so slow drifts are sent eventually
+        assert!(filter.passes("/master/bpm/current", 128.06));
+        assert!(filter.passes("/1/bpm/current", 128.06));
+
+        let mut disabled = EpsilonFilter::new(0.);
+        assert!(disabled.passes("/master/time", 1.));
+        assert!(disabled.passes("/master/time", 1.));
+    }
+
+    #[test]
+    fn valid_addresses_are_kept() {
+        for address in ["", "/rkbx", "/stage/left", "/a_b-c.1"] {
+            assert_eq!(validate_address(address, AddressValidation::Strict), Ok(address.to_string()));
+        }
+        assert_eq!(validate_address("/rkbx/", AddressValidation::Strict), Ok("/rkbx".to_string()));
+    }
+
+    #[test]
+    fn strict_rejects_illegal_addresses() {
+        for address in ["/my stage", "/deck#1", "/a*", "/what?", "/[ab]", "/{a,b}", "/tab\t", "/bühne", "rkbx"] {
+            assert!(validate_address(address, AddressValidation::Strict).is_err(), "{address}");
+        }
+    }
+
+    #[test]
+    fn lenient_escapes_illegal_addresses() {
+        assert_eq!(validate_address("/my stage", AddressValidation::Lenient), Ok("/my_stage".to_string()));
+        assert_eq!(validate_address("/{a,b}", AddressValidation::Lenient), Ok("/_a_b_".to_string()));
+        assert_eq!(validate_address("rkbx", AddressValidation::Lenient), Ok("/rkbx".to_string()));
+    }
+
+    #[test]
+    fn string_encodings() {
+        assert_eq!(StringEncoding::Utf8.encode("Café"), ("Café".as_bytes().to_vec(), false));
+        assert_eq!(StringEncoding::Latin1.encode("Café"), (b"Caf\xE9".to_vec(), false));
+        assert_eq!(StringEncoding::Ascii.encode("Café"), (b"Caf?".to_vec(), true));
+        assert_eq!(StringEncoding::Latin1.encode("夜に駆ける"), (b"?????".to_vec(), true));
+    }
+
+    #[test]
+    fn osc_strings_are_padded() {
+        let mut packet = vec![];
+        push_osc_string(&mut packet, b",s");
+        assert_eq!(packet, b",s\0\0");
+        push_osc_string(&mut packet, b"abcd");
+        assert_eq!(packet, b",s\0\0abcd\0\0\0\0");
+    }
+
+    #[test]
+    fn deck_and_master_divisions_match() {
+        let args = |packets: Vec<OscPacket>| -> Vec<f32> {
+            packets
+                .into_iter()
+                .map(|packet| match packet {
+                    OscPacket::Message(OscMessage { args, .. }) => match args[..] {
+                        [rosc::OscType::Float(value)] => value,
+                        _ => panic!("Expected a single float"),
+                    },
+                    OscPacket::Bundle(_) => panic!("Expected a message"),
+                })
+                .collect()
+        };
+        let divisions = [1., 2., 4., 0.5, 3.];
+        for beat in [0., 0.25, 1.5, 2.75, 3.999] {
+            let master = args(Osc::subdiv_messages("/master", beat, &divisions, false));
+            let deck = args(Osc::subdiv_messages("/2", beat, &divisions, false));
+            assert_eq!(master, deck);
+            for value in master {
+                assert!((0. ..1.).contains(&value), "{value} for beat {beat}");
+            }
+        }
+        assert_eq!(args(Osc::subdiv_messages("/0", 3., &[2., 4.], false)), vec![0.5, 0.75]);
+    }
+
+    #[test]
+    fn combined_divisions_follow_the_beat() {
+        let packets = Osc::subdiv_messages("/master", 3., &[2., 4.], true);
+        let [OscPacket::Message(msg)] = &packets[..] else {
+            panic!("Expected a single message");
+        };
+        assert_eq!(msg.addr, "/master/beat");
+        assert_eq!(msg.args, [rosc::OscType::Float(3.), rosc::OscType::Float(0.5), rosc::OscType::Float(0.75)]);
+    }
+
+    #[test]
+    fn destination_follows_source_family() {
+        let v4: SocketAddr = "127.0.0.1:4460".parse().unwrap();
+        let v6: SocketAddr = "[::1]:4460".parse().unwrap();
+        let local_v4: SocketAddr = "0.0.0.0:4450".parse().unwrap();
+        let local_v6: SocketAddr = "[::]:4450".parse().unwrap();
+        assert_eq!(matching_family(&[v6, v4], &local_v4), Some(v4));
+        assert_eq!(matching_family(&[v6, v4], &local_v6), Some(v6));
+        assert_eq!(matching_family(&[v6], &local_v4), None);
+    }
+
+    /// An OSC module sending to a local socket, and a function returning the address of the next
+    /// message it receives
+    fn osc_with_receiver(entries: &[(&str, &str)]) -> (Box<dyn OutputModule>, impl Fn() -> String) {
+        let (receiver, destination) = crate::outputmodules::test_receiver();
+        let mut all_entries = vec![("osc.source", "127.0.0.1:0"), ("osc.destination", destination.as_str())];
+        all_entries.extend_from_slice(entries);
+        let conf = Config::from_entries(&all_entries).reduce_to_namespace("osc");
+        let logger = conf.logger.clone();
+        let osc = Osc::create(conf, logger).unwrap();
+        let received = move || {
+            let mut buf = [0u8; rosc::decoder::MTU];
+            let size = receiver.recv(&mut buf).unwrap();
+            match decode_udp(&buf[..size]).unwrap().1 {
+                OscPacket::Message(msg) => msg.addr,
+                OscPacket::Bundle(_) => panic!("Expected a message"),
+            }
+        };
+        (osc, received)
+    }
+
+    #[test]
+    fn deck_offset_only_shifts_deck_addresses() {
+        let (mut osc, received) = osc_with_receiver(&[("osc.deck_offset", "1")]);
+
+        osc.bpm_changed(120., 0);
+        assert_eq!(received(), "/1/bpm/current");
+        osc.beat_valid_changed(true, 3);
+        assert_eq!(received(), "/beat/4/valid");
+        osc.on_air_changed(true, 1);
+        assert_eq!(received(), "/deck/2/onair");
+        osc.bpm_changed_master(120.);
+        assert_eq!(received(), "/master/bpm/current");
+        osc.bar_countdown_changed_master(4);
+        assert_eq!(received(), "/beat/master/bar_countdown");
+    }
+
+    #[test]
+    fn unlisted_decks_are_left_out() {
+        let (mut osc, received) = osc_with_receiver(&[("osc.msg.decks", "0,1")]);
+
+        osc.bpm_changed(120., 2);
+        osc.on_air_changed(true, 3);
+        osc.bpm_changed(120., 1);
+        assert_eq!(received(), "/1/bpm/current");
+        osc.bpm_changed(120., 3);
+        osc.bpm_changed_master(120.);
+        assert_eq!(received(), "/master/bpm/current");
+    }
+
+    #[test]
+    fn address_patterns() {
+        assert!(address_matches("/master/bpm/current", "/master/bpm/current"));
+        assert!(address_matches("/*/bpm/current", "/2/bpm/current"));
+        assert!(!address_matches("/*/bpm/current", "/master/bpm/original"));
+        assert!(!address_matches("/master/bpm", "/master/bpm/current"));
+        assert!(!address_matches("/master/bpm/current", "/master/bpm"));
+    }
+
+    #[test]
+    fn arguments_are_coerced() {
+        use rosc::OscType;
+        assert_eq!(coerce_arg(OscType::Float(127.9), &OutputFormat::Int), OscType::Int(127));
+        assert_eq!(coerce_arg(OscType::Int(3), &OutputFormat::Float), OscType::Float(3.));
+        assert_eq!(coerce_arg(OscType::Float(1.5), &OutputFormat::String), OscType::String("1.5".to_string()));
+        assert_eq!(coerce_arg(OscType::String("128".to_string()), &OutputFormat::Int), OscType::Int(128));
+        assert_eq!(
+            coerce_arg(OscType::String("Chorus".to_string()), &OutputFormat::Float),
+            OscType::String("Chorus".to_string())
+        );
+    }
+}
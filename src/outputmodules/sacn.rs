@@ -1,11 +1,15 @@
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
 
 use sacn::packet::ACN_SDT_MULTICAST_PORT;
 use sacn::source::SacnSource;
 
-use crate::{config::Config, log::ScopedLogger};
+use crate::{config::Config, log::ScopedLogger, utils::PhraseParser};
+use super::DisconnectPolicy;
 use super::ModuleCreateOutput;
 use super::OutputModule;
+use super::Pulse;
 
 /// sACN (E1.31) output module
 ///
@@ -17,11 +21,19 @@ use super::OutputModule;
 /// - `targets` (String): comma-separated IPv4 list for unicast. Example: "192.168.0.50,192.168.0.51".
 /// - `priority` (u8): sACN priority 1..200, default 100.
 /// - `source_name` (String): up to 63 ASCII chars shown by receivers. Default: "rkbx_link".
+/// - `scene.<phrase>` (String): channel values output while the master deck is in that phrase, e.g. "1=255,5=128".
+///   `<phrase>` is a phrase group (intro, verse, chorus, bridge, outro, none) or a phrase name such as up_1 or
+///   an alias, which takes precedence over the scene of its group.
+/// - `scene_fade_ms` (u64): crossfade between scenes, default 0.
+/// - `downbeat_channel` (u16): absolute channel set to 255 on every master downbeat. Empty to disable.
+/// - `pulse_width_ms` (u64): how long the downbeat channel stays at 255, default 100.
+/// - `on_disconnect` (String): "hold" (default), "zero" for the BPM, beat and downbeat slots, or "reset" for every channel.
 ///
 /// Slot mapping (starting at `start_channel`):
 /// - +0 : BPM (u8). Capped to 250. Values > 250 are sent as 250.
 /// - +1 : Beat absolute counter (u8). Wraps 0..=255.
 ///
+/// Scene channels are absolute, and can't overlap the two slots above.
 pub struct Sacn {
     src: SacnSource,
     mode: Mode,
@@ -34,6 +46,29 @@ pub struct Sacn {
     logger: ScopedLogger,
     last_beat_floor: i32,
     beat_counter: u8,
+    last_slot: usize, // Highest slot in use
+    scenes: HashMap<String, Vec<(usize, u8)>>, // By lowercase phrase name or group, eg. "up_1" or "verse"
+    scene_channels: Vec<usize>, // Every channel used by any scene
+    scene_fade: Duration,
+    fade_from: Vec<u8>, // Per scene channel
+    fade_to: Vec<u8>,
+    fade_start: Option<Instant>,
+    downbeat_slot: Option<usize>,
+    downbeat_pulse: Pulse,
+    last_beat: f32,
+    on_disconnect: DisconnectPolicy,
+    sent: u64, // Packets per universe and target, for stats
+}
+
+/// Phrase groups as used in the scene config keys, indexed by PhraseParser::phrase_name_to_index
+const SCENE_NAMES: [&str; 6] = ["none", "intro", "verse", "chorus", "bridge", "outro"];
+
+/// The scene of a phrase, eg. `scene.up_1` for "Up 1", or else the scene of its group, eg. `scene.verse`
+fn scene_for<'a>(scenes: &'a HashMap<String, Vec<(usize, u8)>>, phrase: &str) -> Option<&'a [(usize, u8)]> {
+    scenes
+        .get(&phrase.to_lowercase().replace(' ', "_"))
+        .or_else(|| scenes.get(SCENE_NAMES[PhraseParser::phrase_name_to_index(phrase) as usize]))
+        .map(Vec::as_slice)
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -147,9 +182,50 @@ impl Sacn
             targets
         ));
 
+        // Scenes
+        let mut scenes = HashMap::new();
+        // Empty scenes are left out, so a phrase without its own scene falls back to its group
+        for (name, value) in conf.entries_with_prefix("scene.").into_iter().filter(|(_, value)| !value.trim().is_empty()) {
+            let mut scene = vec![];
+            for entry in value.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+                let parsed = entry
+                    .split_once('=')
+                    .and_then(|(channel, value)| Some((channel.trim().parse::<usize>().ok()?, value.trim().parse::<u8>().ok()?)));
+                match parsed {
+                    Some((channel, _)) if !(1..=512).contains(&channel) => {
+                        logger.err(&format!("Scene {name}: channel {channel} is outside 1..=512"));
+                    }
+                    Some((channel, _)) if channel == start_slot || channel == start_slot + 1 => {
+                        logger.err(&format!("Scene {name}: channel {channel} is used for the BPM and beat"));
+                    }
+                    Some(channel_value) => scene.push(channel_value),
+                    None => logger.err(&format!("Scene {name}: invalid entry '{entry}', expected <channel>=<value>")),
+                }
+            }
+            scenes.insert(name.to_lowercase(), scene);
+        }
+        let mut scene_channels: Vec<usize> = scenes.values().flatten().map(|(channel, _)| *channel).collect();
+        scene_channels.sort();
+        scene_channels.dedup();
+
+        let downbeat_slot = match conf.get_or_default("downbeat_channel", String::new()).parse::<usize>() {
+            Ok(channel) if !(1..=512).contains(&channel) => {
+                logger.err(&format!("Downbeat channel {channel} is outside 1..=512"));
+                None
+            }
+            Ok(channel) if channel == start_slot || channel == start_slot + 1 || scene_channels.contains(&channel) => {
+                logger.err(&format!("Downbeat channel {channel} is already used for the BPM, beat or a scene"));
+                None
+            }
+            Ok(channel) => Some(channel),
+            Err(_) => None,
+        };
+        let last_slot = scene_channels.iter().copied().chain(downbeat_slot).chain([start_slot + 1]).max().unwrap_or(start_slot + 1).min(512);
+
         // DMX buffer (start code + 512 slots)
         let mut dmx = [0u8; 513];
         dmx[0] = 0x00; // start code
+        let on_disconnect = DisconnectPolicy::from_config(&conf, DisconnectPolicy::Hold, &logger);
 
         Ok(Box::new(Sacn {
             src,
@@ -163,27 +239,46 @@ impl Sacn
             logger,
             last_beat_floor: i32::MIN,
             beat_counter: 0,
+            last_slot,
+            fade_from: vec![0; scene_channels.len()],
+            fade_to: vec![0; scene_channels.len()],
+            scenes,
+            scene_channels,
+            scene_fade: Duration::from_millis(conf.get_or_default("scene_fade_ms", 0)),
+            fade_start: None,
+            downbeat_slot,
+            downbeat_pulse: Pulse::new(Duration::from_millis(conf.get_or_default("pulse_width_ms", 100))),
+            last_beat: 0.,
+            on_disconnect,
+            sent: 0,
         }))
     }
 
     fn send(&mut self) {
         //only send up to the bytes we actually use (using a low start_slot prevents sending the whole universe on update)
-        let last_slot = (self.start_slot + 1).min(512);
-        let len = 1 + last_slot; // +1 for start code
+        let len = 1 + self.last_slot; // +1 for start code
         let data: &[u8] = &self.dmx[..len];
 
         match self.mode {
             Mode::Multicast => {
-                let _ = self
+                if self
                     .src
-                    .send(&[self.universe], data, Some(self.priority), None, None);
+                    .send(&[self.universe], data, Some(self.priority), None, None)
+                    .is_ok()
+                {
+                    self.sent += 1;
                 }
+            }
             Mode::Unicast => {
                 for &dst in &self.targets {
-                    let _ = self
+                    if self
                         .src
-                        .send(&[self.universe], data, Some(self.priority), Some(dst), None);
+                        .send(&[self.universe], data, Some(self.priority), Some(dst), None)
+                        .is_ok()
+                    {
+                        self.sent += 1;
                     }
+                }
             }
         }
 
@@ -205,6 +300,46 @@ impl Sacn
 
 
 
+    /// Step the crossfade to the current scene, and send while it's running
+    fn update_fade(&mut self) {
+        let Some(start) = self.fade_start else {
+            return;
+        };
+        let progress = if self.scene_fade.is_zero() {
+            1.
+        } else {
+            (start.elapsed().as_secs_f32() / self.scene_fade.as_secs_f32()).min(1.)
+        };
+        for i in 0..self.scene_channels.len() {
+            let (from, to) = (self.fade_from[i] as f32, self.fade_to[i] as f32);
+            self.write_u8_slot(self.scene_channels[i], (from + (to - from) * progress).round() as u8);
+        }
+        if progress >= 1. {
+            self.fade_start = None;
+        }
+        self.send();
+    }
+
+    /// Apply sacn.on_disconnect
+    fn send_disconnect(&mut self) {
+        match self.on_disconnect {
+            DisconnectPolicy::Hold => return,
+            DisconnectPolicy::Zero => {
+                for slot in [self.start_slot, self.start_slot + 1].into_iter().chain(self.downbeat_slot) {
+                    self.write_u8_slot(slot, 0);
+                }
+            }
+            DisconnectPolicy::Reset => {
+                // Scenes too, and a running fade would bring them back
+                self.fade_start = None;
+                self.dmx[1..].fill(0);
+            }
+        }
+        self.beat_counter = 0;
+        self.last_beat_floor = i32::MIN;
+        self.send();
+    }
+
     #[inline]
     fn write_u8_slot(&mut self, slot_1based: usize, value: u8) {
         // DMX slots live at dmx[1..=512]. slot_1based in 1..=512
@@ -215,6 +350,32 @@ impl Sacn
 }
 
 impl OutputModule for Sacn {
+    fn pre_update(&mut self) {
+        self.update_fade();
+        if let Some(slot) = self.downbeat_slot {
+            if self.downbeat_pulse.off_due() {
+                self.write_u8_slot(slot, 0);
+                self.send();
+            }
+        }
+    }
+
+    fn phrase_changed_master(&mut self, phrase: &str) {
+        if self.scene_channels.is_empty() {
+            return;
+        }
+        let scene = scene_for(&self.scenes, phrase).unwrap_or_default();
+        // Channels of other scenes go dark
+        self.fade_to = self
+            .scene_channels
+            .iter()
+            .map(|channel| scene.iter().find(|(c, _)| c == channel).map(|(_, value)| *value).unwrap_or(0))
+            .collect();
+        self.fade_from = self.scene_channels.iter().map(|channel| self.dmx[*channel]).collect();
+        self.fade_start = Some(Instant::now());
+        self.update_fade();
+    }
+
     fn bpm_changed_master(&mut self, bpm: f32){
         let mut v = bpm.round() as i32;
         v = v.clamp(0, 250);
@@ -224,6 +385,15 @@ impl OutputModule for Sacn {
 
     fn beat_update_master(&mut self, beat: f32){
         let floor_now = beat.floor() as i32;
+
+        // New bar, sent together with the beat counter below
+        if let Some(slot) = self.downbeat_slot {
+            if beat < self.last_beat {
+                self.write_u8_slot(slot, 255);
+                self.downbeat_pulse.start();
+            }
+        }
+        self.last_beat = beat;
        
         if self.last_beat_floor != floor_now {
             self.last_beat_floor = floor_now;
@@ -234,9 +404,42 @@ impl OutputModule for Sacn {
         }
     }
 
+    fn connection_changed(&mut self, connected: bool) {
+        if !connected {
+            self.send_disconnect();
+        }
+    }
+
+    fn shutdown(&mut self) {
+        self.send_disconnect();
+    }
+
+    fn stats(&self) -> Option<String> {
+        Some(format!("{} sent", self.sent))
+    }
+
     fn slow_update(&mut self) {
         //this is done as a keepalive.
         //eventually add some info here like play/pause state, etc.
         self.send();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phrases_fall_back_to_the_scene_of_their_group() {
+        let scenes = HashMap::from([
+            ("verse".to_string(), vec![(1, 100)]),
+            ("up_1".to_string(), vec![(1, 200)]),
+            ("none".to_string(), vec![(2, 50)]),
+        ]);
+        assert_eq!(scene_for(&scenes, "Up 1"), Some(&[(1, 200)][..]));
+        assert_eq!(scene_for(&scenes, "Up 2"), Some(&[(1, 100)][..]));
+        assert_eq!(scene_for(&scenes, "Verse 3"), Some(&[(1, 100)][..]));
+        assert_eq!(scene_for(&scenes, ""), Some(&[(2, 50)][..]));
+        assert_eq!(scene_for(&scenes, "Down"), None);
+    }
+}
@@ -4,7 +4,7 @@ use crate::log::ScopedLogger;
 use crate::outputmodules::OutputModule;
 use std::fs::{File, OpenOptions};
 use std::io::{self, BufRead, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use super::ModuleCreateOutput;
 
@@ -14,7 +14,13 @@ pub struct Setlist {
     stopped: bool,
     filename: String,
     separator: String,
+    include_id: bool,
+    include_beat: bool,
+    absolute_beat: f32,
+    beat_valid: bool,
     last_trackinfo: Option<TrackInfo>,
+    min_play_time: Duration,
+    pending: Option<(TrackInfo, Instant, u64, Option<f32>)>, // Track that hasn't been master for min_play_time yet, when it became master, its timestamp and beat
 }
 
 impl Setlist {
@@ -24,10 +30,16 @@ impl Setlist {
         let mut setlist = Setlist {
             filename,
             separator: config.get_or_default("separator", " - ".to_string()),
+            include_id: config.get_or_default("include_id", false),
+            include_beat: config.get_or_default("include_beat", false),
+            absolute_beat: 0.,
+            beat_valid: false,
             stopped: true,
             start_time: 0,
             logger: logger.clone(),
             last_trackinfo: None,
+            min_play_time: Duration::from_secs_f32(config.get_or_default("min_play_seconds", 0.0f32).max(0.)),
+            pending: None,
         };
 
         if let Ok(file) = File::open("setlist.txt") {
@@ -85,38 +97,185 @@ impl Setlist {
         let seconds = seconds % 60;
         format!("{hours:02}:{minutes:02}:{seconds:02}")
     }
-}
 
-impl OutputModule for Setlist {
-    fn track_changed_master(&mut self, track: &TrackInfo) {
-        if self.stopped {
+    /// Write the pending track once it has been master long enough
+    fn write_pending(&mut self) {
+        let Some((_, since, _, _)) = &self.pending else {
+            return;
+        };
+        if since.elapsed() < self.min_play_time {
             return;
         }
-        if let Some(last_track) = &self.last_trackinfo {
-            if last_track == track {
-                return;
-            }
+        if let Some((track, _, elapsed_time, beat)) = self.pending.take() {
+            self.write_track(&track, elapsed_time, beat);
         }
+    }
+
+    fn write_track(&mut self, track: &TrackInfo, elapsed_time: u64, beat: Option<f32>) {
+        let id = match &track.id {
+            Some(id) if self.include_id => format!(" [{id}]"),
+            _ => String::new(),
+        };
+        let beat = match beat {
+            _ if !self.include_beat => String::new(),
+            Some(beat) => format!(" #{}", beat.floor() as i64),
+            None => " #-".to_string(),
+        };
+        self.write_line(&format!(
+            "{}{} {} {} {}{}",
+            Self::to_timestamp(elapsed_time),
+            beat,
+            track.artist,
+            self.separator,
+            track.title,
+            id
+        ));
+        self.last_trackinfo = Some(track.clone());
+    }
+
+    fn write_line(&self, line: &str) {
         if let Ok(mut file) = OpenOptions::new()
             .read(false)
             .append(true)
             .open(&self.filename)
         {
-            let elapsed_time = self.get_seconds() - self.start_time;
-
-            writeln!(
-                file,
-                "{} {} {} {}",
-                Self::to_timestamp(elapsed_time),
-                track.artist,
-                self.separator,
-                track.title
-            ).unwrap_or_else(|e| {
+            writeln!(file, "{line}").unwrap_or_else(|e| {
                 self.logger.err(&format!("Failed to write to setlist file: {e}"));
             });
         } else {
             self.logger.err("Failed to open setlist file for writing!");
         }
-        self.last_trackinfo = Some(track.clone());
+    }
+}
+
+impl OutputModule for Setlist {
+    fn absolute_beat_update_master(&mut self, beat: f32) {
+        self.absolute_beat = beat;
+    }
+
+    fn beat_valid_changed_master(&mut self, valid: bool) {
+        self.beat_valid = valid;
+    }
+
+    fn track_changed_master(&mut self, track: &TrackInfo) {
+        if self.stopped {
+            return;
+        }
+        if let Some(last_track) = &self.last_trackinfo {
+            if last_track == track {
+                // Back to the logged track before the previewed one was played long enough
+                self.pending = None;
+                return;
+            }
+        }
+        if self.pending.as_ref().is_some_and(|(pending, _, _, _)| pending == track) {
+            return;
+        }
+        // A track that wasn't played long enough is discarded
+        let elapsed_time = self.get_seconds() - self.start_time;
+        // Tracks without a beatgrid have no meaningful beat count
+        let beat = self.beat_valid.then_some(self.absolute_beat);
+        self.pending = Some((track.clone(), Instant::now(), elapsed_time, beat));
+        self.write_pending();
+    }
+
+    fn reconnected(&mut self) {
+        if self.stopped {
+            return;
+        }
+        // Marks the gap, as tracks played meanwhile are missing
+        let elapsed_time = self.get_seconds() - self.start_time;
+        self.write_line(&format!("{} -- Rekordbox reconnected --", Self::to_timestamp(elapsed_time)));
+    }
+
+    fn slow_update(&mut self) {
+        if !self.stopped {
+            self.write_pending();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A new setlist in the temp directory, started at time 0 like `create` does
+    fn setlist(name: &str, min_play_time: Duration) -> Setlist {
+        let path = std::env::temp_dir().join(format!("rkbx_link_setlist_{}_{name}.txt", std::process::id()));
+        std::fs::write(&path, "0\n").unwrap();
+        Setlist {
+            start_time: 0,
+            logger: ScopedLogger::new(&std::rc::Rc::new(crate::log::Logger::new(false)), "Test"),
+            stopped: false,
+            filename: path.to_string_lossy().to_string(),
+            separator: "-".to_string(),
+            include_id: false,
+            include_beat: false,
+            absolute_beat: 0.,
+            beat_valid: false,
+            last_trackinfo: None,
+            min_play_time,
+            pending: None,
+        }
+    }
+
+    fn track(title: &str) -> TrackInfo {
+        TrackInfo {
+            title: title.to_string(),
+            ..TrackInfo::default()
+        }
+    }
+
+    /// Titles written so far, in order
+    fn written(setlist: &Setlist) -> Vec<String> {
+        std::fs::read_to_string(&setlist.filename)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| Some(line.rsplit_once(" - ")?.1.trim().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn tracks_are_written_right_away_without_min_play_time() {
+        let mut setlist = setlist("immediate", Duration::ZERO);
+        setlist.track_changed_master(&track("A"));
+        setlist.track_changed_master(&track("A"));
+        setlist.track_changed_master(&track("B"));
+        setlist.track_changed_master(&track("A"));
+        assert_eq!(written(&setlist), ["A", "B", "A"]);
+    }
+
+    #[test]
+    fn tracks_are_written_on_slow_update_once_played_long_enough() {
+        let mut setlist = setlist("deferred", Duration::from_millis(50));
+        setlist.track_changed_master(&track("A"));
+        setlist.slow_update();
+        assert!(written(&setlist).is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        setlist.slow_update();
+        assert_eq!(written(&setlist), ["A"]);
+    }
+
+    #[test]
+    fn tracks_taken_over_too_soon_are_discarded() {
+        let mut setlist = setlist("discarded", Duration::from_millis(50));
+        setlist.track_changed_master(&track("A"));
+        setlist.track_changed_master(&track("B"));
+        std::thread::sleep(Duration::from_millis(60));
+        setlist.slow_update();
+        assert_eq!(written(&setlist), ["B"]);
+    }
+
+    #[test]
+    fn returning_to_the_logged_track_drops_the_pending_one() {
+        let mut setlist = setlist("returned", Duration::from_millis(50));
+        setlist.track_changed_master(&track("A"));
+        std::thread::sleep(Duration::from_millis(60));
+        setlist.slow_update();
+        setlist.track_changed_master(&track("B"));
+        setlist.track_changed_master(&track("A"));
+        std::thread::sleep(Duration::from_millis(60));
+        setlist.slow_update();
+        assert_eq!(written(&setlist), ["A"]);
     }
 }
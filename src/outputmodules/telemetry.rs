@@ -0,0 +1,98 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{config::Config, log::ScopedLogger};
+
+use super::{ModuleCreateOutput, OutputModule};
+
+/// Appends master tempo samples to a CSV file, to plot the tempo curve of a set afterwards
+pub struct Telemetry {
+    writer: BufWriter<std::fs::File>,
+    logger: ScopedLogger,
+    sample_period: i32,
+    flush_period: i32,
+    tick: i32,
+    rows_since_flush: i32,
+    bpm: f32,
+    beat: f32,
+    masterdeck_index: usize,
+}
+
+impl Telemetry {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let filename = conf.get_or_default("filename", "telemetry.csv".to_string());
+        let file = match OpenOptions::new().create(true).append(true).open(&filename) {
+            Ok(f) => f,
+            Err(e) => {
+                logger.err(&format!("Failed to open telemetry file {filename}: {e}"));
+                return Err(());
+            }
+        };
+        let is_new = file.metadata().map(|m| m.len() == 0).unwrap_or(false);
+        let mut writer = BufWriter::new(file);
+        if is_new {
+            if let Err(e) = writeln!(writer, "timestamp,master_bpm,beat,masterdeck") {
+                logger.err(&format!("Failed to write to telemetry file: {e}"));
+                return Err(());
+            }
+        }
+        logger.info(&format!("Recording to {filename}"));
+
+        Ok(Box::new(Telemetry {
+            writer,
+            logger,
+            sample_period: conf.get_every_nth("sample_every_nth", 1),
+            flush_period: conf.get_every_nth("flush_every_nth", 10),
+            tick: 0,
+            rows_since_flush: 0,
+            bpm: 0.,
+            beat: 0.,
+            masterdeck_index: 0,
+        }))
+    }
+
+    fn write_row(&mut self) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.);
+        if let Err(e) = writeln!(
+            self.writer,
+            "{timestamp:.3},{:.2},{:.3},{}",
+            self.bpm, self.beat, self.masterdeck_index
+        ) {
+            self.logger.err(&format!("Failed to write to telemetry file: {e}"));
+            return;
+        }
+
+        self.rows_since_flush += 1;
+        if self.rows_since_flush >= self.flush_period {
+            self.rows_since_flush = 0;
+            if let Err(e) = self.writer.flush() {
+                self.logger.err(&format!("Failed to flush telemetry file: {e}"));
+            }
+        }
+    }
+}
+
+impl OutputModule for Telemetry {
+    fn bpm_changed_master(&mut self, bpm: f32) {
+        self.bpm = bpm;
+    }
+
+    fn beat_update_master(&mut self, beat: f32) {
+        self.beat = beat;
+    }
+
+    fn masterdeck_index_changed(&mut self, index: usize) {
+        self.masterdeck_index = index;
+    }
+
+    fn slow_update(&mut self) {
+        self.tick = (self.tick + 1) % self.sample_period;
+        if self.tick == 0 {
+            self.write_row();
+        }
+    }
+}
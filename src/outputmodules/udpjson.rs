@@ -0,0 +1,107 @@
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::events::{EventRecorder, EventSink, ModuleEvent};
+use crate::log::ScopedLogger;
+
+use super::ModuleCreateOutput;
+
+/// One datagram, eg. `{"seq":12,"t":3.52,"event":{"beat_update_master":{"beat":2.5}}}`. Events are
+/// the same as in the debug.record_events log
+#[derive(Serialize)]
+struct Datagram<'a> {
+    seq: u64, // Counts up by one per datagram, so receivers can detect drops
+    t: f64,   // Seconds since the module started
+    event: &'a ModuleEvent,
+}
+
+/// Streams every event as a small JSON datagram to one destination, for receivers that would
+/// rather parse JSON than OSC
+pub struct UdpJson {
+    socket: UdpSocket,
+    destination: SocketAddr,
+    started: Instant,
+    seq: u64,
+}
+
+impl UdpJson {
+    pub fn create(conf: Config, logger: ScopedLogger) -> ModuleCreateOutput {
+        let destination_str = conf.get_or_default("destination", "127.0.0.1:7002".to_string());
+        let Some(destination) = destination_str.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) else {
+            logger.err(&format!("Invalid UDP JSON destination address: {destination_str}"));
+            return Err(());
+        };
+        // Any free port in the family of the destination
+        let source = if destination.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = match UdpSocket::bind(source) {
+            Ok(socket) => socket,
+            Err(e) => {
+                logger.err(&format!("Failed to open socket: {e}"));
+                return Err(());
+            }
+        };
+        logger.info(&format!("Sending events to {destination}"));
+
+        let sink = UdpJson {
+            socket,
+            destination,
+            started: Instant::now(),
+            seq: 0,
+        };
+        Ok(Box::new(EventRecorder::new(Box::new(sink), logger)))
+    }
+}
+
+impl EventSink for UdpJson {
+    fn write(&mut self, event: ModuleEvent) -> std::io::Result<()> {
+        // Sent before every update, so it carries no information
+        if event == ModuleEvent::PreUpdate {
+            return Ok(());
+        }
+        let datagram = Datagram {
+            seq: self.seq,
+            t: self.started.elapsed().as_secs_f64(),
+            event: &event,
+        };
+        self.seq += 1;
+        let Ok(data) = serde_json::to_vec(&datagram) else {
+            return Ok(());
+        };
+        // A receiver that isn't up yet is no reason to stop sending
+        let _ = self.socket.send_to(&data, self.destination);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::outputmodules::test_receiver;
+
+    #[test]
+    fn datagrams_are_numbered_in_order() {
+        let (receiver, destination) = test_receiver();
+        let conf = Config::from_entries(&[("udpjson.destination", &destination)]).reduce_to_namespace("udpjson");
+        let logger = conf.logger.clone();
+        let mut module = UdpJson::create(conf, logger).unwrap();
+
+        module.pre_update();
+        module.beat_update_master(2.5);
+        module.bpm_changed_master(128.);
+
+        let mut buf = [0u8; 1024];
+        let mut received = vec![];
+        for _ in 0..2 {
+            let len = receiver.recv(&mut buf).unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+            received.push(value);
+        }
+        assert_eq!(received[0]["seq"], 0);
+        assert_eq!(received[0]["event"]["beat_update_master"]["beat"], 2.5);
+        assert_eq!(received[1]["seq"], 1);
+        assert_eq!(received[1]["event"]["bpm_changed_master"]["bpm"], 128.);
+    }
+}
@@ -0,0 +1,55 @@
+use std::ffi::OsString;
+use std::time::Duration;
+
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+    ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::beatkeeper::KEEPER_CONTROL;
+
+/// Must match the name the service was installed under
+const SERVICE_NAME: &str = "rkbx_link";
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Hands the process over to the service control manager. Blocks until the service has stopped.
+pub fn start() -> windows_service::Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    // Stopping goes through the same path as Ctrl+C, so the modules get to reset their outputs
+    let handler = |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            KEEPER_CONTROL.request_stop();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let Ok(status_handle) = service_control_handler::register(SERVICE_NAME, handler) else {
+        return;
+    };
+
+    let set_state = |state, controls_accepted| {
+        let _ = status_handle.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        });
+    };
+
+    set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    );
+    crate::run(true, crate::Source::Rekordbox, None);
+    set_state(ServiceState::Stopped, ServiceControlAccept::empty());
+}
@@ -0,0 +1,111 @@
+use std::net::UdpSocket;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Deserialize;
+
+use crate::beatkeeper::{DeckSource, TimingDataRaw};
+use crate::memory::{MemoryReadError, MemoryReadErrorType};
+
+/// Time without packets after which the source counts as disconnected
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Timing data of one deck, eg. `{"deck": 0, "bpm": 128.0, "position": 61.25, "master": true}`
+#[derive(Deserialize)]
+struct Packet {
+    deck: usize, // Rekordbox's deck index, as in keeper.deck_map
+    bpm: f32,
+    position: f64, // Seconds into the track
+    #[serde(default)]
+    master: bool,
+}
+
+/// Timing data sent over UDP by a companion app, for setups where rkbx_link can't read
+/// Rekordbox's memory. Only the tempo, position and master deck are provided, so without a
+/// beatgrid the beats follow keeper.fallback_beats
+pub struct UdpSource {
+    socket: UdpSocket,
+    address: String,
+    deck_map: Vec<usize>,
+    decks: Vec<Option<TimingDataRaw>>,
+    masterdeck_index: usize, // Rekordbox's deck index
+    last_received: Instant,
+}
+
+impl UdpSource {
+    pub fn new(address: &str, deck_map: &[usize]) -> Result<Self, MemoryReadError> {
+        let socket = UdpSocket::bind(address)
+            .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+            .map_err(|e| error(format!("failed to listen on {address}: {e}")))?;
+        Ok(UdpSource {
+            socket,
+            address: address.to_string(),
+            deck_map: deck_map.to_vec(),
+            decks: deck_map.iter().map(|_| None).collect(),
+            masterdeck_index: deck_map.first().copied().unwrap_or(0),
+            last_received: Instant::now(),
+        })
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        // Anything else sent to the port is ignored
+        let Ok(packet) = serde_json::from_slice::<Packet>(data) else {
+            return;
+        };
+        let Some(i) = self.deck_map.iter().position(|&deck| deck == packet.deck) else {
+            return;
+        };
+        self.last_received = Instant::now();
+        self.decks[i] = Some(TimingDataRaw {
+            current_bpm: packet.bpm,
+            sample_position: (packet.position * 44100.) as i64,
+            read_at: SystemTime::now(),
+        });
+        if packet.master {
+            self.masterdeck_index = packet.deck;
+        }
+    }
+}
+
+impl DeckSource for UdpSource {
+    fn poll(&mut self) -> Result<(), MemoryReadError> {
+        let mut buf = [0u8; 1024];
+        // Errors other than an empty socket, eg. ICMP port unreachable on Windows, are skipped too
+        while let Ok(len) = self.socket.recv(&mut buf) {
+            self.receive(&buf[..len]);
+        }
+        if self.last_received.elapsed() > TIMEOUT {
+            return Err(error(format!("nothing received on {} for {}s", self.address, TIMEOUT.as_secs())));
+        }
+        Ok(())
+    }
+
+    fn deckcount(&self) -> usize {
+        self.deck_map.len()
+    }
+
+    fn read_timing_data(&self, deck: usize) -> Result<TimingDataRaw, MemoryReadError> {
+        let Some(td) = &self.decks[deck] else {
+            return Err(error(format!("nothing received on {} for deck {} yet", self.address, self.deck_map[deck])));
+        };
+        Ok(TimingDataRaw {
+            current_bpm: td.current_bpm,
+            sample_position: td.sample_position,
+            read_at: td.read_at,
+        })
+    }
+
+    fn read_masterdeck_index(&self) -> Result<usize, MemoryReadError> {
+        Ok(self.masterdeck_index)
+    }
+}
+
+/// Reported like a Rekordbox process that can't be found, as nothing can be tracked
+fn error(detail: String) -> MemoryReadError {
+    MemoryReadError {
+        pointer: None,
+        label: None,
+        address: 0,
+        detail: Some(detail),
+        error_type: MemoryReadErrorType::ProcessNotFound,
+    }
+}
@@ -1,5 +1,12 @@
+use std::sync::OnceLock;
+
 use rekordcrate::anlz::Phrase;
 
+use crate::{config::Config, log::ScopedLogger};
+
+/// Custom names for the built-in phrase names, from keeper.phrase_alias.<name>. Set once at startup
+static PHRASE_ALIASES: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
 pub struct PhraseParser {
     phrase_names: Vec<Vec<String>>,
     hi_phrase_names: Vec<Vec<String>>,
@@ -24,9 +31,55 @@ impl PhraseParser {
         }
     }
 
+    /// Name of the phrase as sent to the modules, with aliases applied
     pub fn get_phrase_name(&self, mood: &rekordcrate::anlz::Mood, phrase: &Phrase) -> String {
-        if mood == &rekordcrate::anlz::Mood::High {
-            let phrase_kind = match phrase.kind {
+        let variation = phrase.k1 as usize + 2 * phrase.k2 as usize + phrase.k3 as usize;
+        Self::alias(&self.phrase_name(mood, phrase.kind, variation))
+    }
+
+    /// Read the phrase aliases. Keys are the built-in names with spaces as underscores, eg.
+    /// keeper.phrase_alias.Up_1 Build
+    pub fn set_aliases(conf: &Config, logger: &ScopedLogger) {
+        let known = Self::new().known_names();
+        let aliases = conf
+            .entries_with_prefix("phrase_alias.")
+            .into_iter()
+            .filter_map(|(name, alias)| {
+                let name = name.replace('_', " ");
+                if !known.contains(&name) {
+                    logger.err(&format!("Unknown phrase name in keeper.phrase_alias: {name}"));
+                    return None;
+                }
+                if alias.is_empty() {
+                    return None;
+                }
+                Some((name, alias))
+            })
+            .collect();
+        let _ = PHRASE_ALIASES.set(aliases);
+    }
+
+    fn known_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.phrase_names.iter().chain(&self.hi_phrase_names).flatten().cloned().collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    fn aliases() -> &'static [(String, String)] {
+        PHRASE_ALIASES.get().map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// The alias of a built-in phrase name, or the name itself
+    pub fn alias(name: &str) -> String {
+        aliased(Self::aliases(), name).to_string()
+    }
+
+    /// Name of a phrase kind, with the variation of High mood phrases. Values Rekordbox isn't
+    /// known to use give an empty name, which maps to index 0
+    fn phrase_name(&self, mood: &rekordcrate::anlz::Mood, kind: u16, variation: usize) -> String {
+        let name = if mood == &rekordcrate::anlz::Mood::High {
+            let phrase_kind = match kind {
                 1 => 0,
                 2 => 1,
                 3 => 2,
@@ -34,9 +87,11 @@ impl PhraseParser {
                 6 => 4,
                 _ => 99
             };
-            return self.hi_phrase_names[phrase_kind][(phrase.k1 + 2 * phrase.k2 + phrase.k3) as usize].clone();
-        }
-        self.phrase_names[self.mood_to_int(mood)][phrase.kind as usize - 1].clone()
+            self.hi_phrase_names.get(phrase_kind).and_then(|names| names.get(variation))
+        } else {
+            (kind as usize).checked_sub(1).and_then(|i| self.phrase_names[self.mood_to_int(mood)].get(i))
+        };
+        name.cloned().unwrap_or_default()
     }
 
     fn mood_to_int(&self, mood: &rekordcrate::anlz::Mood) -> usize {
@@ -47,8 +102,18 @@ impl PhraseParser {
         }
     }
 
+    /// 1 = Low, 2 = Mid, 3 = High. 0 is used for tracks without phrase analysis
+    pub fn mood_to_index(mood: &rekordcrate::anlz::Mood) -> u8 {
+        match mood {
+            rekordcrate::anlz::Mood::Low => 1,
+            rekordcrate::anlz::Mood::Mid => 2,
+            rekordcrate::anlz::Mood::High => 3,
+        }
+    }
+
+    /// Phrase group of a name, 0 if unknown. Aliases map to the group of their built-in name
     pub fn phrase_name_to_index (phrase_name: &str) -> i32{
-        match phrase_name {
+        match unaliased(Self::aliases(), phrase_name) {
             "Intro" | "Intro 1" | "Intro 2" => 1,
             "Verse 1" | "Verse 2" | "Verse 3" | "Verse 4" | "Verse 5" | "Verse 6" | "Up 1" | "Up 2" | "Up 3" => 2,
             "Chorus" | "Chorus 1" | "Chorus 2" => 3,
@@ -58,3 +123,120 @@ impl PhraseParser {
         }
     }
 }
+
+/// Alias of `name`, if it has one
+fn aliased<'a>(aliases: &'a [(String, String)], name: &'a str) -> &'a str {
+    aliases.iter().find(|(builtin, _)| builtin == name).map_or(name, |(_, alias)| alias)
+}
+
+/// Built-in name of an alias. Names that aren't aliases are returned as they are
+fn unaliased<'a>(aliases: &'a [(String, String)], name: &'a str) -> &'a str {
+    aliases.iter().find(|(_, alias)| alias == name).map_or(name, |(builtin, _)| builtin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rekordcrate::anlz::Mood;
+
+    #[test]
+    fn low_and_mid_phrase_names() {
+        let parser = PhraseParser::new();
+        assert_eq!(parser.phrase_name(&Mood::Low, 1, 0), "Intro");
+        assert_eq!(parser.phrase_name(&Mood::Low, 4, 0), "Verse 1");
+        assert_eq!(parser.phrase_name(&Mood::Low, 5, 0), "Verse 2");
+        assert_eq!(parser.phrase_name(&Mood::Low, 8, 0), "Bridge");
+        assert_eq!(parser.phrase_name(&Mood::Low, 9, 0), "Chorus");
+        assert_eq!(parser.phrase_name(&Mood::Low, 10, 0), "Outro");
+        assert_eq!(parser.phrase_name(&Mood::Mid, 1, 0), "Intro");
+        assert_eq!(parser.phrase_name(&Mood::Mid, 4, 0), "Verse 3");
+        assert_eq!(parser.phrase_name(&Mood::Mid, 7, 0), "Verse 6");
+        assert_eq!(parser.phrase_name(&Mood::Mid, 8, 0), "Bridge");
+        assert_eq!(parser.phrase_name(&Mood::Mid, 9, 0), "Chorus");
+        assert_eq!(parser.phrase_name(&Mood::Mid, 10, 0), "Outro");
+    }
+
+    #[test]
+    fn high_phrase_names() {
+        let parser = PhraseParser::new();
+        assert_eq!(parser.phrase_name(&Mood::High, 1, 0), "Intro 2");
+        assert_eq!(parser.phrase_name(&Mood::High, 1, 1), "Intro 1");
+        assert_eq!(parser.phrase_name(&Mood::High, 2, 0), "Up 1");
+        assert_eq!(parser.phrase_name(&Mood::High, 2, 2), "Up 3");
+        assert_eq!(parser.phrase_name(&Mood::High, 3, 0), "Down");
+        assert_eq!(parser.phrase_name(&Mood::High, 5, 0), "Chorus 2");
+        assert_eq!(parser.phrase_name(&Mood::High, 5, 1), "Chorus 1");
+        assert_eq!(parser.phrase_name(&Mood::High, 6, 0), "Outro 2");
+        assert_eq!(parser.phrase_name(&Mood::High, 6, 1), "Outro 1");
+    }
+
+    #[test]
+    fn unknown_phrases_fall_back_to_empty() {
+        let parser = PhraseParser::new();
+        for mood in [Mood::Low, Mood::Mid] {
+            assert_eq!(parser.phrase_name(&mood, 0, 0), "");
+            assert_eq!(parser.phrase_name(&mood, 11, 0), "");
+            assert_eq!(parser.phrase_name(&mood, u16::MAX, 0), "");
+        }
+        assert_eq!(parser.phrase_name(&Mood::High, 0, 0), "");
+        assert_eq!(parser.phrase_name(&Mood::High, 4, 0), "");
+        assert_eq!(parser.phrase_name(&Mood::High, 7, 0), "");
+        assert_eq!(parser.phrase_name(&Mood::High, 1, 2), "");
+        assert_eq!(parser.phrase_name(&Mood::High, 3, 1), "");
+        assert_eq!(PhraseParser::phrase_name_to_index(""), 0);
+    }
+
+    #[test]
+    fn every_phrase_name_has_an_index() {
+        let parser = PhraseParser::new();
+        for mood in [Mood::Low, Mood::Mid, Mood::High] {
+            for kind in 1..=10 {
+                for variation in 0..3 {
+                    let name = parser.phrase_name(&mood, kind, variation);
+                    if name.is_empty() {
+                        continue;
+                    }
+                    let index = PhraseParser::phrase_name_to_index(&name);
+                    assert!((1..=5).contains(&index), "{name} has index {index}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn phrase_groups() {
+        assert_eq!(PhraseParser::phrase_name_to_index("Intro 1"), 1);
+        assert_eq!(PhraseParser::phrase_name_to_index("Verse 4"), 2);
+        assert_eq!(PhraseParser::phrase_name_to_index("Up 2"), 2);
+        assert_eq!(PhraseParser::phrase_name_to_index("Chorus"), 3);
+        assert_eq!(PhraseParser::phrase_name_to_index("Down"), 4);
+        assert_eq!(PhraseParser::phrase_name_to_index("Bridge"), 4);
+        assert_eq!(PhraseParser::phrase_name_to_index("Outro 2"), 5);
+        assert_eq!(PhraseParser::phrase_name_to_index("Breakdown"), 0);
+    }
+
+    #[test]
+    fn aliases_map_both_ways() {
+        let aliases = [("Up 1".to_string(), "Build".to_string()), ("Down".to_string(), "Break".to_string())];
+        assert_eq!(aliased(&aliases, "Up 1"), "Build");
+        assert_eq!(aliased(&aliases, "Chorus"), "Chorus");
+        assert_eq!(unaliased(&aliases, "Build"), "Up 1");
+        assert_eq!(unaliased(&aliases, "Break"), "Down");
+        assert_eq!(unaliased(&aliases, "Outro"), "Outro");
+    }
+
+    #[test]
+    fn known_names_cover_all_moods() {
+        let names = PhraseParser::new().known_names();
+        for name in ["Intro", "Verse 6", "Up 3", "Down", "Chorus 2", "Outro 1"] {
+            assert!(names.contains(&name.to_string()), "{name}");
+        }
+    }
+
+    #[test]
+    fn mood_indices() {
+        assert_eq!(PhraseParser::mood_to_index(&Mood::Low), 1);
+        assert_eq!(PhraseParser::mood_to_index(&Mood::Mid), 2);
+        assert_eq!(PhraseParser::mood_to_index(&Mood::High), 3);
+    }
+}